@@ -0,0 +1,792 @@
+//! Anthropic Messages API 后端
+//!
+//! 将统一的 OpenAI 格式消息转换为 Anthropic `/v1/messages` 的请求结构：
+//! system 消息提取为顶层 `system` 字段，工具调用与工具结果转换为
+//! `tool_use` / `tool_result` 内容块
+
+use futures_util::StreamExt;
+use serde_json::Value;
+use std::error::Error;
+
+use crate::chatbot::config::RetryConfig;
+use crate::chatbot::llm::{
+    parse_sse_data, send_with_retry, CompletionResponse, FunctionCall, LlmBackend, LlmMessage,
+    LlmRequestParams, Reasoning, TokenUsage, ToolCall,
+};
+
+/// Anthropic API 版本
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// 未配置 `max_tokens` 时使用的默认值（Anthropic 接口要求必填）
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Anthropic 客户端
+pub struct AnthropicClient {
+    http_client: reqwest::Client,
+    api_key: String,
+    base_url: String,
+    model: String,
+    request_params: LlmRequestParams,
+    retry: RetryConfig,
+}
+
+impl AnthropicClient {
+    /// 创建新的 Anthropic 客户端
+    ///
+    /// # 参数
+    /// - `api_key`: API 密钥
+    /// - `base_url`: API 基础 URL（如 `https://api.anthropic.com`）
+    /// - `model`: 使用的模型名称
+    /// - `request_params`: 请求参数配置
+    pub fn new(
+        api_key: String,
+        base_url: String,
+        model: String,
+        request_params: LlmRequestParams,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Self {
+            http_client: reqwest::Client::new(),
+            api_key,
+            base_url,
+            model,
+            request_params,
+            retry: RetryConfig::default(),
+        })
+    }
+
+    /// 设置重试策略
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// 获取 messages 接口地址
+    fn messages_url(&self) -> String {
+        let base = self.base_url.trim_end_matches('/');
+        if base.ends_with("/messages") {
+            base.to_string()
+        } else if base.ends_with("/v1") {
+            format!("{}/messages", base)
+        } else {
+            format!("{}/v1/messages", base)
+        }
+    }
+
+    /// 发送请求，失败时按重试策略重试
+    async fn send_with_retry(
+        &self,
+        request_body: &Value,
+    ) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>> {
+        send_with_retry(&self.retry, &self.model, "Anthropic", || {
+            self.http_client
+                .post(self.messages_url())
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", ANTHROPIC_VERSION)
+                .header("Content-Type", "application/json")
+                .json(request_body)
+        })
+        .await
+    }
+
+    /// 构建请求体
+    ///
+    /// `presence_penalty` / `frequency_penalty` 在 Anthropic 接口中不存在，会被忽略
+    fn build_request_body(
+        &self,
+        messages: &[LlmMessage],
+        tools: Option<&Vec<Value>>,
+        stream: bool,
+    ) -> Value {
+        let (system, messages) = convert_messages(messages);
+
+        let mut max_tokens = self.request_params.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+        // max_tokens 必须大于思考预算
+        if let Some(budget) = self.request_params.thinking_budget {
+            if max_tokens <= budget {
+                max_tokens = budget + DEFAULT_MAX_TOKENS;
+            }
+        }
+
+        let mut request_body = serde_json::json!({
+            "model": self.model,
+            "messages": messages,
+            "max_tokens": max_tokens,
+        });
+
+        if let Some(system) = system {
+            request_body["system"] = serde_json::json!(system);
+        }
+        if stream {
+            request_body["stream"] = serde_json::json!(true);
+        }
+
+        match self.request_params.thinking_budget {
+            Some(budget) => {
+                // 启用 extended thinking 时接口不允许修改 temperature / top_p
+                request_body["thinking"] = serde_json::json!({
+                    "type": "enabled",
+                    "budget_tokens": budget,
+                });
+            }
+            None => {
+                if let Some(temp) = self.request_params.temperature {
+                    request_body["temperature"] = serde_json::json!(temp);
+                }
+                if let Some(top_p) = self.request_params.top_p {
+                    request_body["top_p"] = serde_json::json!(top_p);
+                }
+            }
+        }
+
+        if let Some(tools) = tools {
+            if !tools.is_empty() {
+                request_body["tools"] = Value::Array(convert_tools(tools));
+            }
+        }
+
+        request_body
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmBackend for AnthropicClient {
+    async fn chat_completion(
+        &self,
+        messages: Vec<LlmMessage>,
+        tools: Option<&Vec<Value>>,
+    ) -> Result<CompletionResponse, Box<dyn Error + Send + Sync>> {
+        let request_body = self.build_request_body(&messages, tools, false);
+        let response = self.send_with_retry(&request_body).await?;
+
+        let response_text = response.text().await?;
+
+        let json: Value = serde_json::from_str(&response_text).map_err(|e| {
+            format!(
+                "Failed to parse Anthropic response: {}. Body: {}",
+                e, response_text
+            )
+        })?;
+
+        if json["type"] == "error" {
+            return Err(format!("Anthropic API returned error: {}", json["error"]).into());
+        }
+
+        Ok(parse_response(&json))
+    }
+
+    /// 流式请求，按内容块拼接文本和 `tool_use` 的 JSON 参数
+    async fn chat_completion_stream(
+        &self,
+        messages: Vec<LlmMessage>,
+        tools: Option<&Vec<Value>>,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<CompletionResponse, Box<dyn Error + Send + Sync>> {
+        let request_body = self.build_request_body(&messages, tools, true);
+        // 只对建立连接阶段重试，流开始后的错误直接返回，避免重复输出
+        let response = self.send_with_retry(&request_body).await?;
+
+        let mut accumulator = AnthropicStreamAccumulator::default();
+        let mut stream = response.bytes_stream();
+        // 按字节缓冲，避免多字节 UTF-8 字符被分片截断
+        let mut buffer: Vec<u8> = Vec::new();
+
+        'outer: while let Some(chunk) = stream.next().await {
+            buffer.extend_from_slice(&chunk?);
+
+            while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line_bytes: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line_bytes);
+
+                // 事件类型同时包含在 data 的 `type` 字段中，这里只处理 data 行
+                let data = match parse_sse_data(&line) {
+                    Some(data) => data,
+                    None => continue,
+                };
+
+                let json: Value = serde_json::from_str(data).map_err(|e| {
+                    format!(
+                        "Failed to parse Anthropic stream event: {}. Data: {}",
+                        e, data
+                    )
+                })?;
+                match json["type"].as_str() {
+                    Some("error") => {
+                        return Err(
+                            format!("Anthropic API returned error: {}", json["error"]).into()
+                        );
+                    }
+                    Some("message_stop") => break 'outer,
+                    _ => {}
+                }
+
+                if let Some(delta) = accumulator.apply_event(&json) {
+                    on_delta(&delta);
+                }
+            }
+        }
+
+        Ok(accumulator.into_response())
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+}
+
+/// 将 OpenAI 格式的消息转换为 Anthropic 格式
+///
+/// 返回 (system 文本, messages)。相邻的同角色消息会被合并，
+/// 因为 Anthropic 要求 user / assistant 交替出现（多个工具结果需放在同一条 user 消息中）。
+/// Anthropic 返回的 thinking / redacted_thinking 块按原顺序放在助手消息最前面回传
+fn convert_messages(messages: &[LlmMessage]) -> (Option<String>, Vec<Value>) {
+    let mut system_parts: Vec<&str> = Vec::new();
+    let mut converted: Vec<Value> = Vec::new();
+
+    for message in messages {
+        let (role, blocks) = match message.role.as_str() {
+            "system" => {
+                if let Some(content) = message.content.as_deref().filter(|c| !c.is_empty()) {
+                    system_parts.push(content);
+                }
+                continue;
+            }
+            "tool" => {
+                let block = serde_json::json!({
+                    "type": "tool_result",
+                    "tool_use_id": message.tool_call_id.clone().unwrap_or_default(),
+                    "content": message.content.clone().unwrap_or_default(),
+                });
+                ("user", vec![block])
+            }
+            "assistant" => {
+                let mut blocks: Vec<Value> = message
+                    .reasoning
+                    .iter()
+                    .flat_map(|reasoning| reasoning.blocks.iter().cloned())
+                    .collect();
+                blocks.extend(text_blocks(message));
+                for call in message.tool_calls.iter().flatten() {
+                    let input: Value = serde_json::from_str(&call.function.arguments)
+                        .unwrap_or_else(|_| serde_json::json!({}));
+                    blocks.push(serde_json::json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.function.name,
+                        "input": input,
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            _ => {
+                let mut blocks: Vec<Value> = message
+                    .images
+                    .iter()
+                    .map(|image| image_block(image))
+                    .collect();
+                blocks.extend(text_blocks(message));
+                ("user", blocks)
+            }
+        };
+
+        if blocks.is_empty() {
+            continue;
+        }
+
+        match converted.last_mut() {
+            Some(last) if last["role"] == role => {
+                if let Some(content) = last["content"].as_array_mut() {
+                    content.extend(blocks);
+                }
+            }
+            _ => converted.push(serde_json::json!({ "role": role, "content": blocks })),
+        }
+    }
+
+    let system = if system_parts.is_empty() {
+        None
+    } else {
+        Some(system_parts.join("\n\n"))
+    };
+    (system, converted)
+}
+
+/// 消息文本对应的内容块（空文本不生成内容块）
+fn text_blocks(message: &LlmMessage) -> Vec<Value> {
+    message
+        .content
+        .as_deref()
+        .filter(|text| !text.is_empty())
+        .map(|text| vec![serde_json::json!({ "type": "text", "text": text })])
+        .unwrap_or_default()
+}
+
+/// 图片内容块，data URL 转换为 base64 来源，其余视为 http(s) 地址
+fn image_block(image: &str) -> Value {
+    let base64_source = image.strip_prefix("data:").and_then(|rest| {
+        let (media_type, data) = rest.split_once(";base64,")?;
+        Some(serde_json::json!({
+            "type": "base64",
+            "media_type": media_type,
+            "data": data,
+        }))
+    });
+
+    let source =
+        base64_source.unwrap_or_else(|| serde_json::json!({ "type": "url", "url": image }));
+    serde_json::json!({ "type": "image", "source": source })
+}
+
+/// 将 OpenAI 格式的工具定义转换为 Anthropic 格式
+fn convert_tools(tools: &[Value]) -> Vec<Value> {
+    tools
+        .iter()
+        .filter_map(|tool| {
+            let function = &tool["function"];
+            let name = function["name"].as_str()?;
+            let input_schema = if function["parameters"].is_object() {
+                function["parameters"].clone()
+            } else {
+                serde_json::json!({ "type": "object", "properties": {} })
+            };
+            Some(serde_json::json!({
+                "name": name,
+                "description": function["description"].as_str().unwrap_or_default(),
+                "input_schema": input_schema,
+            }))
+        })
+        .collect()
+}
+
+/// 从 Anthropic 的 `usage` 字段解析 token 用量
+fn parse_usage(value: &Value) -> Option<TokenUsage> {
+    TokenUsage::from_value(&serde_json::json!({
+        "prompt_tokens": value.get("input_tokens")?,
+        "completion_tokens": value.get("output_tokens")?,
+    }))
+}
+
+/// 由 thinking / redacted_thinking 块生成思考内容
+///
+/// 所有块原样保留用于回传（开启 extended thinking 时工具调用循环要求全部带回），
+/// 各 thinking 块的文本拼接后用于日志
+fn reasoning_from_blocks(blocks: Vec<Value>) -> Option<Reasoning> {
+    if blocks.is_empty() {
+        return None;
+    }
+    let content = blocks
+        .iter()
+        .filter_map(|block| block["thinking"].as_str())
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    Some(Reasoning { content, blocks })
+}
+
+/// 解析非流式响应
+fn parse_response(json: &Value) -> CompletionResponse {
+    let mut content = String::new();
+    let mut thinking_blocks = Vec::new();
+    let mut tool_calls = Vec::new();
+
+    for block in json["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str() {
+            Some("text") => content.push_str(block["text"].as_str().unwrap_or_default()),
+            Some("thinking") | Some("redacted_thinking") => thinking_blocks.push(block.clone()),
+            Some("tool_use") => {
+                if let (Some(id), Some(name)) = (block["id"].as_str(), block["name"].as_str()) {
+                    tool_calls.push(ToolCall {
+                        id: id.to_string(),
+                        call_type: "function".to_string(),
+                        function: FunctionCall {
+                            name: name.to_string(),
+                            arguments: block["input"].to_string(),
+                        },
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    CompletionResponse {
+        content: if content.is_empty() {
+            None
+        } else {
+            Some(content)
+        },
+        tool_calls,
+        usage: parse_usage(&json["usage"]),
+        reasoning: reasoning_from_blocks(thinking_blocks),
+    }
+}
+
+/// 流式内容块的拼接状态
+#[derive(Debug, Default)]
+struct PartialBlock {
+    block_type: String,
+    id: String,
+    name: String,
+    /// text 块的正文或 thinking 块的思考内容
+    text: String,
+    signature: String,
+    /// redacted_thinking 块的加密内容
+    data: String,
+    partial_json: String,
+}
+
+/// Anthropic 流式响应累加器
+///
+/// 按 `index` 归并 `content_block_start` / `content_block_delta` 事件，
+/// 并从 `message_start` / `message_delta` 中收集 token 用量
+#[derive(Debug, Default)]
+struct AnthropicStreamAccumulator {
+    blocks: Vec<PartialBlock>,
+    input_tokens: u32,
+    output_tokens: u32,
+    has_usage: bool,
+}
+
+impl AnthropicStreamAccumulator {
+    /// 处理一个流式事件，返回本次新增的文本（如果有）
+    fn apply_event(&mut self, event: &Value) -> Option<String> {
+        match event["type"].as_str()? {
+            "message_start" => {
+                let usage = &event["message"]["usage"];
+                if let Some(input) = usage["input_tokens"].as_u64() {
+                    self.input_tokens = input as u32;
+                    self.has_usage = true;
+                }
+                if let Some(output) = usage["output_tokens"].as_u64() {
+                    self.output_tokens = output as u32;
+                }
+                None
+            }
+            "message_delta" => {
+                if let Some(output) = event["usage"]["output_tokens"].as_u64() {
+                    self.output_tokens = output as u32;
+                    self.has_usage = true;
+                }
+                None
+            }
+            "content_block_start" => {
+                let block = &event["content_block"];
+                let partial = self.block_mut(event);
+                partial.block_type = block["type"].as_str().unwrap_or_default().to_string();
+                partial.id = block["id"].as_str().unwrap_or_default().to_string();
+                partial.name = block["name"].as_str().unwrap_or_default().to_string();
+                partial.data = block["data"].as_str().unwrap_or_default().to_string();
+
+                match block["text"].as_str() {
+                    Some(text) if !text.is_empty() => {
+                        partial.text.push_str(text);
+                        Some(text.to_string())
+                    }
+                    _ => None,
+                }
+            }
+            "content_block_delta" => {
+                let delta = &event["delta"];
+                let partial = self.block_mut(event);
+                match delta["type"].as_str()? {
+                    "text_delta" => {
+                        let text = delta["text"].as_str().filter(|t| !t.is_empty())?;
+                        partial.text.push_str(text);
+                        Some(text.to_string())
+                    }
+                    // 思考内容只累积，不作为增量文本输出
+                    "thinking_delta" => {
+                        partial
+                            .text
+                            .push_str(delta["thinking"].as_str().unwrap_or_default());
+                        None
+                    }
+                    "signature_delta" => {
+                        partial
+                            .signature
+                            .push_str(delta["signature"].as_str().unwrap_or_default());
+                        None
+                    }
+                    "input_json_delta" => {
+                        partial
+                            .partial_json
+                            .push_str(delta["partial_json"].as_str().unwrap_or_default());
+                        None
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// 获取事件对应的内容块，不存在时自动补齐
+    fn block_mut(&mut self, event: &Value) -> &mut PartialBlock {
+        let index = event["index"].as_u64().unwrap_or(0) as usize;
+        while self.blocks.len() <= index {
+            self.blocks.push(PartialBlock::default());
+        }
+        &mut self.blocks[index]
+    }
+
+    /// 生成完整响应
+    fn into_response(self) -> CompletionResponse {
+        let mut content = String::new();
+        let mut thinking_blocks = Vec::new();
+        let mut tool_calls = Vec::new();
+
+        for block in self.blocks {
+            match block.block_type.as_str() {
+                "text" => content.push_str(&block.text),
+                "thinking" => thinking_blocks.push(serde_json::json!({
+                    "type": "thinking",
+                    "thinking": block.text,
+                    "signature": block.signature,
+                })),
+                "redacted_thinking" => thinking_blocks.push(serde_json::json!({
+                    "type": "redacted_thinking",
+                    "data": block.data,
+                })),
+                "tool_use" if !block.name.is_empty() => {
+                    let arguments = if block.partial_json.trim().is_empty() {
+                        "{}".to_string()
+                    } else {
+                        block.partial_json
+                    };
+                    tool_calls.push(ToolCall {
+                        id: block.id,
+                        call_type: "function".to_string(),
+                        function: FunctionCall {
+                            name: block.name,
+                            arguments,
+                        },
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        let usage = self.has_usage.then_some(TokenUsage {
+            prompt_tokens: self.input_tokens,
+            completion_tokens: self.output_tokens,
+            total_tokens: self.input_tokens + self.output_tokens,
+            reasoning_tokens: 0,
+        });
+
+        CompletionResponse {
+            content: if content.is_empty() {
+                None
+            } else {
+                Some(content)
+            },
+            tool_calls,
+            usage,
+            reasoning: reasoning_from_blocks(thinking_blocks),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_url() {
+        let client = |url: &str| {
+            AnthropicClient::new(
+                "key".to_string(),
+                url.to_string(),
+                "claude".to_string(),
+                LlmRequestParams::default(),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            client("https://api.anthropic.com").messages_url(),
+            "https://api.anthropic.com/v1/messages"
+        );
+        assert_eq!(
+            client("https://api.anthropic.com/v1/").messages_url(),
+            "https://api.anthropic.com/v1/messages"
+        );
+        assert_eq!(
+            client("https://proxy.example.com/v1/messages").messages_url(),
+            "https://proxy.example.com/v1/messages"
+        );
+    }
+
+    #[test]
+    fn test_convert_messages() {
+        let tool_call = ToolCall {
+            id: "toolu_1".to_string(),
+            call_type: "function".to_string(),
+            function: FunctionCall {
+                name: "search".to_string(),
+                arguments: r#"{"q":"rust"}"#.to_string(),
+            },
+        };
+        let messages = vec![
+            LlmMessage::system("你是小诗"),
+            LlmMessage::user("搜一下"),
+            LlmMessage::assistant_with_tool_calls(None, vec![tool_call]),
+            LlmMessage::tool("结果1", "toolu_1"),
+            LlmMessage::tool("结果2", "toolu_2"),
+            LlmMessage::user_with_images("看图", vec!["data:image/png;base64,AAAA".to_string()]),
+        ];
+
+        let (system, converted) = convert_messages(&messages);
+        assert_eq!(system.as_deref(), Some("你是小诗"));
+        assert_eq!(converted.len(), 3);
+
+        assert_eq!(converted[0]["role"], "user");
+        assert_eq!(converted[0]["content"][0]["text"], "搜一下");
+
+        assert_eq!(converted[1]["role"], "assistant");
+        assert_eq!(converted[1]["content"][0]["type"], "tool_use");
+        assert_eq!(converted[1]["content"][0]["input"]["q"], "rust");
+
+        // 工具结果与后续用户消息合并为同一条 user 消息
+        let content = converted[2]["content"].as_array().unwrap();
+        assert_eq!(converted[2]["role"], "user");
+        assert_eq!(content.len(), 4);
+        assert_eq!(content[0]["type"], "tool_result");
+        assert_eq!(content[1]["tool_use_id"], "toolu_2");
+        assert_eq!(content[2]["source"]["media_type"], "image/png");
+        assert_eq!(content[3]["text"], "看图");
+    }
+
+    #[test]
+    fn test_convert_tools() {
+        let tools = vec![serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "description": "查询天气",
+                "parameters": { "type": "object", "properties": { "city": { "type": "string" } } }
+            }
+        })];
+
+        let converted = convert_tools(&tools);
+        assert_eq!(converted[0]["name"], "get_weather");
+        assert_eq!(converted[0]["description"], "查询天气");
+        assert_eq!(
+            converted[0]["input_schema"]["properties"]["city"]["type"],
+            "string"
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let json = serde_json::json!({
+            "type": "message",
+            "content": [
+                { "type": "text", "text": "我查一下" },
+                { "type": "tool_use", "id": "toolu_1", "name": "search", "input": { "q": "rust" } }
+            ],
+            "usage": { "input_tokens": 30, "output_tokens": 10 }
+        });
+
+        let response = parse_response(&json);
+        assert_eq!(response.content.as_deref(), Some("我查一下"));
+        assert_eq!(response.tool_calls[0].id, "toolu_1");
+        assert_eq!(response.tool_calls[0].function.arguments, r#"{"q":"rust"}"#);
+        assert_eq!(response.usage.map(|u| u.total_tokens), Some(40));
+    }
+
+    #[test]
+    fn test_stream_accumulator() {
+        let mut acc = AnthropicStreamAccumulator::default();
+        let events = [
+            r#"{"type":"message_start","message":{"usage":{"input_tokens":25,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"你好"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"search","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"q\":"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"rust\"}"}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":15}}"#,
+        ];
+
+        let mut deltas = Vec::new();
+        for event in events {
+            let json: Value = serde_json::from_str(event).unwrap();
+            if let Some(delta) = acc.apply_event(&json) {
+                deltas.push(delta);
+            }
+        }
+
+        assert_eq!(deltas, vec!["你好"]);
+        let response = acc.into_response();
+        assert_eq!(response.content.as_deref(), Some("你好"));
+        assert_eq!(response.tool_calls.len(), 1);
+        assert_eq!(response.tool_calls[0].function.arguments, r#"{"q":"rust"}"#);
+        assert_eq!(response.usage.map(|u| u.total_tokens), Some(40));
+    }
+
+    #[test]
+    fn test_thinking_blocks() {
+        let json = serde_json::json!({
+            "type": "message",
+            "content": [
+                { "type": "thinking", "thinking": "先搜索一下", "signature": "sig_1" },
+                { "type": "redacted_thinking", "data": "encrypted" },
+                { "type": "thinking", "thinking": "再查天气", "signature": "sig_2" },
+                { "type": "tool_use", "id": "toolu_1", "name": "search", "input": {} }
+            ]
+        });
+
+        let response = parse_response(&json);
+        assert_eq!(response.content, None);
+        let reasoning = response.reasoning.clone().unwrap();
+        assert_eq!(reasoning.content, "先搜索一下\n\n再查天气");
+        assert_eq!(reasoning.blocks.len(), 3);
+
+        // 工具调用循环中所有思考块需要按原顺序放在助手消息最前面原样回传
+        let message = LlmMessage::assistant_with_tool_calls(None, response.tool_calls)
+            .with_reasoning(response.reasoning);
+        let (_, converted) = convert_messages(&[message]);
+        let content = converted[0]["content"].as_array().unwrap();
+        assert_eq!(&content[..3], &json["content"].as_array().unwrap()[..3]);
+        assert_eq!(content[3]["type"], "tool_use");
+
+        // 没有签名的思考内容不回传
+        let message = LlmMessage::assistant("好的").with_reasoning(Reasoning::from_text("想想"));
+        let (_, converted) = convert_messages(&[message]);
+        assert_eq!(converted[0]["content"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_stream_accumulator_thinking() {
+        let mut acc = AnthropicStreamAccumulator::default();
+        let events = [
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"thinking","thinking":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"让我想想"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"signature_delta","signature":"sig_1"}}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"redacted_thinking","data":"encrypted"}}"#,
+            r#"{"type":"content_block_start","index":2,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":2,"delta":{"type":"text_delta","text":"答案"}}"#,
+        ];
+
+        let mut deltas = Vec::new();
+        for event in events {
+            let json: Value = serde_json::from_str(event).unwrap();
+            if let Some(delta) = acc.apply_event(&json) {
+                deltas.push(delta);
+            }
+        }
+
+        assert_eq!(deltas, vec!["答案"]);
+        let response = acc.into_response();
+        assert_eq!(response.content.as_deref(), Some("答案"));
+        let reasoning = response.reasoning.unwrap();
+        assert_eq!(reasoning.content, "让我想想");
+        assert_eq!(
+            reasoning.blocks,
+            vec![
+                serde_json::json!({ "type": "thinking", "thinking": "让我想想", "signature": "sig_1" }),
+                serde_json::json!({ "type": "redacted_thinking", "data": "encrypted" }),
+            ]
+        );
+    }
+}
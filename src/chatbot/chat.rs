@@ -1,25 +1,57 @@
 use anyhow::Result;
+use futures_util::StreamExt;
 use serde_json::Value;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use crate::chatbot::config::Config;
-use crate::chatbot::llm::{CompletionResponse, LlmClient, LlmMessage, LlmRequestParams};
+use crate::chatbot::config::{ChatOverride, Config, LlmConfig};
+use crate::chatbot::group_context::GroupTimeline;
+use crate::chatbot::maintenance::CleanupReport;
+use crate::chatbot::llm::{
+    create_backend, CompletionResponse, LlmBackend, LlmMessage, LlmRequestParams,
+    SentenceSplitter, TokenUsage, ToolCall,
+};
 use crate::chatbot::mcp::{McpContent, McpManager};
 use crate::chatbot::memory::Memory;
+use crate::chatbot::memory_store::JsonlStore;
 use crate::chatbot::memory_evaluation::MemoryEvaluator;
 use crate::chatbot::prompt_template::PromptTemplate;
 use crate::chatbot::rag::TemporalMemory;
+use crate::chatbot::summary::HistorySummarizer;
+use crate::chatbot::tokenizer::{create_token_counter, TokenCounter};
+use crate::chatbot::usage::{BudgetStatus, UsageTracker};
+use crate::chatbot::vision::{memory_text_with_images, VisionProcessor};
+
+/// 流式输出时每段消息的最少字符数
+const STREAM_SEGMENT_MIN_CHARS: usize = 8;
+
+/// 未配置 `max_tokens` 时为模型回复预留的 token 数
+const DEFAULT_REPLY_RESERVE_TOKENS: usize = 2048;
+
+/// 模型只返回思考内容、没有正文时，切换备用模型前重试同一模型的次数
+const REASONING_ONLY_RETRIES: usize = 1;
 
 /// 聊天机器人
 /// 封装所有聊天相关的逻辑，包括记忆管理、RAG、LLM调用、记忆评估、MCP工具调用等
 pub struct ChatBot {
-    llm: Arc<LlmClient>,
+    /// LLM 后端链：第一个为主模型，其余为按顺序尝试的备用模型（管理命令可切换主模型）
+    llm_chain: RwLock<Vec<Arc<dyn LlmBackend>>>,
+    /// 按群/用户覆盖了模型或温度的主模型，按需创建后缓存
+    override_backends: Mutex<HashMap<String, Arc<dyn LlmBackend>>>,
     short_term_memory: Arc<Memory>,
     long_term_memory: Option<Arc<TemporalMemory>>,
     memory_evaluator: Option<Arc<MemoryEvaluator>>,
     mcp_manager: Option<Arc<McpManager>>,
+    usage_tracker: Arc<UsageTracker>,
+    vision: Option<Arc<VisionProcessor>>,
+    summarizer: Option<Arc<HistorySummarizer>>,
+    group_timeline: Arc<GroupTimeline>,
+    token_counter: Arc<dyn TokenCounter>,
     config: Arc<Config>,
+    /// config.json 所在目录，用于解析相对路径
+    config_dir: Option<PathBuf>,
 }
 
 impl ChatBot {
@@ -29,35 +61,84 @@ impl ChatBot {
     /// - `config`: 配置对象
     /// - `config_path`: 配置文件路径，用于解析 MCP 配置的相对路径
     pub async fn new<P: AsRef<Path>>(config: Config, config_path: P) -> Result<Self> {
-        let config_dir = config_path.as_ref().parent();
-        
-        // 构建 LLM 请求参数
-        let llm_params = LlmRequestParams {
-            temperature: config.llm.temperature,
-            top_p: config.llm.top_p,
-            max_tokens: config.llm.max_tokens,
-            presence_penalty: config.llm.presence_penalty,
-            frequency_penalty: config.llm.frequency_penalty,
+        Self::build(config, config_path.as_ref().parent(), None, true).await
+    }
+
+    /// 用新配置构建替换当前实例的聊天机器人（配置热重载）
+    ///
+    /// 短期记忆、群聊缓冲和 token 用量沿用当前实例；数据库与 RAG 配置未变时沿用长期记忆连接，
+    /// MCP 配置未变且 `reload_mcp` 为 false 时沿用已连接的 MCP 服务
+    ///
+    /// # 参数
+    /// - `config`: 新的配置对象
+    /// - `reload_mcp`: 是否强制重新加载 MCP 配置文件
+    pub async fn reload(&self, config: Config, reload_mcp: bool) -> Result<Self> {
+        let config_dir = self.config_dir.clone();
+        Self::build(config, config_dir.as_deref(), Some(self), reload_mcp).await
+    }
+
+    async fn build(
+        config: Config,
+        config_dir: Option<&Path>,
+        previous: Option<&ChatBot>,
+        reload_mcp: bool,
+    ) -> Result<Self> {
+        // 初始化 LLM 客户端（主模型 + 备用模型）
+        let mut llm_chain = vec![Self::build_llm_backend(&config.llm)?];
+        for fallback in &config.llm.fallbacks {
+            llm_chain.push(Self::build_llm_backend(fallback)?);
+            log::info!("🔁 已配置备用模型: {}", fallback.model);
+        }
+
+        // 短期记忆相关的设置只在启动时生效，热重载沿用原有的记忆
+        let (short_term_memory, token_counter, group_timeline) = match previous {
+            Some(previous) => {
+                Self::warn_restart_required(&previous.config, &config);
+                (
+                    previous.short_term_memory.clone(),
+                    previous.token_counter.clone(),
+                    previous.group_timeline.clone(),
+                )
+            }
+            None => {
+                let token_counter = create_token_counter(&config.memory.tokenizer, config_dir);
+                let short_term_memory = Self::build_short_term_memory(
+                    &config,
+                    config_dir,
+                    token_counter.clone(),
+                );
+                let group_timeline = Arc::new(GroupTimeline::new(config.group.clone()));
+                (Arc::new(short_term_memory), token_counter, group_timeline)
+            }
         };
-        
-        // 初始化 LLM 客户端
-        let llm = LlmClient::new(
-            config.llm.apikey.clone(),
-            config.llm.url.clone(),
-            config.llm.model.clone(),
-            llm_params,
-        )
-        .map_err(|e| anyhow::anyhow!("LLM 客户端初始化失败: {}", e))?;
 
-        // 初始化短期记忆
-        let short_term_memory = Memory::new(config.memory.history_limit, config.memory.history_timeout);
+        // 初始化滚动摘要（使用主模型）
+        let summarizer = if config.memory.summary.enabled {
+            log::info!(
+                "✅ 短期记忆滚动摘要已启用，每挤出 {} 条消息更新一次",
+                config.memory.summary.batch_size
+            );
+            Some(Arc::new(HistorySummarizer::new(
+                llm_chain[0].clone(),
+                config.memory.summary.clone(),
+            )))
+        } else {
+            None
+        };
 
-        // 初始化长期记忆（RAG）
-        let long_term_memory = if config.memory.rag.enabled {
+        // 初始化长期记忆（RAG），数据库和 RAG 配置未变时沿用原有连接
+        let reused_rag = previous.filter(|previous| {
+            same_settings(&previous.config.db, &config.db)
+                && same_settings(&previous.config.memory.rag, &config.memory.rag)
+        });
+        let long_term_memory = if let Some(previous) = reused_rag {
+            previous.long_term_memory.clone()
+        } else if config.memory.rag.enabled {
             match TemporalMemory::new(
                 config.db.postgres.clone(),
                 config.memory.rag.embedding.clone(),
                 config.memory.rag.clone(),
+                token_counter.clone(),
             )
             .await
             {
@@ -67,7 +148,10 @@ impl ChatBot {
                         config.memory.rag.top_n,
                         config.memory.rag.window_size
                     );
-                    Some(Arc::new(rag))
+                    let rag = Arc::new(rag);
+                    // 在后台为旧记忆补建检索词，并在嵌入模型变更后重新生成向量
+                    rag.spawn_backfill();
+                    Some(rag)
                 }
                 Err(e) => {
                     log::error!("❌ RAG 初始化失败: {}", e);
@@ -98,23 +182,14 @@ impl ChatBot {
                 None
             };
 
-        // 初始化 MCP 管理器
-        let mcp_manager = if config.mcp.enabled && !config.mcp.path.is_empty() {
-            // 计算 MCP 配置文件的路径（相对于 config.json 所在目录）
-            let mcp_config_path = if let Some(dir) = config_dir {
-                dir.join(&config.mcp.path)
-            } else {
-                std::path::PathBuf::from(&config.mcp.path)
-            };
-            
-            log::info!("📂 加载 MCP 配置: {:?}", mcp_config_path);
-            
-            match McpManager::from_config_file(&mcp_config_path).await {
-                Ok(manager) => {
-                    let tools = manager.get_all_tools().await;
-                    log::info!("✅ MCP 已启用，共 {} 个工具", tools.len());
-                    Some(Arc::new(manager))
-                }
+        // 初始化 MCP 管理器，配置未变时沿用已连接的服务
+        let reused_mcp = previous
+            .filter(|previous| !reload_mcp && same_settings(&previous.config.mcp, &config.mcp));
+        let mcp_manager = if let Some(previous) = reused_mcp {
+            previous.mcp_manager.clone()
+        } else if config.mcp.enabled && !config.mcp.path.is_empty() {
+            match Self::load_mcp_manager(&config, config_dir).await {
+                Ok(manager) => Some(manager),
                 Err(e) => {
                     log::error!("❌ MCP 初始化失败: {}", e);
                     log::warn!("   将禁用工具调用功能");
@@ -126,22 +201,239 @@ impl ChatBot {
             None
         };
 
+        // 初始化 token 用量统计（启用 RAG 时写入数据库），热重载时保留进程内计数
+        let usage_tracker = match (previous, reused_rag.is_some()) {
+            (Some(previous), true) => previous.usage_tracker.clone(),
+            (Some(previous), false) => {
+                Arc::new(previous.usage_tracker.rebind(long_term_memory.clone()))
+            }
+            (None, _) => Arc::new(UsageTracker::new(long_term_memory.clone())),
+        };
+        if config.budget.enabled {
+            log::info!("💰 Token 预算限制已启用");
+        }
+
+        // 初始化图片理解
+        let vision = if config.vision.enabled {
+            match VisionProcessor::new(config.vision.clone()) {
+                Ok(processor) => {
+                    log::info!("✅ 图片理解已启用，视觉模型: {}", config.vision.model);
+                    Some(Arc::new(processor))
+                }
+                Err(e) => {
+                    log::error!("❌ 视觉模型初始化失败: {}", e);
+                    log::warn!("   图片将以占位文本代替");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
         Ok(Self {
-            llm: Arc::new(llm),
-            short_term_memory: Arc::new(short_term_memory),
+            llm_chain: RwLock::new(llm_chain),
+            override_backends: Mutex::new(HashMap::new()),
+            short_term_memory,
+            usage_tracker,
+            vision,
             long_term_memory,
             memory_evaluator,
             mcp_manager,
+            summarizer,
+            group_timeline,
+            token_counter,
             config: Arc::new(config),
+            config_dir: config_dir.map(Path::to_path_buf),
         })
     }
 
+    /// 创建短期记忆（按配置启用持久化和滚动摘要所需的挤出记录）
+    fn build_short_term_memory(
+        config: &Config,
+        config_dir: Option<&Path>,
+        token_counter: Arc<dyn TokenCounter>,
+    ) -> Memory {
+        let mut short_term_memory = Memory::new(
+            config.memory.history_limit,
+            config.memory.history_timeout,
+            token_counter,
+        );
+
+        // 短期记忆持久化（路径相对于 config.json 所在目录）
+        if config.memory.persistence.enabled {
+            let store_path = match config_dir {
+                Some(dir) => dir.join(&config.memory.persistence.path),
+                None => PathBuf::from(&config.memory.persistence.path),
+            };
+            log::info!("💾 短期记忆持久化已启用: {:?}", store_path);
+            short_term_memory = short_term_memory.with_store(Arc::new(JsonlStore::new(store_path)));
+        }
+
+        if config.memory.summary.enabled {
+            short_term_memory = short_term_memory.with_evicted_tracking();
+        }
+
+        short_term_memory
+    }
+
+    /// 热重载时提示只在启动时生效的配置项
+    fn warn_restart_required(old: &Config, new: &Config) {
+        let mut changed = Vec::new();
+        if old.memory.history_limit != new.memory.history_limit {
+            changed.push("memory.history_limit");
+        }
+        if old.memory.history_timeout != new.memory.history_timeout {
+            changed.push("memory.history_timeout");
+        }
+        if !same_settings(&old.memory.tokenizer, &new.memory.tokenizer) {
+            changed.push("memory.tokenizer");
+        }
+        if !same_settings(&old.memory.persistence, &new.memory.persistence) {
+            changed.push("memory.persistence");
+        }
+        if old.memory.summary.enabled != new.memory.summary.enabled {
+            changed.push("memory.summary.enabled");
+        }
+        if !same_settings(&old.group, &new.group) {
+            changed.push("group");
+        }
+        if !same_settings(&old.maintenance, &new.maintenance) {
+            changed.push("maintenance");
+        }
+        if !same_settings(&old.hot_reload, &new.hot_reload) {
+            changed.push("hot_reload");
+        }
+
+        if !changed.is_empty() {
+            log::warn!("⚠️  以下配置项需要重启插件才能生效: {}", changed.join(", "));
+        }
+    }
+
+    /// 加载 MCP 配置文件并连接所有服务器
+    ///
+    /// # 参数
+    /// - `config`: 配置对象
+    /// - `config_dir`: config.json 所在目录，MCP 配置路径相对于该目录
+    async fn load_mcp_manager(
+        config: &Config,
+        config_dir: Option<&Path>,
+    ) -> Result<Arc<McpManager>> {
+        // 计算 MCP 配置文件的路径（相对于 config.json 所在目录）
+        let mcp_config_path = if let Some(dir) = config_dir {
+            dir.join(&config.mcp.path)
+        } else {
+            PathBuf::from(&config.mcp.path)
+        };
+
+        log::info!("📂 加载 MCP 配置: {:?}", mcp_config_path);
+
+        let manager = McpManager::from_config_file(&mcp_config_path).await?;
+        let tools = manager.get_all_tools().await;
+        log::info!("✅ MCP 已启用，共 {} 个工具", tools.len());
+        Ok(Arc::new(manager))
+    }
+
+    /// 当前的 LLM 后端链
+    fn llm_chain(&self) -> Vec<Arc<dyn LlmBackend>> {
+        self.llm_chain.read().unwrap().clone()
+    }
+
+    /// 应用会话覆盖后的 LLM 后端链
+    ///
+    /// 覆盖了模型或温度时，用主模型配置创建新的主模型后端，备用模型不变。
+    /// 只覆盖温度时沿用当前的主模型（可能已被 `/xs model` 切换）
+    fn llm_chain_for(&self, overrides: &ChatOverride) -> Result<Vec<Arc<dyn LlmBackend>>> {
+        let mut chain = self.llm_chain();
+        if overrides.model.is_none() && overrides.temperature.is_none() {
+            return Ok(chain);
+        }
+
+        let mut llm_config = self.config.llm.clone();
+        llm_config.model = chain[0].model().to_string();
+        if let Some(model) = &overrides.model {
+            llm_config.model = model.clone();
+        }
+        if let Some(temperature) = overrides.temperature {
+            llm_config.temperature = Some(temperature);
+        }
+
+        let cache_key = format!("{}|{:?}", llm_config.model, llm_config.temperature);
+        let mut backends = self.override_backends.lock().unwrap();
+        let backend = match backends.get(&cache_key) {
+            Some(backend) => backend.clone(),
+            None => {
+                let backend = Self::build_llm_backend(&llm_config)?;
+                backends.insert(cache_key, backend.clone());
+                backend
+            }
+        };
+        chain[0] = backend;
+        Ok(chain)
+    }
+
+    /// 群聊消息是否应该触发回复（按群/用户覆盖的触发规则，否则使用全局规则）
+    ///
+    /// # 参数
+    /// - `user_id`: 发送者QQ号
+    /// - `group_id`: 群号
+    /// - `text`: 消息文本
+    /// - `mentioned`: 消息是否 @ 了机器人
+    pub fn should_respond(&self, user_id: i64, group_id: i64, text: &str, mentioned: bool) -> bool {
+        let overrides = self.config.overrides.resolve(user_id, Some(group_id));
+        overrides
+            .trigger
+            .as_ref()
+            .unwrap_or(&self.config.trigger)
+            .matches(text, mentioned, &self.config.persona)
+    }
+
+    /// 计算短期记忆可用的 token 预算
+    ///
+    /// 上下文长度扣除回复预留、系统提示词（含长期记忆）和当前用户输入
+    fn history_token_budget(&self, system_prompt: &str, user_input: &str) -> usize {
+        let reply_reserve = self
+            .config
+            .llm
+            .max_tokens
+            .map(|t| t as usize)
+            .unwrap_or(DEFAULT_REPLY_RESERVE_TOKENS);
+        self.config.llm.context_length.saturating_sub(
+            reply_reserve
+                + self.token_counter.count(system_prompt)
+                + self.token_counter.count(user_input),
+        )
+    }
+
+    /// 根据配置创建 LLM 后端
+    fn build_llm_backend(llm_config: &LlmConfig) -> Result<Arc<dyn LlmBackend>> {
+        let llm_params = LlmRequestParams {
+            temperature: llm_config.temperature,
+            top_p: llm_config.top_p,
+            max_tokens: llm_config.max_tokens,
+            presence_penalty: llm_config.presence_penalty,
+            frequency_penalty: llm_config.frequency_penalty,
+            echo_reasoning: llm_config.reasoning.echo,
+            thinking_budget: llm_config.reasoning.budget_tokens,
+        };
+
+        create_backend(
+            llm_config.provider,
+            llm_config.apikey.clone(),
+            llm_config.url.clone(),
+            llm_config.model.clone(),
+            llm_params,
+            llm_config.retry.clone(),
+        )
+        .map_err(|e| anyhow::anyhow!("LLM 客户端初始化失败 ({}): {}", llm_config.model, e))
+    }
+
     /// 处理用户消息并返回AI回复
     ///
     /// # 参数
     /// - `user_id`: 用户QQ号
     /// - `group_id`: 群号（None表示私聊）
     /// - `user_input`: 用户输入文本
+    /// - `images`: 消息中的图片来源（url 或本地文件）
     /// - `sender_name`: 发送者昵称
     ///
     /// # 返回
@@ -151,13 +443,142 @@ impl ChatBot {
         user_id: i64,
         group_id: Option<i64>,
         user_input: &str,
+        images: &[String],
         sender_name: &str,
     ) -> Result<String> {
-        let conversation_key = Memory::generate_key(user_id, group_id);
+        self.chat_inner(user_id, group_id, user_input, images, sender_name, None)
+            .await
+    }
+
+    /// 以流式方式处理用户消息
+    ///
+    /// LLM 的增量输出会被攒成句子，每凑满一句就调用一次 `on_segment`，
+    /// 适合边生成边发送消息。返回值为完整的 AI 回复。
+    ///
+    /// # 参数
+    /// - `user_id`: 用户QQ号
+    /// - `group_id`: 群号（None表示私聊）
+    /// - `user_input`: 用户输入文本
+    /// - `images`: 消息中的图片来源（url 或本地文件）
+    /// - `sender_name`: 发送者昵称
+    /// - `on_segment`: 分段回调
+    pub async fn chat_stream<F>(
+        &self,
+        user_id: i64,
+        group_id: Option<i64>,
+        user_input: &str,
+        images: &[String],
+        sender_name: &str,
+        mut on_segment: F,
+    ) -> Result<String>
+    where
+        F: FnMut(&str) + Send,
+    {
+        let mut splitter = SentenceSplitter::new(STREAM_SEGMENT_MIN_CHARS);
+
+        let result = {
+            let mut on_delta = |delta: &str| {
+                for segment in splitter.push(delta) {
+                    on_segment(&segment);
+                }
+            };
+            self.chat_inner(
+                user_id,
+                group_id,
+                user_input,
+                images,
+                sender_name,
+                Some(&mut on_delta),
+            )
+            .await
+        };
+
+        if let Some(rest) = splitter.finish() {
+            on_segment(&rest);
+        }
+
+        result
+    }
+
+    /// 是否启用了流式输出
+    pub fn is_streaming(&self) -> bool {
+        self.config.llm.stream
+    }
+
+    /// 处理用户消息的完整流程，`on_delta` 不为空时使用流式请求
+    async fn chat_inner(
+        &self,
+        user_id: i64,
+        group_id: Option<i64>,
+        user_input: &str,
+        images: &[String],
+        sender_name: &str,
+        mut on_delta: Option<&mut (dyn FnMut(&str) + Send)>,
+    ) -> Result<String> {
+        let conversation_key = self.conversation_key(user_id, group_id);
+        // 合并按群/用户配置的覆盖
+        let overrides = self.config.overrides.resolve(user_id, group_id);
+        let character_prompt = overrides
+            .prompt
+            .as_deref()
+            .unwrap_or(&self.config.memory.prompt);
+        let long_term_memory = self
+            .long_term_memory
+            .as_ref()
+            .filter(|_| overrides.rag_enabled.unwrap_or(true));
+        // 共享上下文的群：消息带上发送者，供全群共用的会话区分说话人
+        let shared_group = group_id.filter(|gid| self.group_timeline.is_shared(*gid));
+        let with_speaker = |text: &str| match shared_group {
+            Some(_) => format!("{}({}): {}", sender_name, user_id, text),
+            None => text.to_string(),
+        };
+
+        // 步骤0: 检查 token 预算，超额时礼貌拒绝
+        match self
+            .usage_tracker
+            .check_budget(&self.config.budget, user_id, group_id)
+            .await
+        {
+            Ok(BudgetStatus::Exceeded { scope, period, used, limit }) => {
+                log::info!(
+                    "💰 {} {}额度已用完 ({}/{} tokens)，拒绝请求",
+                    scope,
+                    period,
+                    used,
+                    limit
+                );
+                let reply = self.config.budget.exceeded_message.clone();
+                if let Some(callback) = on_delta.as_deref_mut() {
+                    callback(&reply);
+                }
+                return Ok(reply);
+            }
+            Ok(BudgetStatus::Available) => {}
+            Err(e) => log::warn!("⚠️  查询 token 用量失败: {}", e),
+        }
+
+        // 步骤0.5: 获取图片，启用视觉模型时编码为 data URL
+        let image_urls = match (&self.vision, images.is_empty()) {
+            (Some(vision), false) => vision.prepare_images(images).await,
+            (None, false) => {
+                log::info!("🖼️  收到 {} 张图片，但图片理解未启用", images.len());
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+        // 记忆与检索使用的文本：图片以占位文本代替
+        let placeholder_text = if images.is_empty() {
+            user_input.to_string()
+        } else {
+            memory_text_with_images(user_input, None)
+        };
 
         // 步骤1: 如果启用了数据库，且短期记忆未初始化，则先初始化短期记忆
-        if !self.short_term_memory.is_initialized(&conversation_key) {
-            if let Some(rag) = &self.long_term_memory {
+        // （共享上下文的群没有按成员区分的数据库记录，跳过）
+        if shared_group.is_none()
+            && !self.short_term_memory.is_initialized(&conversation_key)
+        {
+            if let Some(rag) = long_term_memory {
                 if let Ok(recent_msgs) = rag
                     .get_recent_messages(user_id, group_id, self.config.memory.history_limit)
                     .await
@@ -191,14 +612,12 @@ impl ChatBot {
         let short_term_ids = self.short_term_memory.get_message_ids(&conversation_key);
 
         // 步骤3: 检索长期记忆（排除短期记忆）
-        let long_term_memories = if self.long_term_memory.is_some() {
-            let rag = self.long_term_memory.as_ref().unwrap();
-
+        let long_term_memories = if let Some(rag) = long_term_memory {
             // 检索长期记忆（排除短期记忆）
             match rag
                 .get_contextual_memory(
                     user_id,
-                    user_input,
+                    &placeholder_text,
                     group_id,
                     Some(self.config.memory.rag.top_n),
                     Some(self.config.memory.rag.window_size),
@@ -221,29 +640,48 @@ impl ChatBot {
             None
         };
 
-        // 步骤4: 使用长期记忆构建system prompt
-        let system_prompt = if let Some(ref memories) = long_term_memories {
+        // 步骤4: 使用长期记忆构建system prompt，并附上早前对话摘要
+        let mut system_prompt = if let Some(ref memories) = long_term_memories {
             if !memories.is_empty() {
                 PromptTemplate::build_system_prompt(
-                    &self.config.memory.prompt,
+                    &self.config.persona,
+                    character_prompt,
                     Some(memories),
                     self.config.memory.rag.max_memory_tokens,
+                    self.token_counter.as_ref(),
                 )
             } else {
                 PromptTemplate::build_system_prompt(
-                    &self.config.memory.prompt,
+                    &self.config.persona,
+                    character_prompt,
                     None,
                     self.config.memory.rag.max_memory_tokens,
+                    self.token_counter.as_ref(),
                 )
             }
         } else {
-            PromptTemplate::build_simple_system_prompt(&self.config.memory.prompt)
+            PromptTemplate::build_simple_system_prompt(&self.config.persona, character_prompt)
         };
+        if let Some(summary) = self.short_term_memory.get_summary(&conversation_key) {
+            PromptTemplate::append_history_summary(&mut system_prompt, &summary);
+        }
+        if let Some(gid) = shared_group {
+            let recent = self.group_timeline.recent(gid);
+            if !recent.is_empty() {
+                PromptTemplate::append_group_context(&mut system_prompt, &recent);
+            }
+        }
 
-        // 步骤5: 构建消息历史（使用 LlmMessage 格式）
+        // 步骤5: 构建消息历史（使用 LlmMessage 格式），按剩余 token 预算截取短期记忆
+        let history_budget = self.history_token_budget(&system_prompt, &placeholder_text);
         let history = self
             .short_term_memory
-            .get_history(&conversation_key, &system_prompt);
+            .get_history(&conversation_key, &system_prompt, history_budget);
+        log::debug!(
+            "🧮 短期记忆共约 {} tokens，预算 {} tokens",
+            self.short_term_memory.get_token_count(&conversation_key),
+            history_budget
+        );
 
         // 转换为 LlmMessage 格式
         let mut messages: Vec<LlmMessage> = history
@@ -251,8 +689,19 @@ impl ChatBot {
             .map(|(role, content)| LlmMessage::from_tuple(&role, &content))
             .collect();
 
-        // 添加当前用户输入
-        messages.push(LlmMessage::user(user_input));
+        // 添加当前用户输入（有图片时另备一份带图片的消息交给视觉模型）
+        let llm_chain = self.llm_chain_for(&overrides)?;
+        let vision_messages = if image_urls.is_empty() {
+            None
+        } else {
+            let mut with_images = messages.clone();
+            with_images.push(LlmMessage::user_with_images(
+                &with_speaker(user_input),
+                image_urls.clone(),
+            ));
+            Some(with_images)
+        };
+        messages.push(LlmMessage::user(&with_speaker(&placeholder_text)));
 
         log::info!(
             "💭 对话 key: {}, 短期记忆: {} 条, 当前问题: 1 条",
@@ -260,42 +709,138 @@ impl ChatBot {
             messages.len() - 2 // 减去 system prompt 和当前用户消息
         );
 
-        // 步骤6: 请求LLM（支持工具调用循环）
-        let response = self.completion_with_tools(&mut messages).await?;
+        // 步骤6: 请求LLM（支持工具调用循环），同时为图片生成描述
+        // 有图片时先交给视觉模型（不提供工具），失败时改用文本模型链，图片以占位文本代替
+        let completion = async {
+            if let (Some(vision), Some(mut vision_messages)) = (&self.vision, vision_messages) {
+                log::info!("🖼️  本轮包含 {} 张图片，使用视觉模型", image_urls.len());
+                let vision_chain = [vision.client()];
+                let mut emitted = false;
+                let result = match on_delta.as_deref_mut() {
+                    Some(callback) => {
+                        let mut tracked = |delta: &str| {
+                            emitted = true;
+                            callback(delta);
+                        };
+                        self.completion_with_tools(
+                            &vision_chain,
+                            &mut vision_messages,
+                            Some(&[]),
+                            Some(&mut tracked),
+                        )
+                        .await
+                    }
+                    None => {
+                        self.completion_with_tools(
+                            &vision_chain,
+                            &mut vision_messages,
+                            Some(&[]),
+                            None,
+                        )
+                        .await
+                    }
+                };
+                match result {
+                    Ok((response, usage)) => return Ok((response, usage, vision.client())),
+                    // 已经输出了部分回复，不再切换模型，避免重复发送
+                    Err(e) if emitted => return Err(e),
+                    Err(e) => log::warn!("⚠️  视觉模型调用失败: {}，改用文本模型回复", e),
+                }
+            }
+
+            let (response, usage) = self
+                .completion_with_tools(
+                    &llm_chain,
+                    &mut messages,
+                    overrides.allowed_tools.as_deref(),
+                    on_delta,
+                )
+                .await?;
+            Ok::<_, anyhow::Error>((response, usage, llm_chain[0].clone()))
+        };
+        let caption_future = async {
+            match &self.vision {
+                Some(vision) if vision.caption_enabled() && !image_urls.is_empty() => {
+                    match vision.caption(&image_urls).await {
+                        Ok(caption) => Some(caption),
+                        Err(e) => {
+                            log::warn!("⚠️  {}", e);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            }
+        };
+        let (completion, caption) = tokio::join!(completion, caption_future);
+        let (response, usage, backend) = completion?;
 
         log::info!("🤖 AI回复: {}", response);
 
-        // 步骤7: LLM成功响应后，保存当前对话到短期记忆
+        // 记录 token 用量（异步执行，不阻塞回复）
+        self.record_usage_async(user_id, group_id, backend.model(), usage);
+
+        // 步骤7: LLM成功响应后，保存当前对话到短期记忆（图片记录为描述或占位文本）
+        let memory_text = match caption.as_deref() {
+            Some(caption) => {
+                log::info!("🖼️  图片描述: {}", caption);
+                memory_text_with_images(user_input, Some(caption))
+            }
+            None => placeholder_text,
+        };
         let user_message_id = self
             .short_term_memory
-            .add_user_message(&conversation_key, user_input.to_string());
+            .add_user_message(&conversation_key, with_speaker(&memory_text));
 
         let assistant_message_id = self
             .short_term_memory
             .add_assistant_message(&conversation_key, response.clone());
 
+        // 被挤出短期记忆的旧对话异步合并进滚动摘要
+        self.summarize_evicted_async(&conversation_key);
+
         // 步骤8: 使用memory_evaluator评估对话价值，按需存入长期记忆
-        // 这一步异步执行，不阻塞回复
-        self.evaluate_and_store_memory_async(
-            user_input.to_string(),
-            response.clone(),
-            sender_name.to_string(),
-            user_id,
-            group_id,
-            user_message_id,
-            assistant_message_id,
-        );
+        // 这一步异步执行，不阻塞回复（会话关闭了 RAG 时跳过）
+        if long_term_memory.is_some() {
+            self.evaluate_and_store_memory_async(
+                memory_text,
+                response.clone(),
+                sender_name.to_string(),
+                user_id,
+                group_id,
+                user_message_id,
+                assistant_message_id,
+            );
+        }
 
         Ok(response)
     }
 
     /// 执行带工具调用的 LLM 请求
     ///
-    /// 这个方法会循环处理工具调用，直到 LLM 不再请求工具调用或达到最大迭代次数
-    async fn completion_with_tools(&self, messages: &mut Vec<LlmMessage>) -> Result<String> {
+    /// 这个方法会循环处理工具调用，直到 LLM 不再请求工具调用或达到最大迭代次数。
+    /// 传入 `on_delta` 时每轮都使用流式请求，增量文本会实时回调。
+    /// `allowed_tools` 不为空时只向模型提供其中列出的工具。
+    ///
+    /// # 返回
+    /// (最终回复, 所有轮次累计的 token 用量)
+    async fn completion_with_tools(
+        &self,
+        llm_chain: &[Arc<dyn LlmBackend>],
+        messages: &mut Vec<LlmMessage>,
+        allowed_tools: Option<&[String]>,
+        mut on_delta: Option<&mut (dyn FnMut(&str) + Send)>,
+    ) -> Result<(String, TokenUsage)> {
         // 获取可用工具
         let tools = if let Some(mcp) = &self.mcp_manager {
-            let openai_tools = mcp.get_openai_tools().await;
+            let mut openai_tools = mcp.get_openai_tools().await;
+            if let Some(allowed) = allowed_tools {
+                openai_tools.retain(|tool| {
+                    tool["function"]["name"]
+                        .as_str()
+                        .is_some_and(|name| allowed.iter().any(|a| a == name))
+                });
+            }
             if openai_tools.is_empty() {
                 None
             } else {
@@ -306,14 +851,24 @@ impl ChatBot {
         };
 
         let mut final_response = String::new();
+        let mut total_usage = TokenUsage::default();
 
         for iteration in 0..self.config.mcp.max_tool_iterations {
             // 发送请求
-            let response: CompletionResponse = self
-                .llm
-                .chat_completion(messages.clone(), tools.as_ref())
-                .await
-                .map_err(|e| anyhow::anyhow!("LLM API 调用失败: {}", e))?;
+            let response = self
+                .request_completion(llm_chain, messages, tools.as_ref(), on_delta.as_deref_mut())
+                .await?;
+
+            if let Some(usage) = response.usage {
+                total_usage += usage;
+            }
+
+            // 思考内容只记录日志，不会发送给用户
+            if let Some(reasoning) = &response.reasoning {
+                if self.config.llm.reasoning.log {
+                    log::info!("🧠 思考内容: {}", reasoning.content);
+                }
+            }
 
             // 如果有内容，累积到最终响应
             if let Some(content) = &response.content {
@@ -333,65 +888,213 @@ impl ChatBot {
                 response.tool_calls.len()
             );
 
-            // 添加助手消息（包含工具调用）
-            messages.push(LlmMessage::assistant_with_tool_calls(
-                response.content.as_deref(),
-                response.tool_calls.clone(),
-            ));
+            // 添加助手消息（包含工具调用），思考内容是否回传由各后端按协议决定
+            messages.push(
+                LlmMessage::assistant_with_tool_calls(
+                    response.content.as_deref(),
+                    response.tool_calls.clone(),
+                )
+                .with_reasoning(response.reasoning.clone()),
+            );
 
-            // 处理每个工具调用
-            for tool_call in &response.tool_calls {
-                let tool_name = &tool_call.function.name;
-                let arguments = &tool_call.function.arguments;
+            // 并发执行工具调用（buffered 保证结果顺序与请求顺序一致）
+            let concurrency = self.config.mcp.max_concurrent_tool_calls.max(1);
+            let tool_results: Vec<String> = futures_util::stream::iter(&response.tool_calls)
+                .map(|tool_call| self.execute_tool_call(tool_call, allowed_tools))
+                .buffered(concurrency)
+                .collect()
+                .await;
 
-                log::info!("🔧 调用工具: {} 参数: {}", tool_name, arguments);
+            // 按工具调用的顺序添加工具响应消息
+            for (tool_call, tool_result) in response.tool_calls.iter().zip(tool_results) {
+                messages.push(LlmMessage::tool(&tool_result, &tool_call.id));
+            }
+        }
 
-                // 解析参数
-                let args: Value = serde_json::from_str(arguments).unwrap_or(Value::Null);
+        if final_response.is_empty() {
+            return Err(anyhow::anyhow!("LLM 没有返回有效内容"));
+        }
 
-                // 调用 MCP 工具
-                let tool_result = if let Some(mcp) = &self.mcp_manager {
-                    match mcp.call_tool(tool_name, args).await {
-                        Ok(result) => {
-                            if result.is_error {
-                                format!("工具调用错误: {:?}", result.content)
-                            } else {
-                                // 提取文本内容
-                                result
-                                    .content
-                                    .iter()
-                                    .filter_map(|c| {
-                                        if let McpContent::Text { text } = c {
-                                            Some(text.clone())
-                                        } else {
-                                            None
-                                        }
-                                    })
-                                    .collect::<Vec<_>>()
-                                    .join("\n")
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("❌ 工具 {} 调用失败: {}", tool_name, e);
-                            format!("工具调用失败: {}", e)
-                        }
+        Ok((final_response, total_usage))
+    }
+
+    /// 执行单个工具调用，返回发送给模型的工具结果文本
+    ///
+    /// 调用失败或超时时返回错误描述，交给模型自行处理
+    async fn execute_tool_call(
+        &self,
+        tool_call: &ToolCall,
+        allowed_tools: Option<&[String]>,
+    ) -> String {
+        let tool_name = &tool_call.function.name;
+        let arguments = &tool_call.function.arguments;
+
+        log::info!("🔧 调用工具: {} 参数: {}", tool_name, arguments);
+
+        // 模型可能请求没有提供给它的工具
+        if allowed_tools.is_some_and(|allowed| !allowed.iter().any(|a| a == tool_name)) {
+            log::warn!("⚠️  工具 {} 在当前会话中不可用", tool_name);
+            return format!("工具 {} 在当前会话中不可用", tool_name);
+        }
+
+        // 解析参数
+        let args: Value = serde_json::from_str(arguments).unwrap_or(Value::Null);
+
+        // 调用 MCP 工具
+        let tool_result = if let Some(mcp) = &self.mcp_manager {
+            let timeout = Duration::from_secs(self.config.mcp.tool_call_timeout);
+            match tokio::time::timeout(timeout, mcp.call_tool(tool_name, args)).await {
+                Ok(Ok(result)) => {
+                    if result.is_error {
+                        format!("工具调用错误: {:?}", result.content)
+                    } else {
+                        // 提取文本内容
+                        result
+                            .content
+                            .iter()
+                            .filter_map(|c| {
+                                if let McpContent::Text { text } = c {
+                                    Some(text.clone())
+                                } else {
+                                    None
+                                }
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n")
                     }
-                } else {
-                    "MCP 未启用".to_string()
+                }
+                Ok(Err(e)) => {
+                    log::error!("❌ 工具 {} 调用失败: {}", tool_name, e);
+                    format!("工具调用失败: {}", e)
+                }
+                Err(_) => {
+                    log::error!(
+                        "❌ 工具 {} 调用超时（超过 {} 秒）",
+                        tool_name,
+                        self.config.mcp.tool_call_timeout
+                    );
+                    format!("工具调用超时（超过 {} 秒）", self.config.mcp.tool_call_timeout)
+                }
+            }
+        } else {
+            "MCP 未启用".to_string()
+        };
+
+        log::info!("📥 工具 {} 返回: {}", tool_name, tool_result);
+        tool_result
+    }
+
+    /// 发送一次 LLM 请求，主模型失败时依次尝试备用模型
+    ///
+    /// 流式模式下如果已经输出了部分内容，则不再切换模型，避免重复发送。
+    /// 模型只返回思考内容、没有正文时先重试同一模型，仍然没有正文再切换到备用模型
+    async fn request_completion(
+        &self,
+        llm_chain: &[Arc<dyn LlmBackend>],
+        messages: &[LlmMessage],
+        tools: Option<&Vec<Value>>,
+        mut on_delta: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> Result<CompletionResponse> {
+        let mut last_error = String::new();
+
+        for (index, llm) in llm_chain.iter().enumerate() {
+            for _ in 0..=REASONING_ONLY_RETRIES {
+                let mut emitted = false;
+                let result = match on_delta.as_deref_mut() {
+                    Some(callback) => {
+                        let mut tracked = |delta: &str| {
+                            emitted = true;
+                            callback(delta);
+                        };
+                        llm.chat_completion_stream(messages.to_vec(), tools, &mut tracked)
+                            .await
+                    }
+                    None => llm.chat_completion(messages.to_vec(), tools).await,
                 };
 
-                log::info!("📥 工具 {} 返回: {}", tool_name, tool_result);
+                match result {
+                    Ok(response) if response.is_reasoning_only() => {
+                        log::warn!("⚠️  模型 {} 只返回了思考内容，没有返回正文", llm.model());
+                        last_error = "模型只返回了思考内容，没有返回正文".to_string();
+                    }
+                    Ok(response) => {
+                        if index > 0 {
+                            log::info!("🔁 已由备用模型 {} 完成响应", llm.model());
+                        }
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        if emitted {
+                            return Err(anyhow::anyhow!("LLM API 调用失败: {}", e));
+                        }
+                        last_error = e.to_string();
+                        break;
+                    }
+                }
+            }
 
-                // 添加工具响应消息
-                messages.push(LlmMessage::tool(&tool_result, &tool_call.id));
+            if let Some(next) = llm_chain.get(index + 1) {
+                log::warn!(
+                    "⚠️  模型 {} 调用失败: {}，切换到备用模型 {}",
+                    llm.model(),
+                    last_error,
+                    next.model()
+                );
             }
         }
 
-        if final_response.is_empty() {
-            return Err(anyhow::anyhow!("LLM 没有返回有效内容"));
+        Err(anyhow::anyhow!("LLM API 调用失败: {}", last_error))
+    }
+
+    /// 异步记录 token 用量
+    fn record_usage_async(
+        &self,
+        user_id: i64,
+        group_id: Option<i64>,
+        model: &str,
+        usage: TokenUsage,
+    ) {
+        if usage.total_tokens == 0 {
+            return;
         }
 
-        Ok(final_response)
+        let tracker = self.usage_tracker.clone();
+        let model = model.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = tracker.record(user_id, group_id, &model, &usage).await {
+                log::warn!("⚠️  记录 token 用量失败: {}", e);
+            }
+        });
+    }
+
+    /// 异步把被挤出短期记忆的对话合并进滚动摘要
+    fn summarize_evicted_async(&self, conversation_key: &str) {
+        let Some(summarizer) = self.summarizer.clone() else {
+            return;
+        };
+
+        let evicted = self
+            .short_term_memory
+            .take_evicted(conversation_key, self.config.memory.summary.batch_size);
+        if evicted.is_empty() {
+            return;
+        }
+
+        let memory = self.short_term_memory.clone();
+        let key = conversation_key.to_string();
+        tokio::spawn(async move {
+            let previous = memory.get_summary(&key);
+            match summarizer.summarize(previous.as_deref(), &evicted).await {
+                Ok(summary) => {
+                    log::info!("📝 对话摘要已更新（合并 {} 条消息）: {}", evicted.len(), summary);
+                    memory.finish_summary(&key, summary);
+                }
+                Err(e) => {
+                    log::warn!("⚠️  对话摘要失败，下次重试: {}", e);
+                    memory.restore_evicted(&key, evicted);
+                }
+            }
+        });
     }
 
     /// 异步评估并存储记忆
@@ -408,6 +1111,7 @@ impl ChatBot {
         if let Some(rag) = &self.long_term_memory {
             let rag = rag.clone();
             let memory_evaluator = self.memory_evaluator.clone();
+            let bot_name = self.config.persona.name.clone();
 
             tokio::spawn(async move {
                 if let Some(evaluator) = memory_evaluator {
@@ -450,7 +1154,7 @@ impl ChatBot {
                                         "assistant",
                                         &response,
                                         group_id,
-                                        Some("小诗"),
+                                        Some(&bot_name),
                                         None,
                                         Some(score),
                                         expires_at,
@@ -491,7 +1195,7 @@ impl ChatBot {
                                     "assistant",
                                     &response,
                                     group_id,
-                                    Some("小诗"),
+                                    Some(&bot_name),
                                     None,
                                     None,
                                     None,
@@ -528,7 +1232,7 @@ impl ChatBot {
                             "assistant",
                             &response,
                             group_id,
-                            Some("小诗"),
+                            Some(&bot_name),
                             None,
                             None,
                             None,
@@ -542,10 +1246,36 @@ impl ChatBot {
         }
     }
 
+    /// 会话标识：共享上下文的群全群共用一个会话，其他情况按用户区分
+    fn conversation_key(&self, user_id: i64, group_id: Option<i64>) -> String {
+        match group_id {
+            Some(gid) if self.group_timeline.is_shared(gid) => Memory::generate_group_key(gid),
+            _ => Memory::generate_key(user_id, group_id),
+        }
+    }
+
+    /// 记录群聊中没有 @ 机器人的发言
+    ///
+    /// 仅启用共享上下文的群会缓冲，机器人下次被 @ 时作为上下文提供给模型
+    ///
+    /// # 参数
+    /// - `group_id`: 群号
+    /// - `user_id`: 发送者QQ号
+    /// - `sender_name`: 发送者昵称
+    /// - `text`: 发言内容
+    pub fn record_group_message(
+        &self,
+        group_id: i64,
+        user_id: i64,
+        sender_name: &str,
+        text: &str,
+    ) {
+        self.group_timeline.record(group_id, user_id, sender_name, text);
+    }
+
     /// 清除指定会话的历史
-    #[allow(dead_code)]
     pub fn clear_history(&self, user_id: i64, group_id: Option<i64>) {
-        let conversation_key = Memory::generate_key(user_id, group_id);
+        let conversation_key = self.conversation_key(user_id, group_id);
         self.short_term_memory.clear_history(&conversation_key);
         log::info!("🗑️  已清除会话 {} 的短期记忆", conversation_key);
     }
@@ -556,12 +1286,11 @@ impl ChatBot {
             conversation_count: self.short_term_memory.get_conversation_count(),
             rag_enabled: self.long_term_memory.is_some(),
             mcp_enabled: self.mcp_manager.is_some(),
-            llm_model: self.config.llm.model.clone(),
+            llm_model: self.llm_chain.read().unwrap()[0].model().to_string(),
         }
     }
 
     /// 获取 MCP 工具列表
-    #[allow(dead_code)]
     pub async fn get_mcp_tools(&self) -> Vec<String> {
         if let Some(mcp) = &self.mcp_manager {
             mcp.get_all_tools()
@@ -580,7 +1309,6 @@ impl ChatBot {
     ///
     /// # 返回
     /// 清理的记录数量
-    #[allow(dead_code)]
     pub async fn cleanup_expired_memories(&self) -> Result<u64> {
         if let Some(rag) = &self.long_term_memory {
             rag.cleanup_expired_memories().await
@@ -588,6 +1316,114 @@ impl ChatBot {
             Ok(0)
         }
     }
+
+    /// 执行一次清理：删除过期记忆、按 `cleanup_days` 删除过旧的记忆、清理超时的短期会话
+    ///
+    /// 单项失败只记录日志，不影响其他清理项
+    pub async fn run_cleanup(&self) -> CleanupReport {
+        let mut report = CleanupReport {
+            stale_sessions: self.short_term_memory.cleanup_expired(),
+            ..CleanupReport::default()
+        };
+
+        if let Some(rag) = &self.long_term_memory {
+            match rag.cleanup_expired_memories().await {
+                Ok(count) => report.expired_memories = count,
+                Err(e) => log::warn!("⚠️  清理过期记忆失败: {}", e),
+            }
+            match rag.cleanup_old_memories().await {
+                Ok(count) => report.old_memories = count,
+                Err(e) => log::warn!("⚠️  清理超过 cleanup_days 的记忆失败: {}", e),
+            }
+        }
+
+        report
+    }
+
+    /// 维护向量索引（未启用 RAG 时不执行）
+    pub async fn maintain_vector_indexes(&self) -> Result<()> {
+        if let Some(rag) = &self.long_term_memory {
+            rag.maintain_vector_indexes().await?;
+        }
+        Ok(())
+    }
+
+    /// 是否启用了 RAG 长期记忆
+    pub fn rag_enabled(&self) -> bool {
+        self.long_term_memory.is_some()
+    }
+
+    /// 当前模型链中的模型名称（第一个为主模型）
+    pub fn model_names(&self) -> Vec<String> {
+        self.llm_chain
+            .read()
+            .unwrap()
+            .iter()
+            .map(|llm| llm.model().to_string())
+            .collect()
+    }
+
+    /// 切换主模型，沿用主模型的服务商、地址、密钥和请求参数
+    ///
+    /// 只影响对话，滚动摘要仍使用启动时的主模型
+    ///
+    /// # 参数
+    /// - `model`: 新的模型名称
+    pub fn switch_model(&self, model: &str) -> Result<()> {
+        let mut llm_config = self.config.llm.clone();
+        llm_config.model = model.to_string();
+        let backend = Self::build_llm_backend(&llm_config)?;
+
+        let mut chain = self.llm_chain.write().unwrap();
+        log::info!("🔀 主模型已切换: {} -> {}", chain[0].model(), model);
+        chain[0] = backend;
+        Ok(())
+    }
+
+    /// MCP 配置文件路径（未启用 MCP 时为 None）
+    pub fn mcp_config_path(&self) -> Option<PathBuf> {
+        if !self.config.mcp.enabled || self.config.mcp.path.is_empty() {
+            return None;
+        }
+        Some(match &self.config_dir {
+            Some(dir) => dir.join(&self.config.mcp.path),
+            None => PathBuf::from(&self.config.mcp.path),
+        })
+    }
+
+    /// 关闭被热重载替换下来的实例中不再使用的资源
+    ///
+    /// 目前只有 MCP 服务需要显式关闭（stdio 服务是子进程）
+    ///
+    /// # 参数
+    /// - `successor`: 替换当前实例的新实例
+    pub async fn retire(&self, successor: &ChatBot) {
+        if let Some(mcp) = &self.mcp_manager {
+            let shared = successor
+                .mcp_manager
+                .as_ref()
+                .is_some_and(|next| Arc::ptr_eq(mcp, next));
+            if !shared {
+                mcp.shutdown().await;
+                log::info!("🔌 已关闭旧的 MCP 服务");
+            }
+        }
+    }
+
+    /// 是否为管理员
+    pub fn is_admin(&self, user_id: i64) -> bool {
+        self.config.admin.admins.contains(&user_id)
+    }
+
+    /// 管理命令前缀
+    pub fn command_prefix(&self) -> &str {
+        &self.config.admin.prefix
+    }
+}
+
+/// 比较两份配置是否相同（按序列化结果比较）
+fn same_settings<T: serde::Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
 }
 
 /// 聊天统计信息
@@ -599,3 +1435,404 @@ pub struct ChatStats {
     pub mcp_enabled: bool,
     pub llm_model: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::test_support::{mock_config, MockLlmServer, MockReply, DEFAULT_REPLY};
+
+    async fn mock_chatbot(server: &MockLlmServer) -> ChatBot {
+        ChatBot::new(mock_config(server), "/tmp/config.json").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_chat_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text("你好，我是小诗！"));
+        let chatbot = mock_chatbot(&server).await;
+
+        let reply = chatbot.chat(10001, None, "你好", &[], "测试用户").await.unwrap();
+        assert_eq!(reply, "你好，我是小诗！");
+
+        // 第二轮对话带上短期记忆
+        let reply = chatbot.chat(10001, None, "再见", &[], "测试用户").await.unwrap();
+        assert_eq!(reply, DEFAULT_REPLY);
+
+        let requests = server.chat_requests();
+        let messages = requests[1]["messages"].as_array().unwrap();
+        let roles: Vec<&str> = messages.iter().map(|m| m["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert_eq!(messages[2]["content"], "你好，我是小诗！");
+    }
+
+    #[tokio::test]
+    async fn test_tool_loop_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::ToolCalls(vec![
+            ("search".to_string(), serde_json::json!({ "q": "天气" })),
+            ("weather".to_string(), serde_json::json!({ "city": "北京" })),
+        ]));
+        server.push_reply(MockReply::text("今天北京晴。"));
+        let chatbot = mock_chatbot(&server).await;
+
+        let reply = chatbot
+            .chat(10001, Some(20001), "今天天气怎么样", &[], "测试用户")
+            .await
+            .unwrap();
+        assert_eq!(reply, "今天北京晴。");
+
+        // 第二次请求包含助手的工具调用和按顺序排列的工具结果
+        let requests = server.chat_requests();
+        assert_eq!(requests.len(), 2);
+        let messages = requests[1]["messages"].as_array().unwrap();
+        let n = messages.len();
+        assert_eq!(messages[n - 3]["role"], "assistant");
+        assert_eq!(messages[n - 3]["tool_calls"].as_array().unwrap().len(), 2);
+        assert_eq!(messages[n - 2]["tool_call_id"], messages[n - 3]["tool_calls"][0]["id"]);
+        assert_eq!(messages[n - 1]["tool_call_id"], messages[n - 3]["tool_calls"][1]["id"]);
+        assert_eq!(messages[n - 1]["content"], "MCP 未启用");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_tool_calls_with_mock_mcp() {
+        let server = MockLlmServer::start().await;
+        let dir = std::env::temp_dir().join(format!("xiaoshi-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mcp_config = serde_json::json!({
+            "mcpServers": { "mock": { "transport": "streamable-http", "url": server.mcp_url() } }
+        });
+        std::fs::write(dir.join("mcp.json"), mcp_config.to_string()).unwrap();
+
+        let mut config = mock_config(&server);
+        config.mcp.enabled = true;
+        config.mcp.path = "mcp.json".to_string();
+        config.mcp.max_concurrent_tool_calls = 2;
+        config.mcp.tool_call_timeout = 1;
+        let chatbot = ChatBot::new(config, dir.join("config.json")).await.unwrap();
+
+        // b 比 a 先完成，d 超过 1 秒超时
+        let sleep = |label: &str, ms: u64| {
+            ("sleep".to_string(), serde_json::json!({ "label": label, "ms": ms }))
+        };
+        server.push_reply(MockReply::ToolCalls(vec![
+            sleep("a", 800),
+            sleep("b", 200),
+            sleep("c", 800),
+            sleep("d", 5000),
+        ]));
+        server.push_reply(MockReply::text("都查完了。"));
+
+        let started = std::time::Instant::now();
+        let reply = chatbot.chat(10001, None, "查一下", &[], "测试用户").await.unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(reply, "都查完了。");
+
+        // 两个一组并发执行：约 0.8 + 1 秒，逐个执行需要 2.8 秒
+        assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+        assert_eq!(server.max_tool_calls_in_flight(), 2);
+
+        // 工具结果按请求顺序排列，超时的调用把错误告诉模型
+        let requests = server.chat_requests();
+        let messages = requests[1]["messages"].as_array().unwrap();
+        let results: Vec<&str> = messages[messages.len() - 4..]
+            .iter()
+            .map(|m| m["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(&results[..3], &["a", "b", "c"]);
+        assert!(results[3].contains("工具调用超时"), "{}", results[3]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text("第一句话说完了。第二句话也说完了。"));
+        let chatbot = mock_chatbot(&server).await;
+
+        let mut segments = Vec::new();
+        let reply = chatbot
+            .chat_stream(10001, None, "说两句话", &[], "测试用户", |segment| {
+                segments.push(segment.to_string())
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(reply, "第一句话说完了。第二句话也说完了。");
+        assert_eq!(segments, vec!["第一句话说完了。", "第二句话也说完了。"]);
+        assert_eq!(server.chat_requests()[0]["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn test_overrides_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        let mut config = mock_config(&server);
+        config.llm.model = "global-model".to_string();
+        config.overrides.groups.insert(
+            20001,
+            ChatOverride {
+                prompt: Some("你是群里的猫娘".to_string()),
+                model: Some("group-model".to_string()),
+                temperature: Some(0.2),
+                ..ChatOverride::default()
+            },
+        );
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        chatbot.chat(10001, Some(20001), "你好", &[], "测试用户").await.unwrap();
+        chatbot.chat(10001, None, "你好", &[], "测试用户").await.unwrap();
+
+        // 群覆盖只影响该群的会话
+        let requests = server.chat_requests();
+        assert_eq!(requests[0]["model"], "group-model");
+        assert_eq!(requests[0]["temperature"], 0.2);
+        assert!(requests[0]["messages"][0]["content"]
+            .as_str()
+            .unwrap()
+            .contains("你是群里的猫娘"));
+        assert_eq!(requests[1]["model"], "global-model");
+        assert!(requests[1]["temperature"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_temperature_override_keeps_switched_model() {
+        let server = MockLlmServer::start().await;
+        let mut config = mock_config(&server);
+        config.overrides.groups.insert(
+            20001,
+            ChatOverride {
+                temperature: Some(0.2),
+                ..ChatOverride::default()
+            },
+        );
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        chatbot.chat(10001, Some(20001), "你好", &[], "测试用户").await.unwrap();
+        chatbot.switch_model("switched-model").unwrap();
+        chatbot.chat(10001, Some(20001), "你好", &[], "测试用户").await.unwrap();
+
+        // 只覆盖温度的群跟随运行时切换的主模型
+        let requests = server.chat_requests();
+        assert_eq!(requests[0]["model"], "mock-model");
+        assert_eq!(requests[1]["model"], "switched-model");
+        assert_eq!(requests[1]["temperature"], 0.2);
+    }
+
+    #[tokio::test]
+    async fn test_persona_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        let mut config = mock_config(&server);
+        config.persona.name = "阿布".to_string();
+        config.persona.aliases = vec!["布布".to_string()];
+        config.trigger.name = true;
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        // 开启 trigger.name 后，群聊中提到名字或别名即触发回复
+        assert!(chatbot.should_respond(10001, 20001, "阿布在吗", false));
+        assert!(chatbot.should_respond(10001, 20001, "布布早上好", false));
+        assert!(!chatbot.should_respond(10001, 20001, "小诗在吗", false));
+
+        chatbot.chat(10001, None, "你好", &[], "测试用户").await.unwrap();
+        let system = server.chat_requests()[0]["messages"][0]["content"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(system.contains("名为\"阿布\""));
+        assert!(!system.contains("小诗"));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::Error(400, r#"{"error":"bad request"}"#.to_string()));
+        server.push_reply(MockReply::text("备用模型的回复"));
+
+        let mut config = mock_config(&server);
+        config.llm.model = "primary-model".to_string();
+        let mut fallback = config.llm.clone();
+        fallback.model = "fallback-model".to_string();
+        config.llm.fallbacks = vec![fallback];
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        let reply = chatbot.chat(10001, None, "你好", &[], "测试用户").await.unwrap();
+        assert_eq!(reply, "备用模型的回复");
+
+        let requests = server.chat_requests();
+        assert_eq!(requests[0]["model"], "primary-model");
+        assert_eq!(requests[1]["model"], "fallback-model");
+    }
+
+    #[tokio::test]
+    async fn test_reasoning_only_reply_is_retried() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text("<think>用户在打招呼</think>"));
+        server.push_reply(MockReply::text("<think>还是没想好</think>"));
+        server.push_reply(MockReply::text("你好呀"));
+
+        let mut config = mock_config(&server);
+        config.llm.model = "primary-model".to_string();
+        let mut fallback = config.llm.clone();
+        fallback.model = "fallback-model".to_string();
+        config.llm.fallbacks = vec![fallback];
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        let reply = chatbot.chat(10001, None, "你好", &[], "测试用户").await.unwrap();
+        assert_eq!(reply, "你好呀");
+
+        // 只有思考内容时先重试主模型，仍然没有正文再切换到备用模型
+        let models: Vec<Value> = server
+            .chat_requests()
+            .iter()
+            .map(|request| request["model"].clone())
+            .collect();
+        assert_eq!(models, vec!["primary-model", "primary-model", "fallback-model"]);
+    }
+
+    #[tokio::test]
+    async fn test_vision_fallback_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::Error(400, r#"{"error":"bad request"}"#.to_string()));
+        server.push_reply(MockReply::text("看不清图片，不过听起来很可爱"));
+
+        let mut config = mock_config(&server);
+        config.vision.enabled = true;
+        config.vision.model = "vision-model".to_string();
+        config.vision.url = server.url();
+        config.vision.caption = false;
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        let images = vec!["base64://R0lGODlh".to_string()];
+        let reply = chatbot
+            .chat(10001, None, "看看我的猫", &images, "测试用户")
+            .await
+            .unwrap();
+        assert_eq!(reply, "看不清图片，不过听起来很可爱");
+
+        // 视觉模型失败后由文本模型按占位文本回复
+        let requests = server.chat_requests();
+        assert_eq!(requests[0]["model"], "vision-model");
+        assert_eq!(requests[1]["model"], "mock-model");
+        let messages = requests[1]["messages"].as_array().unwrap();
+        assert_eq!(messages.last().unwrap()["content"], "看看我的猫 [图片]");
+    }
+
+    #[tokio::test]
+    async fn test_history_token_budget_with_mock_server() {
+        use crate::chatbot::tokenizer::HeuristicCounter;
+
+        let server = MockLlmServer::start().await;
+        let mut config = mock_config(&server);
+
+        // 上下文只够容纳最近一轮问答
+        let counter = HeuristicCounter;
+        let system_prompt =
+            PromptTemplate::build_simple_system_prompt(&config.persona, &config.memory.prompt);
+        config.llm.max_tokens = Some(10);
+        config.llm.context_length = 10
+            + counter.count(&system_prompt)
+            + counter.count("第三问")
+            + counter.count("第二问")
+            + counter.count(DEFAULT_REPLY);
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        for input in ["第一问", "第二问", "第三问"] {
+            chatbot.chat(10001, None, input, &[], "测试用户").await.unwrap();
+        }
+
+        let requests = server.chat_requests();
+        let messages = requests[2]["messages"].as_array().unwrap();
+        let contents: Vec<&str> = messages[1..]
+            .iter()
+            .map(|m| m["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(contents, vec!["第二问", DEFAULT_REPLY, "第三问"]);
+    }
+
+    #[tokio::test]
+    async fn test_history_summary_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        let mut config = mock_config(&server);
+        config.memory.history_limit = 2;
+        config.memory.summary.enabled = true;
+        config.memory.summary.batch_size = 2;
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        chatbot.chat(10001, None, "第一问", &[], "测试用户").await.unwrap();
+        chatbot.chat(10001, None, "第二问", &[], "测试用户").await.unwrap();
+
+        // 等待异步摘要完成
+        let key = Memory::generate_key(10001, None);
+        for _ in 0..100 {
+            if chatbot.short_term_memory.get_summary(&key).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(chatbot.short_term_memory.get_summary(&key).as_deref(), Some(DEFAULT_REPLY));
+
+        chatbot.chat(10001, None, "第三问", &[], "测试用户").await.unwrap();
+
+        let requests = server.chat_requests();
+        let summary_input = requests[2]["messages"][1]["content"].as_str().unwrap();
+        assert!(summary_input.contains("User: 第一问"));
+        let system_prompt = requests[3]["messages"][0]["content"].as_str().unwrap();
+        assert!(system_prompt.contains("早前对话摘要"));
+        assert!(system_prompt.contains(DEFAULT_REPLY));
+    }
+
+    #[tokio::test]
+    async fn test_shared_group_context_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text("我觉得挺好的"));
+        let mut config = mock_config(&server);
+        config.group.shared_groups = vec![20001];
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        chatbot.record_group_message(20001, 10002, "小红", "周末去爬山吧");
+        // 未启用共享上下文的群不会被记录
+        chatbot.record_group_message(20002, 10003, "小刚", "别的群的消息");
+        chatbot
+            .chat(10001, Some(20001), "你觉得呢？", &[], "小明")
+            .await
+            .unwrap();
+        chatbot
+            .chat(10002, Some(20001), "那你去吗？", &[], "小红")
+            .await
+            .unwrap();
+
+        let requests = server.chat_requests();
+        let messages = requests[0]["messages"].as_array().unwrap();
+        let system_prompt = messages[0]["content"].as_str().unwrap();
+        assert!(system_prompt.contains("小红(10002): 周末去爬山吧"));
+        assert!(!system_prompt.contains("别的群的消息"));
+        assert_eq!(messages[1]["content"], "小明(10001): 你觉得呢？");
+
+        // 另一位成员能看到之前的问答
+        let messages = requests[1]["messages"].as_array().unwrap();
+        let contents: Vec<&str> = messages[1..]
+            .iter()
+            .map(|m| m["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            contents,
+            vec!["小明(10001): 你觉得呢？", "我觉得挺好的", "小红(10002): 那你去吗？"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_cleanup_without_rag() {
+        let server = MockLlmServer::start().await;
+        let mut config = mock_config(&server);
+        config.memory.history_timeout = 0;
+        let chatbot = ChatBot::new(config, "/tmp/config.json").await.unwrap();
+
+        chatbot.short_term_memory.add_user_message("10001", "你好".to_string());
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let report = chatbot.run_cleanup().await;
+        assert_eq!(report.stale_sessions, 1);
+        assert_eq!(report.expired_memories, 0);
+        assert_eq!(chatbot.get_stats().conversation_count, 0);
+        assert!(chatbot.maintain_vector_indexes().await.is_ok());
+    }
+}
@@ -0,0 +1,224 @@
+//! 管理命令
+//!
+//! 机器人主人可以在聊天中用 `/xs <命令>` 操作机器人（前缀可配置），
+//! 只有 `admin.admins` 中的QQ号可以执行
+
+use crate::chatbot::chat::ChatStats;
+use crate::chatbot::reload::SharedChatBot;
+
+/// 管理命令
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// 显示帮助
+    Help,
+    /// 清除短期记忆，可指定同一会话场景下的其他用户
+    Clear(Option<i64>),
+    /// 显示运行状态
+    Stats,
+    /// 列出 MCP 工具
+    Tools,
+    /// 重新加载 config.json 和 mcp.json
+    Reload,
+    /// 删除已过期的长期记忆
+    Forget,
+    /// 查看模型链或切换主模型
+    Model(Option<String>),
+    /// 无法识别的命令
+    Unknown(String),
+}
+
+impl AdminCommand {
+    /// 解析管理命令，不是以前缀开头的消息返回 None
+    ///
+    /// # 参数
+    /// - `text`: 消息文本
+    /// - `prefix`: 命令前缀
+    pub fn parse(text: &str, prefix: &str) -> Option<Self> {
+        let rest = text.trim().strip_prefix(prefix)?;
+        // 前缀后必须是空白或结束，避免 `/xsabc` 被误判
+        if rest.chars().next().is_some_and(|c| !c.is_whitespace()) {
+            return None;
+        }
+
+        let mut parts = rest.split_whitespace();
+        let command = match parts.next() {
+            None | Some("help") => Self::Help,
+            Some("clear") => match parts.next() {
+                None => Self::Clear(None),
+                Some(arg) => match arg.parse() {
+                    Ok(user_id) => Self::Clear(Some(user_id)),
+                    Err(_) => Self::Unknown(format!("clear {}", arg)),
+                },
+            },
+            Some("stats") => Self::Stats,
+            Some("tools") => Self::Tools,
+            Some("reload") => Self::Reload,
+            Some("forget") => Self::Forget,
+            Some("model") => Self::Model(parts.next().map(|s| s.to_string())),
+            Some(other) => Self::Unknown(other.to_string()),
+        };
+        Some(command)
+    }
+}
+
+/// 生成帮助文本
+pub fn help_text(prefix: &str) -> String {
+    [
+        "📖 管理命令：".to_string(),
+        format!("{} clear [QQ号] - 清除当前会话的短期记忆", prefix),
+        format!("{} stats - 查看运行状态", prefix),
+        format!("{} tools - 列出 MCP 工具", prefix),
+        format!("{} reload - 重新加载配置文件", prefix),
+        format!("{} forget - 删除已过期的长期记忆", prefix),
+        format!("{} model [模型名] - 查看模型或切换主模型", prefix),
+    ]
+    .join("\n")
+}
+
+/// 格式化统计信息
+pub fn format_stats(stats: &ChatStats) -> String {
+    let status = |enabled: bool| if enabled { "已启用" } else { "未启用" };
+    format!(
+        "📊 运行状态\n模型: {}\n活跃会话: {}\nRAG: {}\nMCP: {}",
+        stats.llm_model,
+        stats.conversation_count,
+        status(stats.rag_enabled),
+        status(stats.mcp_enabled)
+    )
+}
+
+/// 执行管理命令并返回回复文本（调用方负责检查管理员权限）
+///
+/// # 参数
+/// - `shared`: 可热重载的聊天机器人
+/// - `command`: 解析后的命令
+/// - `user_id`: 发送命令的QQ号
+/// - `group_id`: 群号（私聊为 None）
+pub async fn execute_command(
+    shared: &SharedChatBot,
+    command: AdminCommand,
+    user_id: i64,
+    group_id: Option<i64>,
+) -> String {
+    let chatbot = shared.current();
+    let prefix = chatbot.command_prefix();
+    match command {
+        AdminCommand::Help => help_text(prefix),
+        AdminCommand::Clear(target) => {
+            let target = target.unwrap_or(user_id);
+            chatbot.clear_history(target, group_id);
+            format!("🗑️ 已清除 {} 的短期记忆", target)
+        }
+        AdminCommand::Stats => format_stats(&chatbot.get_stats()),
+        AdminCommand::Tools => {
+            let tools = chatbot.get_mcp_tools().await;
+            if tools.is_empty() {
+                "🔧 当前没有可用的 MCP 工具".to_string()
+            } else {
+                format!("🔧 MCP 工具（{} 个）：\n{}", tools.len(), tools.join("\n"))
+            }
+        }
+        AdminCommand::Reload => match shared.reload(true).await {
+            Ok(()) => format!(
+                "🔄 配置已重新加载，主模型: {}",
+                shared.current().get_stats().llm_model
+            ),
+            Err(e) => format!("❌ 重新加载失败，继续使用当前配置: {}", e),
+        },
+        AdminCommand::Forget => {
+            if !chatbot.rag_enabled() {
+                return "⏸️ RAG 未启用，没有长期记忆可清理".to_string();
+            }
+            match chatbot.cleanup_expired_memories().await {
+                Ok(count) => format!("🧹 已删除 {} 条过期的长期记忆", count),
+                Err(e) => format!("❌ 清理失败: {}", e),
+            }
+        }
+        AdminCommand::Model(None) => {
+            let models = chatbot.model_names();
+            let mut reply = format!("🤖 主模型: {}", models[0]);
+            if models.len() > 1 {
+                reply.push_str(&format!("\n备用模型: {}", models[1..].join(", ")));
+            }
+            reply
+        }
+        AdminCommand::Model(Some(model)) => match chatbot.switch_model(&model) {
+            Ok(()) => format!("🔀 主模型已切换为 {}", model),
+            Err(e) => format!("❌ 切换模型失败: {}", e),
+        },
+        AdminCommand::Unknown(name) => {
+            format!("❓ 未知命令: {}\n\n{}", name, help_text(prefix))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::chat::ChatBot;
+    use crate::chatbot::test_support::{mock_config, MockLlmServer, MockReply};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(AdminCommand::parse("你好", "/xs"), None);
+        assert_eq!(AdminCommand::parse("/xsabc", "/xs"), None);
+        assert_eq!(AdminCommand::parse("/xs", "/xs"), Some(AdminCommand::Help));
+        assert_eq!(AdminCommand::parse("  /xs stats ", "/xs"), Some(AdminCommand::Stats));
+        assert_eq!(
+            AdminCommand::parse("/xs clear 10001", "/xs"),
+            Some(AdminCommand::Clear(Some(10001)))
+        );
+        assert_eq!(
+            AdminCommand::parse("/xs clear abc", "/xs"),
+            Some(AdminCommand::Unknown("clear abc".to_string()))
+        );
+        assert_eq!(
+            AdminCommand::parse("/xs model gpt-4o", "/xs"),
+            Some(AdminCommand::Model(Some("gpt-4o".to_string())))
+        );
+        assert_eq!(
+            AdminCommand::parse("!bot  tools", "!bot"),
+            Some(AdminCommand::Tools)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text("记住了"));
+
+        let mut config = mock_config(&server);
+        config.admin.admins = vec![10001];
+        let config_path = std::env::temp_dir()
+            .join(format!("xiaoshi-test-{}", uuid::Uuid::new_v4()))
+            .join("config.json");
+        let chatbot = ChatBot::new(config, &config_path).await.unwrap();
+        let shared = SharedChatBot::new(chatbot, &config_path);
+        let chatbot = shared.current();
+        assert!(chatbot.is_admin(10001));
+        assert!(!chatbot.is_admin(10002));
+
+        chatbot.chat(10001, None, "我叫小明", &[], "测试用户").await.unwrap();
+        assert_eq!(chatbot.get_stats().conversation_count, 1);
+
+        let reply = execute_command(&shared, AdminCommand::Clear(None), 10001, None).await;
+        assert!(reply.contains("10001"));
+        assert_eq!(chatbot.get_stats().conversation_count, 0);
+
+        let reply = execute_command(&shared, AdminCommand::Stats, 10001, None).await;
+        assert!(reply.contains("mock-model"));
+
+        let model = AdminCommand::Model(Some("other-model".to_string()));
+        execute_command(&shared, model, 10001, None).await;
+        assert_eq!(chatbot.model_names(), vec!["other-model"]);
+
+        chatbot.chat(10001, None, "你好", &[], "测试用户").await.unwrap();
+        assert_eq!(server.chat_requests()[1]["model"], "other-model");
+
+        // 配置文件不存在，重载失败并保留当前实例
+        let reply = execute_command(&shared, AdminCommand::Reload, 10001, None).await;
+        assert!(reply.starts_with("❌"));
+        assert!(Arc::ptr_eq(&chatbot, &shared.current()));
+    }
+}
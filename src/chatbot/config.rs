@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
+use crate::chatbot::secrets::{expand_references, restore_references, Substitution};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub llm: LlmConfig,
@@ -9,6 +12,424 @@ pub struct Config {
     pub memory: MemoryConfig,
     #[serde(default)]
     pub mcp: McpConfig,
+    #[serde(default)]
+    pub budget: BudgetConfig,
+    #[serde(default)]
+    pub vision: VisionConfig,
+    #[serde(default)]
+    pub group: GroupContextConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub hot_reload: HotReloadConfig,
+    #[serde(default)]
+    pub persona: PersonaConfig,
+    #[serde(default)]
+    pub trigger: TriggerConfig,
+    #[serde(default)]
+    pub overrides: OverridesConfig,
+    /// 加载时展开的环境变量和文件引用，保存时恢复原始写法
+    #[serde(skip)]
+    pub(crate) references: Vec<Substitution>,
+}
+
+/// 机器人人设
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersonaConfig {
+    /// 机器人的名字，用于系统提示词和长期记忆中的发送者名称（默认 "小诗"）
+    #[serde(default = "default_persona_name")]
+    pub name: String,
+    /// 别名（昵称），群聊中提到名字或别名时可以触发回复
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// 自我介绍，写入系统提示词的角色设定部分
+    #[serde(default)]
+    pub description: String,
+}
+
+fn default_persona_name() -> String {
+    "小诗".to_string()
+}
+
+impl Default for PersonaConfig {
+    fn default() -> Self {
+        Self {
+            name: default_persona_name(),
+            aliases: Vec::new(),
+            description: String::new(),
+        }
+    }
+}
+
+impl PersonaConfig {
+    /// 消息中是否提到了机器人的名字或别名
+    pub fn is_mentioned_in(&self, text: &str) -> bool {
+        std::iter::once(&self.name)
+            .chain(&self.aliases)
+            .any(|name| !name.is_empty() && text.contains(name.as_str()))
+    }
+}
+
+/// 群聊触发规则（私聊总是回复）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TriggerConfig {
+    /// 被 @ 时回复（默认 true）
+    #[serde(default = "default_trigger_mention")]
+    pub mention: bool,
+    /// 消息中提到机器人的名字或别名（`persona`）时回复（默认 false，保持旧版只回复 @ 的行为）
+    #[serde(default)]
+    pub name: bool,
+    /// 消息包含任一关键词时回复
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// 回复群里的所有消息
+    #[serde(default)]
+    pub all_messages: bool,
+}
+
+fn default_trigger_mention() -> bool {
+    true
+}
+
+impl Default for TriggerConfig {
+    fn default() -> Self {
+        Self {
+            mention: default_trigger_mention(),
+            name: false,
+            keywords: Vec::new(),
+            all_messages: false,
+        }
+    }
+}
+
+impl TriggerConfig {
+    /// 群聊消息是否应该触发回复
+    ///
+    /// # 参数
+    /// - `text`: 消息文本
+    /// - `mentioned`: 消息是否 @ 了机器人
+    /// - `persona`: 机器人人设，用于识别消息中提到的名字
+    pub fn matches(&self, text: &str, mentioned: bool, persona: &PersonaConfig) -> bool {
+        (self.mention && mentioned)
+            || (self.name && persona.is_mentioned_in(text))
+            || self.all_messages
+            || self.keywords.iter().any(|k| !k.is_empty() && text.contains(k.as_str()))
+    }
+}
+
+/// 单个群或用户的配置覆盖，未填写的项沿用全局配置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatOverride {
+    /// 人设提示词，替换 `memory.prompt`
+    #[serde(default)]
+    pub prompt: Option<String>,
+    /// 主模型名称（沿用 `llm` 的服务商、地址和密钥）
+    #[serde(default)]
+    pub model: Option<String>,
+    /// 采样温度
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    /// 是否使用长期记忆（只能关闭全局已启用的 RAG）
+    #[serde(default)]
+    pub rag_enabled: Option<bool>,
+    /// 允许使用的 MCP 工具名称，未填写时可使用全部工具
+    #[serde(default)]
+    pub allowed_tools: Option<Vec<String>>,
+    /// 群聊触发规则，替换全局的 `trigger`
+    #[serde(default)]
+    pub trigger: Option<TriggerConfig>,
+}
+
+impl ChatOverride {
+    /// 用 `other` 中填写了的项覆盖当前配置
+    pub fn merge(mut self, other: &ChatOverride) -> Self {
+        if other.prompt.is_some() {
+            self.prompt = other.prompt.clone();
+        }
+        if other.model.is_some() {
+            self.model = other.model.clone();
+        }
+        if other.temperature.is_some() {
+            self.temperature = other.temperature;
+        }
+        if other.rag_enabled.is_some() {
+            self.rag_enabled = other.rag_enabled;
+        }
+        if other.allowed_tools.is_some() {
+            self.allowed_tools = other.allowed_tools.clone();
+        }
+        if other.trigger.is_some() {
+            self.trigger = other.trigger.clone();
+        }
+        self
+    }
+}
+
+/// 按群号、QQ号覆盖全局配置
+///
+/// 同时命中时用户覆盖优先于群覆盖
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OverridesConfig {
+    /// 按群号覆盖
+    #[serde(default)]
+    pub groups: HashMap<i64, ChatOverride>,
+    /// 按QQ号覆盖（私聊和群聊都生效）
+    #[serde(default)]
+    pub users: HashMap<i64, ChatOverride>,
+}
+
+impl OverridesConfig {
+    /// 合并某个会话命中的所有覆盖
+    ///
+    /// # 参数
+    /// - `user_id`: 用户QQ号
+    /// - `group_id`: 群号（None表示私聊）
+    pub fn resolve(&self, user_id: i64, group_id: Option<i64>) -> ChatOverride {
+        let mut merged = ChatOverride::default();
+        if let Some(group) = group_id.and_then(|gid| self.groups.get(&gid)) {
+            merged = merged.merge(group);
+        }
+        if let Some(user) = self.users.get(&user_id) {
+            merged = merged.merge(user);
+        }
+        merged
+    }
+}
+
+/// 配置热重载
+///
+/// 定期检查 config.json 和 mcp.json 的修改时间，文件变化后重新加载配置，
+/// 新配置无效时继续使用当前配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HotReloadConfig {
+    /// 是否监听配置文件变化（默认 true，关闭后仍可用管理命令 reload 手动重载）
+    #[serde(default = "default_hot_reload_enabled")]
+    pub enabled: bool,
+    /// 检查文件变化的间隔（秒）
+    #[serde(default = "default_hot_reload_interval")]
+    pub interval: u64,
+}
+
+fn default_hot_reload_enabled() -> bool {
+    true
+}
+
+fn default_hot_reload_interval() -> u64 {
+    5
+}
+
+impl Default for HotReloadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_hot_reload_enabled(),
+            interval: default_hot_reload_interval(),
+        }
+    }
+}
+
+/// 管理命令配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConfig {
+    /// 管理员QQ号列表，为空时所有管理命令都不可用
+    #[serde(default)]
+    pub admins: Vec<i64>,
+    /// 命令前缀（默认 `/xs`）
+    #[serde(default = "default_command_prefix")]
+    pub prefix: String,
+}
+
+fn default_command_prefix() -> String {
+    "/xs".to_string()
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            admins: Vec::new(),
+            prefix: default_command_prefix(),
+        }
+    }
+}
+
+/// 后台维护任务配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    /// 是否启用后台维护任务（默认 true）
+    #[serde(default = "default_maintenance_enabled")]
+    pub enabled: bool,
+    /// 清理间隔（秒）：删除过期记忆、执行 `cleanup_days`、清理超时的短期会话
+    #[serde(default = "default_cleanup_interval")]
+    pub cleanup_interval: u64,
+    /// 向量索引维护间隔（秒）：补建或重建向量索引并更新统计信息，0 表示不执行
+    #[serde(default = "default_index_interval")]
+    pub index_interval: u64,
+}
+
+fn default_maintenance_enabled() -> bool {
+    true
+}
+
+fn default_cleanup_interval() -> u64 {
+    3600
+}
+
+fn default_index_interval() -> u64 {
+    86400
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_maintenance_enabled(),
+            cleanup_interval: default_cleanup_interval(),
+            index_interval: default_index_interval(),
+        }
+    }
+}
+
+/// 群聊共享上下文配置
+///
+/// 列表中的群不再按 `群号:QQ号` 区分会话，而是共用一条群时间线：
+/// 与机器人的对话带上发送者昵称共享给全群，未 @ 机器人的发言也会缓冲下来作为上下文
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupContextConfig {
+    /// 启用共享上下文的群号列表
+    #[serde(default)]
+    pub shared_groups: Vec<i64>,
+    /// 每个群缓冲的最近发言条数
+    #[serde(default = "default_group_buffer_size")]
+    pub buffer_size: usize,
+    /// 缓冲发言的有效期（秒）
+    #[serde(default = "default_group_buffer_timeout")]
+    pub buffer_timeout: u64,
+}
+
+fn default_group_buffer_size() -> usize {
+    30
+}
+
+fn default_group_buffer_timeout() -> u64 {
+    1800
+}
+
+impl Default for GroupContextConfig {
+    fn default() -> Self {
+        Self {
+            shared_groups: Vec::new(),
+            buffer_size: default_group_buffer_size(),
+            buffer_timeout: default_group_buffer_timeout(),
+        }
+    }
+}
+
+/// 视觉模型配置（处理用户发送的图片）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VisionConfig {
+    /// 是否启用图片理解，未启用时图片仅以 "[图片]" 占位
+    #[serde(default)]
+    pub enabled: bool,
+    /// API 协议类型
+    #[serde(default)]
+    pub provider: LlmProvider,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub apikey: String,
+    /// 最大输出 token 数，设为 None 使用 API 默认值
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// 单条消息最多处理的图片数量
+    #[serde(default = "default_max_images")]
+    pub max_images: usize,
+    /// 单张图片的最大字节数，超过则跳过
+    #[serde(default = "default_max_image_bytes")]
+    pub max_image_bytes: usize,
+    /// 是否为图片生成简短描述写入记忆，便于后续对话引用
+    #[serde(default = "default_caption_enabled")]
+    pub caption: bool,
+    /// 生成图片描述的提示词
+    #[serde(default = "default_caption_prompt")]
+    pub caption_prompt: String,
+}
+
+fn default_max_images() -> usize {
+    4
+}
+
+fn default_max_image_bytes() -> usize {
+    5 * 1024 * 1024
+}
+
+fn default_caption_enabled() -> bool {
+    true
+}
+
+fn default_caption_prompt() -> String {
+    "用一句话（不超过50字）客观描述这张图片的主要内容，只输出描述本身。".to_string()
+}
+
+impl Default for VisionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            provider: LlmProvider::default(),
+            model: String::new(),
+            url: String::new(),
+            apikey: String::new(),
+            max_tokens: None,
+            max_images: default_max_images(),
+            max_image_bytes: default_max_image_bytes(),
+            caption: default_caption_enabled(),
+            caption_prompt: default_caption_prompt(),
+        }
+    }
+}
+
+/// Token 预算配置
+///
+/// 额度按自然日 / 自然月（本地时间）统计 prompt + completion 的总 token 数，
+/// 未配置的额度项不做限制
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BudgetConfig {
+    /// 是否启用预算限制
+    #[serde(default)]
+    pub enabled: bool,
+    /// 每个群每日 token 额度
+    #[serde(default)]
+    pub group_daily_tokens: Option<u64>,
+    /// 每个群每月 token 额度
+    #[serde(default)]
+    pub group_monthly_tokens: Option<u64>,
+    /// 每个用户每日 token 额度（私聊与群聊合计）
+    #[serde(default)]
+    pub user_daily_tokens: Option<u64>,
+    /// 每个用户每月 token 额度（私聊与群聊合计）
+    #[serde(default)]
+    pub user_monthly_tokens: Option<u64>,
+    /// 超出额度时的回复
+    #[serde(default = "default_budget_exceeded_message")]
+    pub exceeded_message: String,
+}
+
+fn default_budget_exceeded_message() -> String {
+    "抱歉，这里的聊天额度已经用完啦，等额度刷新后再来找我吧～".to_string()
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            group_daily_tokens: None,
+            group_monthly_tokens: None,
+            user_daily_tokens: None,
+            user_monthly_tokens: None,
+            exceeded_message: default_budget_exceeded_message(),
+        }
+    }
 }
 
 /// MCP (Model Context Protocol) 配置
@@ -45,24 +466,43 @@ pub struct McpConfig {
     /// 最大工具调用循环次数，防止无限循环
     #[serde(default = "default_max_tool_iterations")]
     pub max_tool_iterations: usize,
+    /// 同一轮中最多同时执行的工具调用数量
+    #[serde(default = "default_max_concurrent_tool_calls")]
+    pub max_concurrent_tool_calls: usize,
+    /// 单个工具调用的超时时间（秒）
+    #[serde(default = "default_tool_call_timeout")]
+    pub tool_call_timeout: u64,
 }
 
 fn default_max_tool_iterations() -> usize {
     10
 }
 
+fn default_max_concurrent_tool_calls() -> usize {
+    4
+}
+
+fn default_tool_call_timeout() -> u64 {
+    60
+}
+
 impl Default for McpConfig {
     fn default() -> Self {
         Self {
             enabled: false,
             path: String::new(),
             max_tool_iterations: default_max_tool_iterations(),
+            max_concurrent_tool_calls: default_max_concurrent_tool_calls(),
+            tool_call_timeout: default_tool_call_timeout(),
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LlmConfig {
+    /// API 协议类型：`openai`（OpenAI 兼容接口，默认）或 `anthropic`
+    #[serde(default)]
+    pub provider: LlmProvider,
     pub model: String,
     pub url: String,
     pub apikey: String,
@@ -81,6 +521,93 @@ pub struct LlmConfig {
     /// frequency_penalty 参数（-2 到 2），设为 None 使用 API 默认值
     #[serde(default)]
     pub frequency_penalty: Option<f64>,
+    /// 模型上下文长度（token），扣除系统提示词、长期记忆和回复预留后即为短期记忆的预算
+    #[serde(default = "default_context_length")]
+    pub context_length: usize,
+    /// 是否启用流式输出，启用后回复会按句子分段发送
+    #[serde(default)]
+    pub stream: bool,
+    /// 请求失败（429 / 5xx / 网络错误）时的重试策略
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// 推理模型（思考内容）相关配置
+    #[serde(default)]
+    pub reasoning: ReasoningConfig,
+    /// 备用模型列表，主模型重试仍失败时按顺序切换（备用项自身的 fallbacks 会被忽略）
+    #[serde(default)]
+    pub fallbacks: Vec<LlmConfig>,
+}
+
+fn default_context_length() -> usize {
+    32768
+}
+
+/// LLM API 协议类型
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LlmProvider {
+    /// OpenAI 兼容的 `/chat/completions` 接口（DeepSeek、硅基流动等）
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// Anthropic 原生 Messages 接口（`/v1/messages`）
+    #[serde(rename = "anthropic")]
+    Anthropic,
+}
+
+/// 推理模型配置
+///
+/// 思考内容（`reasoning_content`、`<think>` 标签、Anthropic thinking 块）只用于日志和
+/// 工具调用循环中的回传，不会发送给用户，也不会写入记忆
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReasoningConfig {
+    /// 工具调用循环中是否把思考内容回传给模型（OpenAI 兼容接口）
+    ///
+    /// DeepSeek-R1 等模型不接受回传，保持 `false`；Kimi 等要求回传的模型设为 `true`。
+    /// Anthropic 接口的 thinking 块总是按协议要求回传
+    #[serde(default)]
+    pub echo: bool,
+    /// 是否在日志中输出思考内容
+    #[serde(default)]
+    pub log: bool,
+    /// Anthropic extended thinking 的 token 预算，None 表示不启用
+    #[serde(default)]
+    pub budget_tokens: Option<u32>,
+}
+
+/// LLM 请求重试配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// 最大重试次数（不含首次请求），0 表示不重试
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// 首次重试的退避时间（毫秒），之后按指数增长
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// 单次退避时间上限（毫秒）
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_backoff_ms() -> u64 {
+    8000
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: default_max_retries(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -125,6 +652,108 @@ pub struct MemoryConfig {
     #[serde(default = "default_prompt")]
     pub prompt: String,            // 系统提示词
     pub rag: RagConfig,            // RAG 配置
+    #[serde(default)]
+    pub tokenizer: TokenizerConfig, // token 计数配置
+    #[serde(default)]
+    pub summary: SummaryConfig,    // 滚动摘要配置
+    #[serde(default)]
+    pub persistence: PersistenceConfig, // 短期记忆持久化配置
+}
+
+/// 短期记忆持久化配置
+///
+/// 启用后每次会话变化都会写入 JSONL 文件，插件重启时恢复未超时的会话（不依赖 PostgreSQL）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistenceConfig {
+    /// 是否启用（默认 false）
+    #[serde(default)]
+    pub enabled: bool,
+    /// 存储文件路径（相对于 config.json 所在目录）
+    #[serde(default = "default_persistence_path")]
+    pub path: String,
+}
+
+fn default_persistence_path() -> String {
+    "short_term_memory.jsonl".to_string()
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_persistence_path(),
+        }
+    }
+}
+
+/// 短期记忆滚动摘要配置
+///
+/// 超出 `history_limit` 被挤出的旧对话由主模型压缩为每个会话的摘要，并注入系统提示词
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SummaryConfig {
+    /// 是否启用滚动摘要（默认 false）
+    #[serde(default)]
+    pub enabled: bool,
+    /// 累计挤出多少条消息后触发一次摘要
+    #[serde(default = "default_summary_batch_size")]
+    pub batch_size: usize,
+    /// 摘要的最大字数
+    #[serde(default = "default_summary_max_chars")]
+    pub max_chars: usize,
+    /// 生成摘要的提示词
+    #[serde(default = "default_summary_prompt")]
+    pub prompt: String,
+}
+
+fn default_summary_batch_size() -> usize {
+    6
+}
+
+fn default_summary_max_chars() -> usize {
+    300
+}
+
+fn default_summary_prompt() -> String {
+    "你是对话摘要助手。请把【已有摘要】和【新增对话】合并为一份新的摘要，\
+保留人物、事实、约定、用户偏好和尚未结束的话题，省略寒暄和重复内容。\
+直接输出摘要正文，不要添加标题或解释。"
+        .to_string()
+}
+
+impl Default for SummaryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            batch_size: default_summary_batch_size(),
+            max_chars: default_summary_max_chars(),
+            prompt: default_summary_prompt(),
+        }
+    }
+}
+
+/// token 计数配置
+///
+/// 配置 tiktoken 词表后按 BPE 精确计数，否则使用对中文友好的启发式估算
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TokenizerConfig {
+    /// 词表编码，决定预分词规则
+    #[serde(default)]
+    pub encoding: TokenizerEncoding,
+    /// `.tiktoken` 词表文件路径（相对于 config.json 所在目录），为空时使用启发式估算
+    #[serde(default)]
+    pub path: String,
+}
+
+/// BPE 词表编码
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenizerEncoding {
+    /// GPT-4 / GPT-3.5 使用的编码
+    #[default]
+    #[serde(rename = "cl100k_base")]
+    Cl100kBase,
+    /// GPT-4o 使用的编码
+    #[serde(rename = "o200k_base")]
+    O200kBase,
 }
 
 fn default_prompt() -> String {
@@ -140,7 +769,21 @@ pub struct RagConfig {
     pub window_size: usize,        // 每锚点上下文宽度
     pub max_memory_tokens: usize,  // 记忆总token限制
     #[serde(default = "default_cleanup_days")]
-    pub cleanup_days: u64,         // 清理过期数据的天数
+    pub cleanup_days: u64,         // 非永久记忆的最长保留天数（0 表示不限制）
+    #[serde(default)]
+    pub search_mode: SearchMode,   // 检索方式（默认纯向量检索）
+    #[serde(default = "default_rrf_k")]
+    pub rrf_k: u32,                // 混合检索时倒数排名融合的平滑常数（默认 60）
+    #[serde(default = "default_min_similarity")]
+    pub min_similarity: f32,       // 向量检索锚点的最低余弦相似度（默认 -1，不过滤）
+    #[serde(default)]
+    pub ranking: RankingConfig,    // 锚点排序权重
+    #[serde(default)]
+    pub rerank: RerankConfig,      // 重排序模型配置（默认不启用）
+    #[serde(default)]
+    pub mmr_enabled: bool,         // 是否用最大边际相关性（MMR）选择锚点，减少重复记忆（默认 false）
+    #[serde(default = "default_mmr_lambda")]
+    pub mmr_lambda: f32,           // MMR 中相关度的权重，1 只看相关度，0 只看多样性（默认 0.7）
     pub memory_evaluation: MemoryEvaluationConfig, // 记忆评估配置
 }
 
@@ -148,11 +791,125 @@ fn default_cleanup_days() -> u64 {
     30
 }
 
+fn default_rrf_k() -> u32 {
+    60
+}
+
+fn default_min_similarity() -> f32 {
+    -1.0
+}
+
+fn default_mmr_lambda() -> f32 {
+    0.7
+}
+
+/// 长期记忆锚点的排序权重
+///
+/// 排序分 = 相关度 × `similarity_weight` + 新近度 × `recency_weight` + 记忆评分 × `score_weight`，
+/// 三项都在 0-1 之间：相关度为余弦相似度（混合检索时为归一化的 RRF 得分），
+/// 新近度按 `recency_half_life_days` 指数衰减，记忆评分为 `score / 100`（未评分按 0.5 计）。
+/// 默认只按相关度排序
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RankingConfig {
+    /// 相关度权重（默认 1.0）
+    #[serde(default = "default_similarity_weight")]
+    pub similarity_weight: f32,
+    /// 新近度权重（默认 0）
+    #[serde(default)]
+    pub recency_weight: f32,
+    /// 记忆评分权重（默认 0）
+    #[serde(default)]
+    pub score_weight: f32,
+    /// 新近度的半衰期（天，默认 30）
+    #[serde(default = "default_recency_half_life_days")]
+    pub recency_half_life_days: f32,
+}
+
+fn default_similarity_weight() -> f32 {
+    1.0
+}
+
+fn default_recency_half_life_days() -> f32 {
+    30.0
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        Self {
+            similarity_weight: default_similarity_weight(),
+            recency_weight: 0.0,
+            score_weight: 0.0,
+            recency_half_life_days: default_recency_half_life_days(),
+        }
+    }
+}
+
+/// 重排序（rerank）模型配置
+///
+/// 启用后多取一些候选锚点，用交叉编码器模型逐条对照查询打分，取得分最高的 `top_n` 条；
+/// 调用失败时沿用检索的排序
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankConfig {
+    /// 是否启用重排序（默认 false）
+    #[serde(default)]
+    pub enabled: bool,
+    /// 重排序模型，如 `BAAI/bge-reranker-v2-m3`、`jina-reranker-v2-base-multilingual`
+    #[serde(default)]
+    pub model: String,
+    /// `/rerank` 接口地址
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub apikey: String,
+    /// 交给重排序模型的候选锚点数（默认 20）
+    #[serde(default = "default_rerank_candidates")]
+    pub candidates: usize,
+    /// 请求超时（秒，默认 10）
+    #[serde(default = "default_rerank_timeout")]
+    pub timeout: u64,
+}
+
+fn default_rerank_candidates() -> usize {
+    20
+}
+
+fn default_rerank_timeout() -> u64 {
+    10
+}
+
+impl Default for RerankConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            model: String::new(),
+            url: String::new(),
+            apikey: String::new(),
+            candidates: default_rerank_candidates(),
+            timeout: default_rerank_timeout(),
+        }
+    }
+}
+
+/// 长期记忆的检索方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SearchMode {
+    /// 只按向量距离检索
+    #[default]
+    #[serde(rename = "vector")]
+    Vector,
+    /// 向量检索与关键词检索各取候选，再用倒数排名融合（RRF）合并，
+    /// 适合名字、数字等语义向量不敏感的内容
+    #[serde(rename = "hybrid")]
+    Hybrid,
+}
+
 /// 记忆评估配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryEvaluationConfig {
     #[serde(default = "default_evaluation_enabled")]
     pub enabled: bool,             // 是否启用记忆评估（默认 true）
+    #[serde(default)]
+    pub provider: LlmProvider,     // API 协议类型
     pub model: String,             // 评估模型
     pub url: String,               // API URL
     pub apikey: String,            // API Key
@@ -229,12 +986,19 @@ pub struct EmbeddingConfig {
     pub model: String,
     pub url: String,
     pub apikey: String,
+    /// 向量维度，设为 None 时启动时调用嵌入接口探测
+    #[serde(default)]
+    pub dimension: Option<usize>,
+    /// 嵌入模型或维度与数据库中记录的不一致时，是否清空旧向量并在后台重新生成（默认 false，拒绝启动 RAG）
+    #[serde(default)]
+    pub reembed_on_change: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             llm: LlmConfig {
+                provider: LlmProvider::default(),
                 model: String::new(),
                 url: String::new(),
                 apikey: String::new(),
@@ -243,6 +1007,11 @@ impl Default for Config {
                 max_tokens: None,
                 presence_penalty: None,
                 frequency_penalty: None,
+                context_length: default_context_length(),
+                stream: false,
+                retry: RetryConfig::default(),
+                reasoning: ReasoningConfig::default(),
+                fallbacks: Vec::new(),
             },
             db: DbConfig {
                 postgres: PostgresConfig {
@@ -264,13 +1033,23 @@ impl Default for Config {
                         model: "Qwen/Qwen3-Embedding-0.6B".to_string(),
                         url: "https://api.siliconflow.cn/v1/embeddings".to_string(),
                         apikey: String::new(),
+                        dimension: None,
+                        reembed_on_change: false,
                     },
                     top_n: 3,
                     window_size: 2,
                     max_memory_tokens: 1000,
                     cleanup_days: default_cleanup_days(),
+                    search_mode: SearchMode::default(),
+                    rrf_k: default_rrf_k(),
+                    min_similarity: default_min_similarity(),
+                    ranking: RankingConfig::default(),
+                    rerank: RerankConfig::default(),
+                    mmr_enabled: false,
+                    mmr_lambda: default_mmr_lambda(),
                     memory_evaluation: MemoryEvaluationConfig {
                         enabled: default_evaluation_enabled(),
+                        provider: LlmProvider::default(),
                         model: "Qwen/Qwen3-VL-8B-Instruct".to_string(),
                         url: "https://api.siliconflow.cn/v1".to_string(),
                         apikey: String::new(),
@@ -282,8 +1061,21 @@ impl Default for Config {
                         frequency_penalty: None,
                     },
                 },
+                tokenizer: TokenizerConfig::default(),
+                summary: SummaryConfig::default(),
+                persistence: PersistenceConfig::default(),
             },
             mcp: McpConfig::default(),
+            budget: BudgetConfig::default(),
+            vision: VisionConfig::default(),
+            group: GroupContextConfig::default(),
+            maintenance: MaintenanceConfig::default(),
+            admin: AdminConfig::default(),
+            hot_reload: HotReloadConfig::default(),
+            persona: PersonaConfig::default(),
+            trigger: TriggerConfig::default(),
+            overrides: OverridesConfig::default(),
+            references: Vec::new(),
         }
     }
 }
@@ -300,9 +1092,12 @@ pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn std::error
         return Ok(default_config);
     }
     
-    // 读取配置文件
+    // 读取配置文件并展开 `${ENV_VAR}` 和 `${file:path}` 引用
     let content = fs::read_to_string(path)?;
-    let config: Config = serde_json::from_str(&content)?;
+    let mut value: serde_json::Value = serde_json::from_str(&content)?;
+    let references = expand_references(&mut value, path.parent())?;
+    let mut config: Config = serde_json::from_value(value)?;
+    config.references = references;
     
     Ok(config)
 }
@@ -316,8 +1111,14 @@ pub fn save_config<P: AsRef<Path>>(path: P, config: &Config) -> Result<(), Box<d
         fs::create_dir_all(parent)?;
     }
     
-    // 将配置序列化为格式化的JSON
-    let content = serde_json::to_string_pretty(config)?;
+    // 将配置序列化为格式化的JSON，展开过的引用恢复原始写法，避免密钥落盘
+    let content = if config.references.is_empty() {
+        serde_json::to_string_pretty(config)?
+    } else {
+        let mut value = serde_json::to_value(config)?;
+        restore_references(&mut value, &config.references);
+        serde_json::to_string_pretty(&value)?
+    };
     fs::write(path, content)?;
     
     Ok(())
@@ -363,5 +1164,125 @@ mod tests {
         // 清理测试文件
         fs::remove_file(temp_path).ok();
     }
+
+    #[test]
+    fn test_overrides_resolve() {
+        let json = r#"{
+            "groups": {
+                "100": { "prompt": "群人设", "temperature": 0.3, "allowed_tools": ["search"] }
+            },
+            "users": {
+                "1": { "temperature": 1.2, "rag_enabled": false }
+            }
+        }"#;
+        let overrides: OverridesConfig = serde_json::from_str(json).unwrap();
+
+        // 用户覆盖优先于群覆盖，未填写的项沿用群覆盖
+        let merged = overrides.resolve(1, Some(100));
+        assert_eq!(merged.prompt.as_deref(), Some("群人设"));
+        assert_eq!(merged.temperature, Some(1.2));
+        assert_eq!(merged.rag_enabled, Some(false));
+        assert_eq!(merged.allowed_tools, Some(vec!["search".to_string()]));
+
+        // 私聊只命中用户覆盖
+        let merged = overrides.resolve(1, None);
+        assert!(merged.prompt.is_none());
+        assert!(overrides.resolve(2, Some(200)).model.is_none());
+    }
+
+    #[test]
+    fn test_trigger_matches() {
+        let persona = PersonaConfig {
+            name: "小诗".to_string(),
+            aliases: vec!["诗诗".to_string()],
+            description: String::new(),
+        };
+        // 默认只回复 @
+        let trigger = TriggerConfig::default();
+        assert!(trigger.matches("你好", true, &persona));
+        assert!(!trigger.matches("你好", false, &persona));
+        assert!(!trigger.matches("小诗在吗", false, &persona));
+
+        let trigger = TriggerConfig {
+            name: true,
+            ..TriggerConfig::default()
+        };
+        assert!(trigger.matches("小诗在吗", false, &persona));
+        assert!(trigger.matches("诗诗早", false, &persona));
+
+        let trigger = TriggerConfig {
+            mention: false,
+            name: false,
+            keywords: vec!["天气".to_string()],
+            all_messages: false,
+        };
+        assert!(trigger.matches("今天天气怎么样", false, &persona));
+        assert!(!trigger.matches("小诗在吗", false, &persona));
+        assert!(!trigger.matches("你好", true, &persona));
+    }
+
+    #[test]
+    fn test_save_config_keeps_secret_references() {
+        std::env::set_var("XIAOSHI_TEST_LLM_KEY", "sk-from-env");
+        let temp_path = "/tmp/test_config_secrets.json";
+
+        let mut config = Config::default();
+        config.llm.apikey = "${XIAOSHI_TEST_LLM_KEY}".to_string();
+        save_config(temp_path, &config).unwrap();
+
+        let mut loaded_config = load_config(temp_path).unwrap();
+        assert_eq!(loaded_config.llm.apikey, "sk-from-env");
+
+        // 再次保存时写回引用而不是展开后的密钥
+        loaded_config.llm.model = "gpt-4".to_string();
+        save_config(temp_path, &loaded_config).unwrap();
+        let content = fs::read_to_string(temp_path).unwrap();
+        assert!(content.contains("${XIAOSHI_TEST_LLM_KEY}"));
+        assert!(!content.contains("sk-from-env"));
+        assert!(content.contains("gpt-4"));
+
+        fs::remove_file(temp_path).ok();
+    }
+
+    #[test]
+    fn test_mcp_config_defaults() {
+        let mcp: McpConfig =
+            serde_json::from_str(r#"{ "enabled": true, "path": "mcp.json" }"#).unwrap();
+        assert_eq!(mcp.max_tool_iterations, 10);
+        assert_eq!(mcp.max_concurrent_tool_calls, 4);
+        assert_eq!(mcp.tool_call_timeout, 60);
+    }
+
+    #[test]
+    fn test_llm_provider_deserialization() {
+        let json = r#"{
+            "model": "claude-sonnet-4-5",
+            "url": "https://api.anthropic.com",
+            "apikey": "test-key",
+            "fallbacks": [
+                { "model": "deepseek-chat", "url": "https://api.deepseek.com/v1", "apikey": "k" }
+            ]
+        }"#;
+        let mut value: serde_json::Value = serde_json::from_str(json).unwrap();
+        let llm: LlmConfig = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(llm.provider, LlmProvider::OpenAi);
+
+        value["provider"] = serde_json::json!("anthropic");
+        let llm: LlmConfig = serde_json::from_value(value).unwrap();
+        assert_eq!(llm.provider, LlmProvider::Anthropic);
+        assert_eq!(llm.fallbacks[0].provider, LlmProvider::OpenAi);
+    }
+
+    #[test]
+    fn test_tokenizer_config_deserialization() {
+        let config: TokenizerConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.encoding, TokenizerEncoding::Cl100kBase);
+        assert!(config.path.is_empty());
+
+        let json = r#"{ "encoding": "o200k_base", "path": "o200k_base.tiktoken" }"#;
+        let config: TokenizerConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.encoding, TokenizerEncoding::O200kBase);
+        assert_eq!(config.path, "o200k_base.tiktoken");
+    }
 }
 
@@ -0,0 +1,143 @@
+//! 群聊共享上下文
+//!
+//! 启用共享上下文的群会缓冲所有成员最近的发言（包括没有 @ 机器人的消息），
+//! 机器人被 @ 时把这些发言作为上下文提供给模型
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chatbot::config::GroupContextConfig;
+
+/// 群聊中的一条发言
+#[derive(Debug, Clone)]
+pub struct GroupMessage {
+    pub user_id: i64,
+    pub sender_name: String,
+    pub content: String,
+    pub timestamp: u64, // Unix 时间戳
+}
+
+/// 群聊时间线缓冲
+pub struct GroupTimeline {
+    config: GroupContextConfig,
+    timelines: Mutex<HashMap<i64, VecDeque<GroupMessage>>>,
+}
+
+impl GroupTimeline {
+    /// 创建群聊时间线缓冲
+    pub fn new(config: GroupContextConfig) -> Self {
+        Self {
+            config,
+            timelines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 该群是否启用了共享上下文
+    pub fn is_shared(&self, group_id: i64) -> bool {
+        self.config.shared_groups.contains(&group_id)
+    }
+
+    fn current_timestamp() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// 记录一条群聊发言，未启用共享上下文的群直接忽略
+    ///
+    /// # 参数
+    /// - `group_id`: 群号
+    /// - `user_id`: 发送者QQ号
+    /// - `sender_name`: 发送者昵称
+    /// - `content`: 发言内容
+    pub fn record(&self, group_id: i64, user_id: i64, sender_name: &str, content: &str) {
+        if !self.is_shared(group_id) || content.trim().is_empty() {
+            return;
+        }
+
+        let mut timelines = self.timelines.lock().unwrap();
+        let timeline = timelines.entry(group_id).or_default();
+        timeline.push_back(GroupMessage {
+            user_id,
+            sender_name: sender_name.to_string(),
+            content: content.to_string(),
+            timestamp: Self::current_timestamp(),
+        });
+
+        while timeline.len() > self.config.buffer_size {
+            timeline.pop_front();
+        }
+    }
+
+    /// 获取群里最近的发言（按时间顺序，已过期的发言会被清理）
+    ///
+    /// # 参数
+    /// - `group_id`: 群号
+    pub fn recent(&self, group_id: i64) -> Vec<GroupMessage> {
+        let mut timelines = self.timelines.lock().unwrap();
+        let Some(timeline) = timelines.get_mut(&group_id) else {
+            return Vec::new();
+        };
+
+        let now = Self::current_timestamp();
+        while timeline
+            .front()
+            .is_some_and(|msg| now.saturating_sub(msg.timestamp) > self.config.buffer_timeout)
+        {
+            timeline.pop_front();
+        }
+
+        timeline.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeline(buffer_size: usize) -> GroupTimeline {
+        GroupTimeline::new(GroupContextConfig {
+            shared_groups: vec![100],
+            buffer_size,
+            buffer_timeout: 1800,
+        })
+    }
+
+    #[test]
+    fn test_record_only_shared_groups() {
+        let timeline = timeline(10);
+        timeline.record(100, 1, "小明", "今天好热");
+        timeline.record(200, 2, "小红", "是啊");
+        timeline.record(100, 3, "小刚", "   ");
+
+        let recent = timeline.recent(100);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].sender_name, "小明");
+        assert!(timeline.recent(200).is_empty());
+    }
+
+    #[test]
+    fn test_buffer_size_limit() {
+        let timeline = timeline(2);
+        for i in 0..5 {
+            timeline.record(100, i, "成员", &format!("消息{}", i));
+        }
+
+        let contents: Vec<String> = timeline.recent(100).into_iter().map(|m| m.content).collect();
+        assert_eq!(contents, vec!["消息3", "消息4"]);
+    }
+
+    #[test]
+    fn test_expired_messages_dropped() {
+        let timeline = timeline(10);
+        timeline.record(100, 1, "小明", "很久以前的消息");
+        timeline.timelines.lock().unwrap().get_mut(&100).unwrap()[0].timestamp = 0;
+        timeline.record(100, 2, "小红", "刚刚的消息");
+
+        let recent = timeline.recent(100);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].content, "刚刚的消息");
+    }
+}
@@ -0,0 +1,122 @@
+//! 关键词检索的分词
+//!
+//! PostgreSQL 自带的全文检索解析器不会切分中文，且依赖数据库的 locale。
+//! 这里在 Rust 中把文本切成检索词：连续的中日韩字符切成二元组（单个字符保留为单字），
+//! 字母和数字按整词保留并转为小写。检索词通过 `array_to_tsvector` 直接存为 tsvector，
+//! 不经过数据库的解析器
+
+/// 单个检索词的最大字节数，更长的词（粘贴的哈希、密钥、日志等）不参与检索。
+/// PostgreSQL 的 tsvector 词素不能超过 2047 字节，超出会导致整条对话写入失败
+const MAX_TERM_BYTES: usize = 256;
+
+/// 单条文本最多保留的检索词数，避免超长文本生成过大的 tsvector（上限 1MB）
+const MAX_TERMS: usize = 1024;
+
+/// 是否为中日韩文字（汉字、假名、谚文）
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{4E00}'..='\u{9FFF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{3040}'..='\u{30FF}'
+        | '\u{AC00}'..='\u{D7AF}'
+    )
+}
+
+/// 把一段连续的中日韩字符切成二元组
+fn flush_cjk(run: &mut Vec<char>, terms: &mut Vec<String>) {
+    match run.len() {
+        0 => {}
+        1 => terms.push(run[0].to_string()),
+        _ => terms.extend(run.windows(2).map(|pair| pair.iter().collect())),
+    }
+    run.clear();
+}
+
+fn flush_word(word: &mut String, terms: &mut Vec<String>) {
+    if !word.is_empty() && word.len() <= MAX_TERM_BYTES {
+        terms.push(std::mem::take(word));
+    }
+    word.clear();
+}
+
+/// 把文本切成去重后的检索词，保持首次出现的顺序
+///
+/// 超过 [`MAX_TERM_BYTES`] 的词被丢弃，最多保留前 [`MAX_TERMS`] 个检索词
+pub fn keyword_terms(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut cjk_run = Vec::new();
+    let mut word = String::new();
+
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if is_cjk(c) {
+            flush_word(&mut word, &mut terms);
+            cjk_run.push(c);
+        } else if c.is_alphanumeric() {
+            flush_cjk(&mut cjk_run, &mut terms);
+            word.push(c);
+        } else {
+            flush_cjk(&mut cjk_run, &mut terms);
+            flush_word(&mut word, &mut terms);
+        }
+    }
+    flush_cjk(&mut cjk_run, &mut terms);
+    flush_word(&mut word, &mut terms);
+
+    let mut seen = std::collections::HashSet::new();
+    terms.retain(|term| seen.insert(term.clone()));
+    terms.truncate(MAX_TERMS);
+    terms
+}
+
+/// 生成匹配任一检索词的 tsquery 文本（直接转换为 `tsquery`，不经过全文检索解析器）
+///
+/// 没有检索词时返回 None
+pub fn tsquery_text(terms: &[String]) -> Option<String> {
+    if terms.is_empty() {
+        return None;
+    }
+    let quoted: Vec<String> = terms
+        .iter()
+        .map(|term| format!("'{}'", term.replace('\\', "\\\\").replace('\'', "''")))
+        .collect();
+    Some(quoted.join(" | "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyword_terms() {
+        assert_eq!(
+            keyword_terms("小明的QQ是10086，猫"),
+            vec!["小明", "明的", "qq", "是", "10086", "猫"]
+        );
+        assert_eq!(keyword_terms("GPT-4o 和 gpt"), vec!["gpt", "4o", "和"]);
+        assert!(keyword_terms("，。！ ").is_empty());
+    }
+
+    #[test]
+    fn test_keyword_terms_limits() {
+        // 超长的词被丢弃，前后的词保留
+        let hash = "a".repeat(MAX_TERM_BYTES + 1);
+        assert_eq!(keyword_terms(&format!("key {} end", hash)), vec!["key", "end"]);
+        let long_token = "0123456789abcdef".repeat(500);
+        assert!(keyword_terms(&long_token).is_empty());
+
+        // 检索词数量有上限
+        let text: Vec<String> = (0..MAX_TERMS * 2).map(|i| format!("w{}", i)).collect();
+        let terms = keyword_terms(&text.join(" "));
+        assert_eq!(terms.len(), MAX_TERMS);
+        assert_eq!(terms[0], "w0");
+        assert!(terms.iter().all(|term| term.len() <= MAX_TERM_BYTES));
+    }
+
+    #[test]
+    fn test_tsquery_text() {
+        assert_eq!(tsquery_text(&[]), None);
+        let terms = vec!["小明".to_string(), "it's".to_string()];
+        assert_eq!(tsquery_text(&terms).unwrap(), "'小明' | 'it''s'");
+    }
+}
@@ -1,7 +1,17 @@
 use async_openai::{config::OpenAIConfig, Client as OpenAIClient};
-use serde::{Deserialize, Serialize};
+use futures_util::StreamExt;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value;
 use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::chatbot::anthropic::AnthropicClient;
+use crate::chatbot::config::{LlmProvider, RetryConfig};
+
+/// 服务端要求的 Retry-After 超过该值时不再等待，直接视为失败（交给备用模型处理）
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
 
 /// LLM 请求参数配置
 #[derive(Debug, Clone, Default)]
@@ -11,6 +21,10 @@ pub struct LlmRequestParams {
     pub max_tokens: Option<u32>,
     pub presence_penalty: Option<f64>,
     pub frequency_penalty: Option<f64>,
+    /// 工具调用循环中是否回传思考内容（仅 OpenAI 兼容接口）
+    pub echo_reasoning: bool,
+    /// Anthropic extended thinking 的 token 预算
+    pub thinking_budget: Option<u32>,
 }
 
 /// LLM 客户端封装
@@ -22,6 +36,7 @@ pub struct LlmClient {
     base_url: String,
     model: String,
     request_params: LlmRequestParams,
+    retry: RetryConfig,
 }
 
 /// 工具调用信息
@@ -40,16 +55,70 @@ pub struct FunctionCall {
     pub arguments: String,
 }
 
+/// 模型的思考内容
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reasoning {
+    pub content: String,
+    /// Anthropic 返回的 thinking / redacted_thinking 块，回传时必须按原顺序原样携带
+    pub blocks: Vec<Value>,
+}
+
+impl Reasoning {
+    /// 从文本创建思考内容，空白文本返回 None
+    pub fn from_text(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.is_empty() {
+            None
+        } else {
+            Some(Self {
+                content: text.to_string(),
+                blocks: Vec::new(),
+            })
+        }
+    }
+}
+
 /// LLM 响应消息
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// 序列化时如果带有图片，`content` 会输出为 OpenAI 的多模态内容数组
+/// （`text` + `image_url` 片段），否则输出为普通字符串
+#[derive(Debug, Clone, Deserialize)]
 pub struct LlmMessage {
     pub role: String,
     #[serde(default)]
     pub content: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
     pub tool_calls: Option<Vec<ToolCall>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
     pub tool_call_id: Option<String>,
+    /// 附带的图片（http(s) 地址或 data URL）
+    #[serde(skip)]
+    pub images: Vec<String>,
+    /// 助手消息的思考内容，仅在工具调用循环中按协议回传
+    #[serde(skip)]
+    pub reasoning: Option<Reasoning>,
+}
+
+impl Serialize for LlmMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("role", &self.role)?;
+        if self.images.is_empty() {
+            map.serialize_entry("content", &self.content)?;
+        } else {
+            map.serialize_entry("content", &self.content_parts())?;
+        }
+        if let Some(tool_calls) = &self.tool_calls {
+            map.serialize_entry("tool_calls", tool_calls)?;
+        }
+        if let Some(tool_call_id) = &self.tool_call_id {
+            map.serialize_entry("tool_call_id", tool_call_id)?;
+        }
+        if let Some(reasoning) = &self.reasoning {
+            map.serialize_entry("reasoning_content", &reasoning.content)?;
+        }
+        map.end()
+    }
 }
 
 impl LlmMessage {
@@ -61,6 +130,8 @@ impl LlmMessage {
             content: Some(content.to_string()),
             tool_calls: None,
             tool_call_id: None,
+            images: Vec::new(),
+            reasoning: None,
         }
     }
 
@@ -71,6 +142,8 @@ impl LlmMessage {
             content: Some(content.to_string()),
             tool_calls: None,
             tool_call_id: None,
+            images: Vec::new(),
+            reasoning: None,
         }
     }
 
@@ -82,6 +155,8 @@ impl LlmMessage {
             content: Some(content.to_string()),
             tool_calls: None,
             tool_call_id: None,
+            images: Vec::new(),
+            reasoning: None,
         }
     }
 
@@ -92,6 +167,8 @@ impl LlmMessage {
             content: content.map(|s| s.to_string()),
             tool_calls: Some(tool_calls),
             tool_call_id: None,
+            images: Vec::new(),
+            reasoning: None,
         }
     }
 
@@ -102,9 +179,48 @@ impl LlmMessage {
             content: Some(content.to_string()),
             tool_calls: None,
             tool_call_id: Some(tool_call_id.to_string()),
+            images: Vec::new(),
+            reasoning: None,
         }
     }
 
+    /// 创建带图片的用户消息
+    ///
+    /// # 参数
+    /// - `content`: 文本内容（可以为空）
+    /// - `images`: 图片地址列表（http(s) 地址或 data URL）
+    pub fn user_with_images(content: &str, images: Vec<String>) -> Self {
+        Self {
+            role: "user".to_string(),
+            content: Some(content.to_string()),
+            tool_calls: None,
+            tool_call_id: None,
+            images,
+            reasoning: None,
+        }
+    }
+
+    /// 附加思考内容
+    pub fn with_reasoning(mut self, reasoning: Option<Reasoning>) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    /// 生成 OpenAI 多模态内容片段
+    fn content_parts(&self) -> Vec<Value> {
+        let mut parts = Vec::new();
+        if let Some(text) = self.content.as_deref().filter(|t| !t.is_empty()) {
+            parts.push(serde_json::json!({ "type": "text", "text": text }));
+        }
+        for image in &self.images {
+            parts.push(serde_json::json!({
+                "type": "image_url",
+                "image_url": { "url": image }
+            }));
+        }
+        parts
+    }
+
     /// 从 (role, content) 元组创建消息
     pub fn from_tuple(role: &str, content: &str) -> Self {
         Self {
@@ -112,15 +228,63 @@ impl LlmMessage {
             content: Some(content.to_string()),
             tool_calls: None,
             tool_call_id: None,
+            images: Vec::new(),
+            reasoning: None,
         }
     }
 }
 
+/// Token 用量
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    #[serde(default)]
+    pub prompt_tokens: u32,
+    #[serde(default)]
+    pub completion_tokens: u32,
+    #[serde(default)]
+    pub total_tokens: u32,
+    /// 推理模型的思考 token 数（已包含在 completion_tokens 中）
+    #[serde(default)]
+    pub reasoning_tokens: u32,
+}
+
+impl TokenUsage {
+    /// 从响应中的 `usage` 字段解析，缺失时返回 None
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        if !value.is_object() {
+            return None;
+        }
+        let mut usage: TokenUsage = serde_json::from_value(value.clone()).ok()?;
+        if usage.total_tokens == 0 {
+            usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+        }
+        if let Some(reasoning_tokens) =
+            value["completion_tokens_details"]["reasoning_tokens"].as_u64()
+        {
+            usage.reasoning_tokens = reasoning_tokens as u32;
+        }
+        Some(usage)
+    }
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+    }
+}
+
 /// LLM 完成响应
 #[derive(Debug, Clone)]
 pub struct CompletionResponse {
     pub content: Option<String>,
     pub tool_calls: Vec<ToolCall>,
+    /// Token 用量（服务端未返回时为 None）
+    pub usage: Option<TokenUsage>,
+    /// 思考内容（推理模型才有），不会发送给用户
+    pub reasoning: Option<Reasoning>,
 }
 
 impl CompletionResponse {
@@ -128,6 +292,93 @@ impl CompletionResponse {
     pub fn has_tool_calls(&self) -> bool {
         !self.tool_calls.is_empty()
     }
+
+    /// 是否只返回了思考内容（没有正文也没有工具调用）
+    pub fn is_reasoning_only(&self) -> bool {
+        self.reasoning.is_some()
+            && !self.has_tool_calls()
+            && self.content.as_deref().unwrap_or_default().is_empty()
+    }
+}
+
+/// LLM 后端 trait
+///
+/// 屏蔽不同服务商的接口差异：消息、工具定义统一使用 OpenAI 格式，
+/// 由各实现负责转换为自身的请求 / 响应结构
+#[async_trait::async_trait]
+pub trait LlmBackend: Send + Sync {
+    /// 发送带工具支持的聊天请求
+    ///
+    /// # 参数
+    /// - `messages`: LLM 消息列表
+    /// - `tools`: 可选的工具定义列表（OpenAI 格式）
+    async fn chat_completion(
+        &self,
+        messages: Vec<LlmMessage>,
+        tools: Option<&Vec<Value>>,
+    ) -> Result<CompletionResponse, Box<dyn Error + Send + Sync>>;
+
+    /// 发送流式聊天请求，每收到一段增量文本就调用一次 `on_delta`
+    ///
+    /// 流结束后返回与 [`chat_completion`](Self::chat_completion) 相同结构的完整响应
+    async fn chat_completion_stream(
+        &self,
+        messages: Vec<LlmMessage>,
+        tools: Option<&Vec<Value>>,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<CompletionResponse, Box<dyn Error + Send + Sync>>;
+
+    /// 获取当前使用的模型名称
+    fn model(&self) -> &str;
+
+    /// 发送带历史记录的聊天请求（简单版本，不带工具）
+    ///
+    /// # 参数
+    /// - `messages`: 消息历史，每个元素为 (role, content) 元组
+    ///   - role 可以是 "system", "user", "assistant"
+    ///
+    /// # 返回
+    /// - `Ok(String)`: AI 的回复内容
+    /// - `Err`: 错误信息
+    async fn chat_with_history(
+        &self,
+        messages: Vec<(String, String)>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let llm_messages: Vec<LlmMessage> = messages
+            .into_iter()
+            .map(|(role, content)| LlmMessage::from_tuple(&role, &content))
+            .collect();
+
+        let response = self.chat_completion(llm_messages, None).await?;
+        Ok(response.content.unwrap_or_default())
+    }
+}
+
+/// 根据协议类型创建 LLM 后端
+///
+/// # 参数
+/// - `provider`: API 协议类型
+/// - `api_key`: API 密钥
+/// - `base_url`: API 基础 URL
+/// - `model`: 使用的模型名称
+/// - `request_params`: 请求参数配置
+/// - `retry`: 重试策略
+pub fn create_backend(
+    provider: LlmProvider,
+    api_key: String,
+    base_url: String,
+    model: String,
+    request_params: LlmRequestParams,
+    retry: RetryConfig,
+) -> Result<Arc<dyn LlmBackend>, Box<dyn Error + Send + Sync>> {
+    Ok(match provider {
+        LlmProvider::OpenAi => Arc::new(
+            LlmClient::new(api_key, base_url, model, request_params)?.with_retry(retry),
+        ),
+        LlmProvider::Anthropic => Arc::new(
+            AnthropicClient::new(api_key, base_url, model, request_params)?.with_retry(retry),
+        ),
+    })
 }
 
 impl LlmClient {
@@ -158,57 +409,68 @@ impl LlmClient {
             base_url,
             model,
             request_params,
+            retry: RetryConfig::default(),
         })
     }
 
-    /// 发送带历史记录的聊天请求（简单版本，不带工具）
-    ///
-    /// # 参数
-    /// - `messages`: 消息历史，每个元素为 (role, content) 元组
-    ///   - role 可以是 "system", "user", "assistant"
-    ///
-    /// # 返回
-    /// - `Ok(String)`: AI 的回复内容
-    /// - `Err`: 错误信息
-    pub async fn chat_with_history(
+    /// 设置重试策略
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// 发送请求，失败时按重试策略重试
+    async fn send_with_retry(
         &self,
-        messages: Vec<(String, String)>,
-    ) -> Result<String, Box<dyn Error + Send + Sync>> {
-        let llm_messages: Vec<LlmMessage> = messages
-            .into_iter()
-            .map(|(role, content)| LlmMessage::from_tuple(&role, &content))
-            .collect();
+        request_body: &Value,
+    ) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>> {
+        send_with_retry(&self.retry, &self.model, "OpenAI", || {
+            self.http_client
+                .post(self.completions_url())
+                .header("Authorization", format!("Bearer {}", self.api_key))
+                .header("Content-Type", "application/json")
+                .json(request_body)
+        })
+        .await
+    }
 
-        let response = self.chat_completion(llm_messages, None).await?;
-        Ok(response.content.unwrap_or_default())
+    /// 获取 chat/completions 接口地址
+    fn completions_url(&self) -> String {
+        if self.base_url.ends_with("/chat/completions") {
+            self.base_url.clone()
+        } else {
+            format!("{}/chat/completions", self.base_url.trim_end_matches('/'))
+        }
     }
 
-    /// 发送带工具支持的聊天请求
-    ///
-    /// # 参数
-    /// - `messages`: LLM 消息列表
-    /// - `tools`: 可选的工具定义列表（OpenAI 格式）
-    ///
-    /// # 返回
-    /// - `Ok(CompletionResponse)`: 包含内容和可能的工具调用
-    /// - `Err`: 错误信息
-    pub async fn chat_completion(
+    /// 构建请求体
+    fn build_request_body(
         &self,
         messages: Vec<LlmMessage>,
         tools: Option<&Vec<Value>>,
-    ) -> Result<CompletionResponse, Box<dyn Error + Send + Sync>> {
-        let url = if self.base_url.ends_with("/chat/completions") {
-            self.base_url.clone()
+        stream: bool,
+    ) -> Value {
+        // 不回传时去掉思考内容（DeepSeek-R1 等模型收到 reasoning_content 会报错）
+        let messages: Vec<LlmMessage> = if self.request_params.echo_reasoning {
+            messages
         } else {
-            format!("{}/chat/completions", self.base_url.trim_end_matches('/'))
+            messages
+                .into_iter()
+                .map(|message| message.with_reasoning(None))
+                .collect()
         };
 
-        // 构建请求体
         let mut request_body = serde_json::json!({
             "model": self.model,
             "messages": messages,
         });
 
+        if stream {
+            request_body["stream"] = serde_json::json!(true);
+            // 要求在最后一个数据块中返回 token 用量
+            request_body["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
+
         // 添加可选的请求参数（仅在配置了的情况下添加，以兼容有限制的模型）
         if let Some(temp) = self.request_params.temperature {
             request_body["temperature"] = serde_json::json!(temp);
@@ -233,20 +495,19 @@ impl LlmClient {
             }
         }
 
-        let response = self
-            .http_client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+        request_body
+    }
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await.unwrap_or_default();
-            return Err(format!("OpenAI API Error: {} - {}", status, text).into());
-        }
+#[async_trait::async_trait]
+impl LlmBackend for LlmClient {
+    async fn chat_completion(
+        &self,
+        messages: Vec<LlmMessage>,
+        tools: Option<&Vec<Value>>,
+    ) -> Result<CompletionResponse, Box<dyn Error + Send + Sync>> {
+        let request_body = self.build_request_body(messages, tools, false);
+        let response = self.send_with_retry(&request_body).await?;
 
         let response_text = response.text().await?;
 
@@ -266,7 +527,12 @@ impl LlmClient {
         // 解析响应
         let choice = &json["choices"][0]["message"];
 
-        let content = choice["content"].as_str().map(|s| s.to_string());
+        // 正文中内联的 <think> 标签会被分离到思考内容中
+        let (content, inline_reasoning) =
+            ThinkTagFilter::split(choice["content"].as_str().unwrap_or_default());
+        let reasoning = reasoning_field(choice)
+            .and_then(Reasoning::from_text)
+            .or_else(|| inline_reasoning.as_deref().and_then(Reasoning::from_text));
 
         let tool_calls = if let Some(calls) = choice["tool_calls"].as_array() {
             calls
@@ -287,21 +553,466 @@ impl LlmClient {
         };
 
         Ok(CompletionResponse {
-            content,
+            content: if content.is_empty() {
+                None
+            } else {
+                Some(content)
+            },
             tool_calls,
+            usage: TokenUsage::from_value(&json["usage"]),
+            reasoning,
         })
     }
 
-    /// 获取当前使用的模型名称
-    #[allow(dead_code)]
-    pub fn model(&self) -> &str {
+    /// 流式请求（`stream: true`），工具调用的增量片段会在内部拼接
+    async fn chat_completion_stream(
+        &self,
+        messages: Vec<LlmMessage>,
+        tools: Option<&Vec<Value>>,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<CompletionResponse, Box<dyn Error + Send + Sync>> {
+        let request_body = self.build_request_body(messages, tools, true);
+        // 只对建立连接阶段重试，流开始后的错误直接返回，避免重复输出
+        let response = self.send_with_retry(&request_body).await?;
+
+        let mut accumulator = StreamAccumulator::default();
+        let mut stream = response.bytes_stream();
+        // 按字节缓冲，避免多字节 UTF-8 字符被分片截断
+        let mut buffer: Vec<u8> = Vec::new();
+
+        'outer: while let Some(chunk) = stream.next().await {
+            buffer.extend_from_slice(&chunk?);
+
+            while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line_bytes: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line_bytes);
+
+                let data = match parse_sse_data(&line) {
+                    Some(data) => data,
+                    None => continue,
+                };
+                if data == "[DONE]" {
+                    break 'outer;
+                }
+
+                let json: Value = serde_json::from_str(data).map_err(|e| {
+                    format!("Failed to parse OpenAI stream chunk: {}. Data: {}", e, data)
+                })?;
+                if let Some(error) = json.get("error") {
+                    return Err(format!("OpenAI API returned error: {}", error).into());
+                }
+
+                if let Some(delta) = accumulator.apply_chunk(&json) {
+                    on_delta(&delta);
+                }
+            }
+        }
+
+        if let Some(rest) = accumulator.flush() {
+            on_delta(&rest);
+        }
+        Ok(accumulator.into_response())
+    }
+
+    fn model(&self) -> &str {
         &self.model
     }
 }
 
+/// 发送请求，遇到 429 / 5xx / 网络错误时按指数退避重试
+///
+/// 服务端返回 `Retry-After` 时优先使用该等待时间。`build_request` 每次重试都会被调用，
+/// 用于重新构建请求。
+///
+/// # 参数
+/// - `retry`: 重试策略
+/// - `model`: 模型名称（用于日志）
+/// - `provider`: 服务商名称（用于错误信息）
+/// - `build_request`: 构建请求的闭包
+pub(crate) async fn send_with_retry<F>(
+    retry: &RetryConfig,
+    model: &str,
+    provider: &str,
+    build_request: F,
+) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    let mut attempt: u32 = 0;
+    loop {
+        let result = build_request().send().await;
+
+        let (error, retry_after) = match result {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || status.is_server_error();
+                let retry_after = parse_retry_after(response.headers());
+                let text = response.text().await.unwrap_or_default();
+                let error = format!("{} API Error: {} - {}", provider, status, text);
+                if !retryable {
+                    return Err(error.into());
+                }
+                (error, retry_after)
+            }
+            Err(e) if e.is_timeout() || e.is_connect() || e.is_request() => {
+                (format!("{} API 请求失败: {}", provider, e), None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if attempt >= retry.max_retries {
+            return Err(error.into());
+        }
+
+        let delay = match retry_after {
+            Some(wait) if wait > MAX_RETRY_AFTER => {
+                return Err(format!("{}（Retry-After {} 秒，放弃重试）", error, wait.as_secs()).into());
+            }
+            Some(wait) => wait,
+            None => backoff_delay(retry, attempt),
+        };
+
+        attempt += 1;
+        log::warn!(
+            "⚠️  模型 {} 请求失败，{} 毫秒后进行第 {} 次重试: {}",
+            model,
+            delay.as_millis(),
+            attempt,
+            error
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// 解析 `Retry-After` 响应头（支持秒数和 HTTP 日期两种格式）
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.signed_duration_since(chrono::Utc::now());
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
+
+/// 计算第 `attempt` 次重试（从 0 开始）的退避时间
+///
+/// 指数退避并叠加抖动，实际等待时间落在 [delay/2, delay] 区间
+fn backoff_delay(retry: &RetryConfig, attempt: u32) -> Duration {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let exp = retry
+        .initial_backoff_ms
+        .saturating_mul(1u64 << attempt.min(16));
+    let delay_ms = exp.min(retry.max_backoff_ms);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(attempt);
+    let jitter = (hasher.finish() % 1000) as f64 / 1000.0;
+
+    let half = delay_ms / 2;
+    Duration::from_millis(half + ((delay_ms - half) as f64 * jitter) as u64)
+}
+
+/// 从一行 SSE 文本中提取 `data:` 负载
+pub(crate) fn parse_sse_data(line: &str) -> Option<&str> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    line.strip_prefix("data:").map(|data| data.trim_start())
+}
+
+/// 读取消息中的思考内容字段（`reasoning_content`，部分服务商使用 `reasoning`）
+fn reasoning_field(message: &Value) -> Option<&str> {
+    message["reasoning_content"]
+        .as_str()
+        .or_else(|| message["reasoning"].as_str())
+}
+
+/// `<think>` 标签过滤器
+///
+/// 部分模型会把思考过程以 `<think>...</think>` 的形式内联在正文中。
+/// 过滤器逐段处理增量文本，把标签内的内容分离为思考内容（标签可能被拆到多个分片中）
+#[derive(Debug, Default)]
+pub struct ThinkTagFilter {
+    buffer: String,
+    reasoning: String,
+    in_think: bool,
+    /// 刚结束思考块，跳过紧随其后的空白
+    after_think: bool,
+}
+
+impl ThinkTagFilter {
+    const OPEN_TAG: &'static str = "<think>";
+    const CLOSE_TAG: &'static str = "</think>";
+
+    /// 创建过滤器
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 分离完整文本中的思考内容，返回 (正文, 思考内容)
+    pub fn split(text: &str) -> (String, Option<String>) {
+        let mut filter = Self::new();
+        let mut content = filter.push(text);
+        content.push_str(&filter.finish());
+        (content, filter.take_reasoning())
+    }
+
+    /// 追加增量文本，返回可以输出的正文
+    pub fn push(&mut self, delta: &str) -> String {
+        self.buffer.push_str(delta);
+
+        let mut visible = String::new();
+        loop {
+            let tag = if self.in_think {
+                Self::CLOSE_TAG
+            } else {
+                Self::OPEN_TAG
+            };
+
+            if let Some(pos) = self.buffer.find(tag) {
+                let text: String = self.buffer.drain(..pos).collect();
+                self.buffer.drain(..tag.len());
+                self.emit(&text, &mut visible);
+                self.in_think = !self.in_think;
+                self.after_think = !self.in_think;
+                continue;
+            }
+
+            // 末尾可能是被截断的标签，留到下一个分片再判断
+            let keep = (1..tag.len())
+                .rev()
+                .find(|&n| self.buffer.ends_with(&tag[..n]))
+                .unwrap_or(0);
+            let text: String = self.buffer.drain(..self.buffer.len() - keep).collect();
+            self.emit(&text, &mut visible);
+            return visible;
+        }
+    }
+
+    /// 结束过滤，返回缓冲区中剩余的正文
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.buffer);
+        let mut visible = String::new();
+        self.emit(&rest, &mut visible);
+        visible
+    }
+
+    /// 取出已分离的思考内容
+    pub fn take_reasoning(&mut self) -> Option<String> {
+        let reasoning = std::mem::take(&mut self.reasoning);
+        let reasoning = reasoning.trim();
+        if reasoning.is_empty() {
+            None
+        } else {
+            Some(reasoning.to_string())
+        }
+    }
+
+    fn emit(&mut self, text: &str, visible: &mut String) {
+        if self.in_think {
+            self.reasoning.push_str(text);
+            return;
+        }
+
+        let text = if self.after_think {
+            text.trim_start()
+        } else {
+            text
+        };
+        if !text.is_empty() {
+            self.after_think = false;
+            visible.push_str(text);
+        }
+    }
+}
+
+/// 流式工具调用的拼接状态
+#[derive(Debug, Default)]
+struct PartialToolCall {
+    id: String,
+    call_type: String,
+    name: String,
+    arguments: String,
+}
+
+/// 流式响应累加器
+///
+/// 将 `choices[0].delta` 中的文本和 `tool_calls` 增量片段（按 `index` 归并）拼接成完整响应，
+/// 思考内容单独累积，不会作为增量文本输出
+#[derive(Debug, Default)]
+struct StreamAccumulator {
+    content: String,
+    reasoning: String,
+    think_filter: ThinkTagFilter,
+    tool_calls: Vec<PartialToolCall>,
+    usage: Option<TokenUsage>,
+}
+
+impl StreamAccumulator {
+    /// 处理一个流式数据块，返回本次新增的文本（如果有）
+    fn apply_chunk(&mut self, chunk: &Value) -> Option<String> {
+        if let Some(usage) = TokenUsage::from_value(&chunk["usage"]) {
+            self.usage = Some(usage);
+        }
+
+        let delta = &chunk["choices"][0]["delta"];
+
+        if let Some(calls) = delta["tool_calls"].as_array() {
+            for (position, call) in calls.iter().enumerate() {
+                let index = call["index"].as_u64().map(|i| i as usize).unwrap_or(position);
+                while self.tool_calls.len() <= index {
+                    self.tool_calls.push(PartialToolCall::default());
+                }
+                let partial = &mut self.tool_calls[index];
+
+                if let Some(id) = call["id"].as_str() {
+                    partial.id = id.to_string();
+                }
+                if let Some(call_type) = call["type"].as_str() {
+                    partial.call_type = call_type.to_string();
+                }
+                if let Some(name) = call["function"]["name"].as_str() {
+                    partial.name.push_str(name);
+                }
+                if let Some(arguments) = call["function"]["arguments"].as_str() {
+                    partial.arguments.push_str(arguments);
+                }
+            }
+        }
+
+        if let Some(reasoning) = reasoning_field(delta) {
+            self.reasoning.push_str(reasoning);
+        }
+
+        let text = self.think_filter.push(delta["content"].as_str()?);
+        self.push_content(text)
+    }
+
+    /// 输出过滤器中剩余的正文，流结束时调用
+    fn flush(&mut self) -> Option<String> {
+        let text = self.think_filter.finish();
+        self.push_content(text)
+    }
+
+    fn push_content(&mut self, text: String) -> Option<String> {
+        if text.is_empty() {
+            return None;
+        }
+        self.content.push_str(&text);
+        Some(text)
+    }
+
+    /// 生成完整响应
+    fn into_response(mut self) -> CompletionResponse {
+        self.flush();
+        let reasoning = Reasoning::from_text(&self.reasoning).or_else(|| {
+            self.think_filter
+                .take_reasoning()
+                .and_then(|text| Reasoning::from_text(&text))
+        });
+
+        let tool_calls = self
+            .tool_calls
+            .into_iter()
+            .filter(|call| !call.name.is_empty())
+            .map(|call| ToolCall {
+                id: call.id,
+                call_type: if call.call_type.is_empty() {
+                    "function".to_string()
+                } else {
+                    call.call_type
+                },
+                function: FunctionCall {
+                    name: call.name,
+                    arguments: call.arguments,
+                },
+            })
+            .collect();
+
+        CompletionResponse {
+            content: if self.content.is_empty() {
+                None
+            } else {
+                Some(self.content)
+            },
+            tool_calls,
+            usage: self.usage,
+            reasoning,
+        }
+    }
+}
+
+/// 分句缓冲器
+///
+/// 将流式增量文本攒成完整的句子再输出，避免逐字发送消息
+#[derive(Debug)]
+pub struct SentenceSplitter {
+    buffer: String,
+    min_chars: usize,
+}
+
+impl SentenceSplitter {
+    /// 句子结束符
+    const TERMINATORS: [char; 9] = ['。', '！', '？', '!', '?', '；', ';', '…', '\n'];
+
+    /// 创建分句缓冲器
+    ///
+    /// # 参数
+    /// - `min_chars`: 每段最少字符数，过短的句子会与后续句子合并
+    pub fn new(min_chars: usize) -> Self {
+        Self {
+            buffer: String::new(),
+            min_chars,
+        }
+    }
+
+    /// 追加增量文本，返回已经完整的句子
+    pub fn push(&mut self, delta: &str) -> Vec<String> {
+        self.buffer.push_str(delta);
+
+        let mut segments = Vec::new();
+        loop {
+            let mut cut = None;
+            let mut chars = 0;
+            for (pos, c) in self.buffer.char_indices() {
+                chars += 1;
+                if Self::TERMINATORS.contains(&c) && chars >= self.min_chars {
+                    cut = Some(pos + c.len_utf8());
+                    break;
+                }
+            }
+
+            let Some(cut) = cut else { break };
+            let segment: String = self.buffer.drain(..cut).collect();
+            let segment = segment.trim();
+            if !segment.is_empty() {
+                segments.push(segment.to_string());
+            }
+        }
+        segments
+    }
+
+    /// 取出剩余的文本
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.buffer);
+        let rest = rest.trim();
+        if rest.is_empty() {
+            None
+        } else {
+            Some(rest.to_string())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chatbot::test_support::{MockLlmServer, MockReply, MOCK_PROMPT_TOKENS};
 
     #[test]
     fn test_client_creation() {
@@ -309,6 +1020,7 @@ mod tests {
             "test-key".to_string(),
             "https://api.openai.com/v1".to_string(),
             "gpt-3.5-turbo".to_string(),
+            LlmRequestParams::default(),
         )
         .unwrap();
         assert_eq!(client.model(), "gpt-3.5-turbo");
@@ -346,4 +1058,315 @@ mod tests {
         assert_eq!(tool_call.id, "call_abc123");
         assert_eq!(tool_call.function.name, "get_weather");
     }
+
+    #[test]
+    fn test_parse_sse_data() {
+        assert_eq!(parse_sse_data("data: {\"a\":1}\r\n"), Some("{\"a\":1}"));
+        assert_eq!(parse_sse_data("data:[DONE]"), Some("[DONE]"));
+        assert_eq!(parse_sse_data(": keep-alive"), None);
+        assert_eq!(parse_sse_data(""), None);
+    }
+
+    #[test]
+    fn test_stream_accumulator_content() {
+        let mut acc = StreamAccumulator::default();
+        let chunks = [
+            r#"{"choices":[{"delta":{"role":"assistant","content":""}}]}"#,
+            r#"{"choices":[{"delta":{"content":"你好"}}]}"#,
+            r#"{"choices":[{"delta":{"content":"，世界"}}]}"#,
+            r#"{"choices":[{"delta":{},"finish_reason":"stop"}]}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":12,"completion_tokens":4,"total_tokens":16}}"#,
+        ];
+
+        let mut deltas = Vec::new();
+        for chunk in chunks {
+            let json: Value = serde_json::from_str(chunk).unwrap();
+            if let Some(delta) = acc.apply_chunk(&json) {
+                deltas.push(delta);
+            }
+        }
+
+        assert_eq!(deltas, vec!["你好", "，世界"]);
+        let response = acc.into_response();
+        assert_eq!(response.content.as_deref(), Some("你好，世界"));
+        assert!(!response.has_tool_calls());
+        assert_eq!(response.usage.map(|u| u.total_tokens), Some(16));
+    }
+
+    #[test]
+    fn test_stream_accumulator_tool_calls() {
+        let mut acc = StreamAccumulator::default();
+        let chunks = [
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"search","arguments":""}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"q\":"}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":1,"id":"call_2","function":{"name":"weather","arguments":"{}"}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"rust\"}"}}]}}]}"#,
+        ];
+
+        for chunk in chunks {
+            let json: Value = serde_json::from_str(chunk).unwrap();
+            assert!(acc.apply_chunk(&json).is_none());
+        }
+
+        let response = acc.into_response();
+        assert_eq!(response.content, None);
+        assert_eq!(response.tool_calls.len(), 2);
+        assert_eq!(response.tool_calls[0].id, "call_1");
+        assert_eq!(response.tool_calls[0].function.name, "search");
+        assert_eq!(response.tool_calls[0].function.arguments, r#"{"q":"rust"}"#);
+        assert_eq!(response.tool_calls[1].id, "call_2");
+        assert_eq!(response.tool_calls[1].call_type, "function");
+    }
+
+    #[test]
+    fn test_sentence_splitter() {
+        let mut splitter = SentenceSplitter::new(4);
+
+        assert!(splitter.push("今天天气").is_empty());
+        assert_eq!(splitter.push("不错。我们去"), vec!["今天天气不错。"]);
+        // 过短的句子与后续内容合并
+        assert_eq!(splitter.push("玩吧！好！走吧"), vec!["我们去玩吧！"]);
+        assert_eq!(splitter.push("\n"), vec!["好！走吧"]);
+        assert_eq!(splitter.finish(), None);
+
+        splitter.push("没有结尾");
+        assert_eq!(splitter.finish().as_deref(), Some("没有结尾"));
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let retry = RetryConfig {
+            max_retries: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 3000,
+        };
+
+        for attempt in 0..6 {
+            let expected = (500u64 << attempt).min(3000);
+            let delay = backoff_delay(&retry, attempt).as_millis() as u64;
+            assert!(delay >= expected / 2 && delay <= expected, "attempt {}: {}", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(3)));
+
+        // 已经过去的时间点视为立即重试
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_token_usage_parsing() {
+        assert_eq!(TokenUsage::from_value(&Value::Null), None);
+
+        let usage = TokenUsage::from_value(&serde_json::json!({
+            "prompt_tokens": 100,
+            "completion_tokens": 20
+        }))
+        .unwrap();
+        assert_eq!(usage.total_tokens, 120);
+
+        let mut total = TokenUsage::default();
+        total += usage;
+        total += usage;
+        assert_eq!(total.prompt_tokens, 200);
+        assert_eq!(total.total_tokens, 240);
+    }
+
+    #[test]
+    fn test_llm_message_serialization() {
+        let plain = serde_json::to_value(LlmMessage::user("你好")).unwrap();
+        assert_eq!(plain, serde_json::json!({ "role": "user", "content": "你好" }));
+
+        let with_images = LlmMessage::user_with_images(
+            "这是什么？",
+            vec!["data:image/png;base64,AAAA".to_string()],
+        );
+        let value = serde_json::to_value(&with_images).unwrap();
+        assert_eq!(value["content"][0]["type"], "text");
+        assert_eq!(value["content"][0]["text"], "这是什么？");
+        assert_eq!(value["content"][1]["type"], "image_url");
+        assert_eq!(value["content"][1]["image_url"]["url"], "data:image/png;base64,AAAA");
+
+        let tool = serde_json::to_value(LlmMessage::tool("ok", "call_1")).unwrap();
+        assert_eq!(tool["tool_call_id"], "call_1");
+        assert!(tool.get("tool_calls").is_none());
+    }
+
+    #[test]
+    fn test_think_tag_filter() {
+        let (content, reasoning) = ThinkTagFilter::split("<think>\n先分析问题\n</think>\n\n答案是 42");
+        assert_eq!(content, "答案是 42");
+        assert_eq!(reasoning.as_deref(), Some("先分析问题"));
+
+        let (content, reasoning) = ThinkTagFilter::split("a < b");
+        assert_eq!(content, "a < b");
+        assert_eq!(reasoning, None);
+
+        // 标签被拆分到多个分片中
+        let mut filter = ThinkTagFilter::new();
+        let mut visible = String::new();
+        for delta in ["<thi", "nk>思考", "中</th", "ink>", "\n你好", "<"] {
+            visible.push_str(&filter.push(delta));
+        }
+        assert_eq!(visible, "你好");
+        assert_eq!(filter.finish(), "<");
+        assert_eq!(filter.take_reasoning().as_deref(), Some("思考中"));
+    }
+
+    #[test]
+    fn test_stream_accumulator_reasoning() {
+        let mut acc = StreamAccumulator::default();
+        let chunks = [
+            r#"{"choices":[{"delta":{"reasoning_content":"用户在打招呼"}}]}"#,
+            r#"{"choices":[{"delta":{"reasoning_content":"，礼貌回复","content":null}}]}"#,
+            r#"{"choices":[{"delta":{"content":"你好！"}}]}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":10,"completion_tokens":20,"completion_tokens_details":{"reasoning_tokens":15}}}"#,
+        ];
+
+        let mut deltas = Vec::new();
+        for chunk in chunks {
+            let json: Value = serde_json::from_str(chunk).unwrap();
+            if let Some(delta) = acc.apply_chunk(&json) {
+                deltas.push(delta);
+            }
+        }
+
+        // 思考内容不会作为增量文本输出
+        assert_eq!(deltas, vec!["你好！"]);
+        let response = acc.into_response();
+        assert_eq!(response.content.as_deref(), Some("你好！"));
+        assert_eq!(
+            response.reasoning.map(|r| r.content).as_deref(),
+            Some("用户在打招呼，礼貌回复")
+        );
+        assert_eq!(response.usage.map(|u| u.reasoning_tokens), Some(15));
+    }
+
+    #[test]
+    fn test_reasoning_echo() {
+        let message = LlmMessage::assistant_with_tool_calls(None, vec![])
+            .with_reasoning(Reasoning::from_text("需要查天气"));
+        let messages = vec![LlmMessage::user("天气如何"), message];
+
+        let mut client = LlmClient::new(
+            "test-key".to_string(),
+            "https://api.deepseek.com/v1".to_string(),
+            "deepseek-reasoner".to_string(),
+            LlmRequestParams::default(),
+        )
+        .unwrap();
+
+        let body = client.build_request_body(messages.clone(), None, false);
+        assert!(body["messages"][1].get("reasoning_content").is_none());
+
+        client.request_params.echo_reasoning = true;
+        let body = client.build_request_body(messages, None, false);
+        assert_eq!(body["messages"][1]["reasoning_content"], "需要查天气");
+    }
+
+    fn mock_client(server: &MockLlmServer) -> LlmClient {
+        LlmClient::new(
+            "test-key".to_string(),
+            server.url(),
+            "mock-model".to_string(),
+            LlmRequestParams::default(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text("你好呀"));
+        server.push_reply(MockReply::tool_call("search", serde_json::json!({ "q": "rust" })));
+        let client = mock_client(&server);
+
+        let response = client
+            .chat_completion(vec![LlmMessage::user("你好")], None)
+            .await
+            .unwrap();
+        assert_eq!(response.content.as_deref(), Some("你好呀"));
+        assert_eq!(response.usage.map(|u| u.prompt_tokens), Some(MOCK_PROMPT_TOKENS));
+
+        let response = client
+            .chat_completion(vec![LlmMessage::user("搜一下")], None)
+            .await
+            .unwrap();
+        assert_eq!(response.content, None);
+        assert_eq!(response.tool_calls[0].function.name, "search");
+        assert_eq!(response.tool_calls[0].function.arguments, r#"{"q":"rust"}"#);
+
+        let requests = server.chat_requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["model"], "mock-model");
+        assert_eq!(requests[1]["messages"][0]["content"], "搜一下");
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_stream_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text("今天天气不错。"));
+        server.push_reply(MockReply::ToolCalls(vec![
+            ("search".to_string(), serde_json::json!({ "q": "天气" })),
+            ("weather".to_string(), serde_json::json!({ "city": "北京" })),
+        ]));
+        let client = mock_client(&server);
+
+        let mut deltas: Vec<String> = Vec::new();
+        let mut on_delta = |delta: &str| deltas.push(delta.to_string());
+        let response = client
+            .chat_completion_stream(vec![LlmMessage::user("天气")], None, &mut on_delta)
+            .await
+            .unwrap();
+        assert_eq!(response.content.as_deref(), Some("今天天气不错。"));
+        assert!(response.usage.is_some());
+        assert!(deltas.len() > 1);
+        assert_eq!(deltas.concat(), "今天天气不错。");
+
+        let response = client
+            .chat_completion_stream(vec![LlmMessage::user("天气")], None, &mut |_: &str| {})
+            .await
+            .unwrap();
+        assert_eq!(response.tool_calls.len(), 2);
+        assert_eq!(response.tool_calls[1].function.name, "weather");
+        assert_eq!(response.tool_calls[1].function.arguments, r#"{"city":"北京"}"#);
+
+        assert_eq!(server.chat_requests()[0]["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::Error(503, r#"{"error":"busy"}"#.to_string()));
+        server.push_reply(MockReply::Error(429, r#"{"error":"slow down"}"#.to_string()));
+        server.push_reply(MockReply::text("终于成功了"));
+        server.push_reply(MockReply::Error(401, r#"{"error":"bad key"}"#.to_string()));
+
+        let client = mock_client(&server).with_retry(RetryConfig {
+            max_retries: 2,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 2,
+        });
+
+        let response = client.chat_with_history(vec![("user".to_string(), "你好".to_string())]);
+        assert_eq!(response.await.unwrap(), "终于成功了");
+        assert_eq!(server.chat_requests().len(), 3);
+
+        // 401 不重试
+        let error = client
+            .chat_completion(vec![LlmMessage::user("你好")], None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("401"));
+        assert_eq!(server.chat_requests().len(), 4);
+    }
 }
@@ -0,0 +1,103 @@
+//! 后台维护任务
+//!
+//! 按配置的间隔定期清理过期记忆和超时会话，并维护向量索引
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+
+use crate::chatbot::reload::SharedChatBot;
+use crate::chatbot::config::MaintenanceConfig;
+
+/// 一次清理的结果
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    /// 已过期（expires_at 已到）的长期记忆条数
+    pub expired_memories: u64,
+    /// 超过 `cleanup_days` 被删除的长期记忆条数
+    pub old_memories: u64,
+    /// 超时被清理的短期会话数
+    pub stale_sessions: usize,
+}
+
+impl CleanupReport {
+    /// 是否有任何内容被清理
+    pub fn is_empty(&self) -> bool {
+        self.expired_memories == 0 && self.old_memories == 0 && self.stale_sessions == 0
+    }
+}
+
+/// 启动后台维护任务
+///
+/// 首次执行在一个间隔之后，不会拖慢插件启动；未启用时返回 None
+///
+/// # 参数
+/// - `chatbot`: 可热重载的聊天机器人（每次执行时使用当前生效的实例）
+/// - `config`: 维护任务配置
+pub fn spawn_maintenance(
+    chatbot: Arc<SharedChatBot>,
+    config: MaintenanceConfig,
+) -> Option<JoinHandle<()>> {
+    if !config.enabled || config.cleanup_interval == 0 {
+        log::info!("⏸️  后台维护任务未启用");
+        return None;
+    }
+
+    log::info!(
+        "🧹 后台维护任务已启动，清理间隔 {} 秒，索引维护间隔 {} 秒",
+        config.cleanup_interval,
+        config.index_interval
+    );
+
+    Some(tokio::spawn(async move {
+        let cleanup_period = Duration::from_secs(config.cleanup_interval);
+        let mut cleanup_timer = interval_at(Instant::now() + cleanup_period, cleanup_period);
+        cleanup_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // index_interval 为 0 时下面的分支不会被轮询，间隔取值无关紧要
+        let index_period = Duration::from_secs(config.index_interval.max(1));
+        let mut index_timer = interval_at(Instant::now() + index_period, index_period);
+        index_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = cleanup_timer.tick() => {
+                    let report = chatbot.current().run_cleanup().await;
+                    if report.is_empty() {
+                        log::debug!("🧹 定期清理完成，没有需要清理的内容");
+                    } else {
+                        log::info!(
+                            "🧹 定期清理完成：过期记忆 {} 条，超过保留天数的记忆 {} 条，超时会话 {} 个",
+                            report.expired_memories,
+                            report.old_memories,
+                            report.stale_sessions
+                        );
+                    }
+                }
+                _ = index_timer.tick(), if config.index_interval > 0 && chatbot.current().rag_enabled() => {
+                    log::info!("📊 开始维护向量索引...");
+                    match chatbot.current().maintain_vector_indexes().await {
+                        Ok(()) => log::info!("✅ 向量索引维护完成"),
+                        Err(e) => log::warn!("⚠️  向量索引维护失败: {}", e),
+                    }
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cleanup_report_is_empty() {
+        assert!(CleanupReport::default().is_empty());
+        let report = CleanupReport {
+            stale_sessions: 2,
+            ..CleanupReport::default()
+        };
+        assert!(!report.is_empty());
+    }
+}
@@ -17,6 +17,8 @@ use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::sync::{mpsc, Mutex, RwLock};
 
+use crate::chatbot::secrets::expand_references;
+
 /// MCP 协议版本
 pub const LATEST_PROTOCOL_VERSION: &str = "2024-11-05";
 
@@ -61,10 +63,17 @@ pub enum McpServerConfig {
 
 impl McpConfigFile {
     /// 从文件加载 MCP 配置
+    ///
+    /// 整个值为 `${ENV_VAR}` 或 `${file:path}` 的字符串会被展开（常用于 `env` 中的密钥）
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = std::fs::read_to_string(path.as_ref())
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
             .map_err(|e| anyhow!("读取 MCP 配置文件失败: {}", e))?;
-        let config: McpConfigFile = serde_json::from_str(&content)
+        let mut value: Value = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("解析 MCP 配置文件失败: {}", e))?;
+        expand_references(&mut value, path.parent())
+            .map_err(|e| anyhow!("展开 MCP 配置文件中的引用失败: {}", e))?;
+        let config: McpConfigFile = serde_json::from_value(value)
             .map_err(|e| anyhow!("解析 MCP 配置文件失败: {}", e))?;
         Ok(config)
     }
@@ -924,7 +933,6 @@ impl McpManager {
     }
 
     /// 关闭所有客户端
-    #[allow(dead_code)]
     pub async fn shutdown(&self) {
         for (_, client) in &self.clients {
             client.shutdown().await;
@@ -970,6 +978,36 @@ mod tests {
         assert!(config.mcp_servers.contains_key("http-server"));
     }
 
+    #[test]
+    fn test_load_keeps_file_uri_args() {
+        std::env::set_var("XIAOSHI_TEST_MCP_KEY", "mcp-secret");
+        let dir = std::env::temp_dir().join(format!("xiaoshi-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mcp.json");
+        let content = json!({
+            "mcpServers": {
+                "filesystem": {
+                    "transport": "stdio",
+                    "command": "npx",
+                    "args": ["-y", "@modelcontextprotocol/server-filesystem", "file:///data"],
+                    "env": { "API_KEY": "${XIAOSHI_TEST_MCP_KEY}" }
+                }
+            }
+        });
+        std::fs::write(&path, content.to_string()).unwrap();
+
+        let config = McpConfigFile::load(&path).unwrap();
+        match &config.mcp_servers["filesystem"] {
+            McpServerConfig::Stdio { args, env, .. } => {
+                assert_eq!(args[2], "file:///data");
+                assert_eq!(env["API_KEY"], "mcp-secret");
+            }
+            other => panic!("unexpected transport: {:?}", other),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_mcp_tool_deserialization() {
         let json = r#"{
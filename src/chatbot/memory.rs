@@ -1,22 +1,65 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::chatbot::memory_store::{ConversationSnapshot, ShortTermStore, StoreWriter};
+use crate::chatbot::tokenizer::TokenCounter;
+
+/// 摘要持续失败时最多保留的待摘要消息数
+pub const MAX_PENDING_EVICTED: usize = 200;
+
 /// 对话消息
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub struct ChatMessage {
     pub message_id: String,  // 唯一消息ID（用于去重）
     pub role: String,        // "user" 或 "assistant"
     pub content: String,
     pub timestamp: u64,      // Unix 时间戳（保留用于未来功能）
+    #[serde(default)]
+    pub token_count: usize,  // 内容的 token 数
 }
 
 /// 对话历史记录
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 struct ConversationHistory {
     messages: Vec<ChatMessage>,
     last_update: u64,
+    /// 被挤出短期记忆、等待合并进摘要的消息
+    evicted: Vec<ChatMessage>,
+    /// 早前对话的滚动摘要
+    summary: Option<String>,
+    /// 是否有摘要任务正在进行
+    summarizing: bool,
+}
+
+impl ConversationHistory {
+    fn new(timestamp: u64) -> Self {
+        Self {
+            last_update: timestamp,
+            ..Default::default()
+        }
+    }
+
+    /// 清空历史（超时后调用），摘要一并清除
+    fn reset(&mut self) {
+        self.messages.clear();
+        self.evicted.clear();
+        self.summary = None;
+        self.summarizing = false;
+    }
+
+    /// 生成用于持久化的快照
+    fn snapshot(&self, key: &str) -> ConversationSnapshot {
+        ConversationSnapshot {
+            key: key.to_string(),
+            messages: self.messages.clone(),
+            last_update: self.last_update,
+            evicted: self.evicted.clone(),
+            summary: self.summary.clone(),
+        }
+    }
 }
 
 /// 对话记忆管理器
@@ -24,6 +67,11 @@ pub struct Memory {
     histories: Arc<Mutex<HashMap<String, ConversationHistory>>>,
     history_limit: usize,
     history_timeout: u64,
+    token_counter: Arc<dyn TokenCounter>,
+    /// 是否保留被挤出的消息用于生成摘要
+    track_evicted: bool,
+    /// 持久化存储的写入线程（未配置时仅保存在内存中）
+    store: Option<StoreWriter>,
 }
 
 impl Memory {
@@ -32,14 +80,85 @@ impl Memory {
     /// # 参数
     /// - `history_limit`: 每个对话保留的最大消息数
     /// - `history_timeout`: 对话超时时间（秒），超时后清空历史
-    pub fn new(history_limit: usize, history_timeout: u64) -> Self {
+    /// - `token_counter`: 用于统计每条消息的 token 数
+    pub fn new(
+        history_limit: usize,
+        history_timeout: u64,
+        token_counter: Arc<dyn TokenCounter>,
+    ) -> Self {
         Self {
             histories: Arc::new(Mutex::new(HashMap::new())),
             history_limit,
             history_timeout,
+            token_counter,
+            track_evicted: false,
+            store: None,
         }
     }
 
+    /// 使用持久化存储，并从中恢复未超时的会话
+    ///
+    /// 之后每次会话变化都会保存快照
+    pub fn with_store(mut self, store: Arc<dyn ShortTermStore>) -> Self {
+        match store.load() {
+            Ok(snapshots) => {
+                let now = Self::current_timestamp();
+                let snapshots: Vec<ConversationSnapshot> = snapshots
+                    .into_iter()
+                    .filter(|snapshot| {
+                        now.saturating_sub(snapshot.last_update) <= self.history_timeout
+                    })
+                    .collect();
+
+                // 重写存储，去掉过期会话和旧快照
+                if let Err(e) = store.replace_all(&snapshots) {
+                    log::warn!("⚠️  压缩短期记忆存储失败: {}", e);
+                }
+
+                let mut histories = self.histories.lock().unwrap();
+                for snapshot in snapshots {
+                    let mut messages = snapshot.messages;
+                    // 词表可能已更换，重新计数
+                    for msg in &mut messages {
+                        msg.token_count = self.token_counter.count(&msg.content);
+                    }
+                    histories.insert(
+                        snapshot.key,
+                        ConversationHistory {
+                            messages,
+                            last_update: snapshot.last_update.min(now),
+                            evicted: snapshot.evicted,
+                            summary: snapshot.summary,
+                            summarizing: false,
+                        },
+                    );
+                }
+                if !histories.is_empty() {
+                    log::info!("💾 已恢复 {} 个会话的短期记忆", histories.len());
+                }
+            }
+            Err(e) => log::warn!("⚠️  加载短期记忆失败: {}", e),
+        }
+
+        self.store = Some(StoreWriter::spawn(store));
+        self
+    }
+
+    /// 把会话快照交给写入线程保存
+    ///
+    /// 在持有 `histories` 锁时调用，只复制快照并排队，不等待磁盘写入
+    fn persist(&self, key: &str, history: &ConversationHistory) {
+        if let Some(store) = &self.store {
+            store.save(history.snapshot(key));
+        }
+    }
+
+    /// 保留被挤出的消息，供 [`Memory::take_evicted`] 取出生成摘要
+    pub fn with_evicted_tracking(mut self) -> Self {
+        self.track_evicted = true;
+        self
+    }
+
     /// 生成对话 key
     /// 
     /// # 参数
@@ -56,6 +175,14 @@ impl Memory {
         }
     }
 
+    /// 生成群聊共享会话的 key
+    /// 
+    /// # 返回
+    /// 返回 "{group_id}:group"，全群成员共用
+    pub fn generate_group_key(group_id: i64) -> String {
+        format!("{}:group", group_id)
+    }
+
     /// 获取当前时间戳
     fn current_timestamp() -> u64 {
         SystemTime::now()
@@ -92,33 +219,27 @@ impl Memory {
         let timestamp = Self::current_timestamp();
         let message_id = Self::generate_message_id(key, timestamp, "user");
 
-        let history = histories.entry(key.to_string()).or_insert_with(|| {
-            ConversationHistory {
-                messages: Vec::new(),
-                last_update: timestamp,
-            }
-        });
+        let history = histories.entry(key.to_string()).or_insert_with(|| ConversationHistory::new(timestamp));
 
         // 检查是否超时，如果超时则清空历史
         if timestamp - history.last_update > self.history_timeout {
-            history.messages.clear();
+            history.reset();
         }
 
         // 添加用户消息
         history.messages.push(ChatMessage {
             message_id: message_id.clone(),
             role: "user".to_string(),
+            token_count: self.token_counter.count(&content),
             content,
             timestamp,
         });
 
         // 限制历史消息数量（保留最近的消息）
-        if history.messages.len() > self.history_limit {
-            let excess = history.messages.len() - self.history_limit;
-            history.messages.drain(0..excess);
-        }
+        self.trim(history);
 
         history.last_update = timestamp;
+        self.persist(key, history);
         message_id
     }
 
@@ -139,31 +260,135 @@ impl Memory {
             history.messages.push(ChatMessage {
                 message_id: message_id.clone(),
                 role: "assistant".to_string(),
+                token_count: self.token_counter.count(&content),
                 content,
                 timestamp,
             });
 
             // 限制历史消息数量
-            if history.messages.len() > self.history_limit {
-                let excess = history.messages.len() - self.history_limit;
-                history.messages.drain(0..excess);
-            }
+            self.trim(history);
 
             history.last_update = timestamp;
+            self.persist(key, history);
         }
         
         message_id
     }
 
+    /// 按 `history_limit` 裁剪最早的消息
+    ///
+    /// 裁剪后开头残留的 assistant 回复会一并移除，避免拆开一问一答
+    fn trim(&self, history: &mut ConversationHistory) {
+        if history.messages.len() <= self.history_limit {
+            return;
+        }
+
+        let mut excess = history.messages.len() - self.history_limit;
+        while excess < history.messages.len() && history.messages[excess].role == "assistant" {
+            excess += 1;
+        }
+
+        let evicted = history.messages.drain(0..excess);
+        if self.track_evicted {
+            history.evicted.extend(evicted);
+        }
+    }
+
+    /// 取出等待摘要的被挤出消息
+    ///
+    /// 累计不足 `min_messages` 条或已有摘要任务进行中时返回空列表；
+    /// 返回非空列表后需调用 [`Memory::finish_summary`] 结束本次摘要
+    ///
+    /// # 参数
+    /// - `key`: 对话标识
+    /// - `min_messages`: 触发摘要的最少消息数
+    pub fn take_evicted(&self, key: &str, min_messages: usize) -> Vec<ChatMessage> {
+        let mut histories = self.histories.lock().unwrap();
+        match histories.get_mut(key) {
+            Some(history)
+                if !history.summarizing
+                    && !history.evicted.is_empty()
+                    && history.evicted.len() >= min_messages =>
+            {
+                history.summarizing = true;
+                std::mem::take(&mut history.evicted)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// 结束一次摘要任务
+    ///
+    /// # 参数
+    /// - `key`: 对话标识
+    /// - `summary`: 合并了被挤出消息的新摘要
+    pub fn finish_summary(&self, key: &str, summary: String) {
+        let mut histories = self.histories.lock().unwrap();
+        if let Some(history) = histories.get_mut(key) {
+            // 摘要期间会话已超时重置，丢弃结果
+            if !history.summarizing {
+                return;
+            }
+            history.summarizing = false;
+            history.summary = Some(summary);
+            self.persist(key, history);
+        }
+    }
+
+    /// 摘要失败时放回取出的消息，下次摘要时重试（保留旧摘要）
+    ///
+    /// 放回后等待摘要的消息超过 [`MAX_PENDING_EVICTED`] 条时丢弃最早的部分
+    ///
+    /// # 参数
+    /// - `key`: 对话标识
+    /// - `evicted`: [`Memory::take_evicted`] 取出的消息
+    pub fn restore_evicted(&self, key: &str, evicted: Vec<ChatMessage>) {
+        let mut histories = self.histories.lock().unwrap();
+        if let Some(history) = histories.get_mut(key) {
+            if !history.summarizing {
+                return;
+            }
+            history.summarizing = false;
+
+            // 摘要期间新挤出的消息排在放回的消息之后
+            let newer = std::mem::replace(&mut history.evicted, evicted);
+            history.evicted.extend(newer);
+            if history.evicted.len() > MAX_PENDING_EVICTED {
+                let dropped = history.evicted.len() - MAX_PENDING_EVICTED;
+                history.evicted.drain(0..dropped);
+                log::warn!("⚠️  会话 {} 等待摘要的消息过多，丢弃最早的 {} 条", key, dropped);
+            }
+            self.persist(key, history);
+        }
+    }
+
+    /// 获取早前对话的滚动摘要
+    ///
+    /// # 参数
+    /// - `key`: 对话标识
+    pub fn get_summary(&self, key: &str) -> Option<String> {
+        let histories = self.histories.lock().unwrap();
+        histories.get(key).and_then(|h| h.summary.clone())
+    }
+
     /// 获取对话历史
     /// 
+    /// 按轮次（一条 user 消息及其后的 assistant 回复）从新到旧选取，
+    /// 超出 token 预算时丢弃更早的轮次，不会拆开一问一答
+    /// 
     /// # 参数
     /// - `key`: 对话标识
     /// - `system_prompt`: 系统提示词
+    /// - `token_budget`: 历史消息可用的 token 数
     /// 
     /// # 返回
     /// 返回格式化的消息历史，包含 system 消息
-    pub fn get_history(&self, key: &str, system_prompt: &str) -> Vec<(String, String)> {
+    pub fn get_history(
+        &self,
+        key: &str,
+        system_prompt: &str,
+        token_budget: usize,
+    ) -> Vec<(String, String)> {
         let mut histories = self.histories.lock().unwrap();
         let timestamp = Self::current_timestamp();
 
@@ -174,12 +399,43 @@ impl Memory {
         if let Some(history) = histories.get_mut(key) {
             // 检查是否超时
             if timestamp - history.last_update > self.history_timeout {
-                history.messages.clear();
+                history.reset();
+                self.persist(key, history);
                 return messages;
             }
 
+            // 按轮次分组：每轮从 user 消息开始
+            let mut turns: Vec<&[ChatMessage]> = Vec::new();
+            let mut start = 0;
+            for i in 1..=history.messages.len() {
+                if i == history.messages.len() || history.messages[i].role == "user" {
+                    turns.push(&history.messages[start..i]);
+                    start = i;
+                }
+            }
+
+            // 从最新的轮次开始累计，超出预算即停止
+            let mut used_tokens = 0;
+            let mut kept = 0;
+            for turn in turns.iter().rev() {
+                let tokens: usize = turn.iter().map(|msg| msg.token_count).sum();
+                if used_tokens + tokens > token_budget {
+                    break;
+                }
+                used_tokens += tokens;
+                kept += 1;
+            }
+
+            if kept < turns.len() {
+                log::debug!(
+                    "✂️  短期记忆超出 token 预算 ({})，丢弃最早的 {} 轮对话",
+                    token_budget,
+                    turns.len() - kept
+                );
+            }
+
             // 添加历史消息
-            for msg in &history.messages {
+            for msg in turns[turns.len() - kept..].iter().flat_map(|turn| turn.iter()) {
                 messages.push((msg.role.clone(), msg.content.clone()));
             }
         }
@@ -229,12 +485,7 @@ impl Memory {
         }
         
         // 创建或获取历史记录
-        let history = histories.entry(key.to_string()).or_insert_with(|| {
-            ConversationHistory {
-                messages: Vec::new(),
-                last_update: timestamp,
-            }
-        });
+        let history = histories.entry(key.to_string()).or_insert_with(|| ConversationHistory::new(timestamp));
         
         // 添加消息
         let mut count = 0;
@@ -242,6 +493,7 @@ impl Memory {
             history.messages.push(ChatMessage {
                 message_id,
                 role,
+                token_count: self.token_counter.count(&content),
                 content,
                 timestamp: msg_timestamp,
             });
@@ -249,13 +501,11 @@ impl Memory {
         }
         
         // 限制数量
-        if history.messages.len() > self.history_limit {
-            let excess = history.messages.len() - self.history_limit;
-            history.messages.drain(0..excess);
-            count = self.history_limit;
-        }
+        self.trim(history);
+        count = count.min(history.messages.len());
         
         history.last_update = timestamp;
+        self.persist(key, history);
         count
     }
     
@@ -269,10 +519,12 @@ impl Memory {
     /// 
     /// # 参数
     /// - `key`: 对话标识
-    #[allow(dead_code)]
     pub fn clear_history(&self, key: &str) {
         let mut histories = self.histories.lock().unwrap();
-        histories.remove(key);
+        if let Some(mut history) = histories.remove(key) {
+            history.reset();
+            self.persist(key, &history);
+        }
     }
 
     /// 清除所有对话历史
@@ -280,6 +532,9 @@ impl Memory {
     pub fn clear_all(&self) {
         let mut histories = self.histories.lock().unwrap();
         histories.clear();
+        if let Some(store) = &self.store {
+            store.replace_all(Vec::new());
+        }
     }
 
     /// 获取历史消息数量
@@ -292,6 +547,18 @@ impl Memory {
         histories.get(key).map(|h| h.messages.len()).unwrap_or(0)
     }
 
+    /// 获取历史消息的 token 总数
+    ///
+    /// # 参数
+    /// - `key`: 对话标识
+    pub fn get_token_count(&self, key: &str) -> usize {
+        let histories = self.histories.lock().unwrap();
+        histories
+            .get(key)
+            .map(|h| h.messages.iter().map(|msg| msg.token_count).sum())
+            .unwrap_or(0)
+    }
+
     /// 获取所有对话数量
     #[allow(dead_code)]
     pub fn get_conversation_count(&self) -> usize {
@@ -300,36 +567,57 @@ impl Memory {
     }
 
     /// 清理超时的对话历史
-    #[allow(dead_code)]
-    pub fn cleanup_expired(&self) {
+    /// 
+    /// # 返回
+    /// 被清理的会话数量
+    pub fn cleanup_expired(&self) -> usize {
         let mut histories = self.histories.lock().unwrap();
         let timestamp = Self::current_timestamp();
 
-        histories.retain(|_, history| {
-            timestamp - history.last_update <= self.history_timeout
-        });
+        let expired: Vec<String> = histories
+            .iter()
+            .filter(|(_, history)| {
+                timestamp.saturating_sub(history.last_update) > self.history_timeout
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            if let Some(mut history) = histories.remove(key) {
+                history.reset();
+                self.persist(key, &history);
+            }
+        }
+
+        expired.len()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chatbot::tokenizer::HeuristicCounter;
+
+    fn test_memory(history_limit: usize) -> Memory {
+        Memory::new(history_limit, 3600, Arc::new(HeuristicCounter))
+    }
 
     #[test]
     fn test_generate_key() {
         assert_eq!(Memory::generate_key(123456, None), "123456");
         assert_eq!(Memory::generate_key(123456, Some(789)), "789:123456");
+        assert_eq!(Memory::generate_group_key(789), "789:group");
     }
 
     #[test]
     fn test_add_and_get_messages() {
-        let memory = Memory::new(10, 3600);
+        let memory = test_memory(10);
         let key = "test_user";
 
         memory.add_user_message(key, "你好".to_string());
         memory.add_assistant_message(key, "你好！有什么我可以帮你的吗？".to_string());
 
-        let history = memory.get_history(key, "你是一个测试助手。");
+        let history = memory.get_history(key, "你是一个测试助手。", usize::MAX);
         assert_eq!(history.len(), 3); // system + user + assistant
         assert_eq!(history[0].0, "system");
         assert_eq!(history[1].0, "user");
@@ -338,7 +626,7 @@ mod tests {
 
     #[test]
     fn test_history_limit() {
-        let memory = Memory::new(3, 3600);
+        let memory = test_memory(3);
         let key = "test_user";
 
         for i in 0..5 {
@@ -350,7 +638,7 @@ mod tests {
 
     #[test]
     fn test_clear_history() {
-        let memory = Memory::new(10, 3600);
+        let memory = test_memory(10);
         let key = "test_user";
 
         memory.add_user_message(key, "测试消息".to_string());
@@ -359,5 +647,154 @@ mod tests {
         memory.clear_history(key);
         assert_eq!(memory.get_message_count(key), 0);
     }
+
+    #[test]
+    fn test_history_token_budget() {
+        let memory = test_memory(20);
+        let key = "test_user";
+
+        // 每轮：user 2 tokens + assistant 4 tokens
+        for i in 0..3 {
+            memory.add_user_message(key, format!("问{}", i));
+            memory.add_assistant_message(key, "回答内容".to_string());
+        }
+        memory.add_user_message(key, "长问题".repeat(10));
+
+        // 最新一轮（仅 user，30 tokens）+ 两轮完整问答
+        let history = memory.get_history(key, "系统", 30 + 6 * 2 + 5);
+        let roles: Vec<&str> = history.iter().map(|(role, _)| role.as_str()).collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user", "assistant", "user"]);
+        assert_eq!(history[1].1, "问1");
+
+        // 预算不足以容纳最新一轮时只保留 system
+        let history = memory.get_history(key, "系统", 10);
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn test_evicted_messages_for_summary() {
+        let memory = test_memory(3).with_evicted_tracking();
+        let key = "test_user";
+
+        for i in 0..3 {
+            memory.add_user_message(key, format!("问{}", i));
+            memory.add_assistant_message(key, format!("答{}", i));
+        }
+
+        // 挤出时不留下开头的 assistant 回复
+        let history = memory.get_history(key, "系统", usize::MAX);
+        assert_eq!(history[1].1, "问2");
+        assert_eq!(memory.get_message_count(key), 2);
+
+        // 不足批量大小时不触发
+        assert!(memory.take_evicted(key, 10).is_empty());
+
+        let evicted = memory.take_evicted(key, 4);
+        let contents: Vec<&str> = evicted.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["问0", "答0", "问1", "答1"]);
+
+        // 摘要进行中不会重复取出
+        memory.add_user_message(key, "问3".to_string());
+        memory.add_assistant_message(key, "答3".to_string());
+        assert!(memory.take_evicted(key, 1).is_empty());
+
+        memory.finish_summary(key, "摘要".to_string());
+        assert_eq!(memory.get_summary(key).as_deref(), Some("摘要"));
+        let evicted = memory.take_evicted(key, 1);
+        assert_eq!(evicted.len(), 2);
+
+        // 摘要失败时保留旧摘要，取出的消息放回队首等待重试
+        memory.add_user_message(key, "问4".to_string());
+        memory.add_assistant_message(key, "答4".to_string());
+        memory.restore_evicted(key, evicted);
+        assert_eq!(memory.get_summary(key).as_deref(), Some("摘要"));
+        let retried = memory.take_evicted(key, 1);
+        let contents: Vec<&str> = retried.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["问2", "答2", "问3", "答3"]);
+    }
+
+    #[test]
+    fn test_evicted_not_tracked_by_default() {
+        let memory = test_memory(2);
+        let key = "test_user";
+
+        for i in 0..3 {
+            memory.add_user_message(key, format!("问{}", i));
+            memory.add_assistant_message(key, format!("答{}", i));
+        }
+
+        assert!(memory.take_evicted(key, 1).is_empty());
+        assert!(memory.get_summary(key).is_none());
+    }
+
+    #[test]
+    fn test_restore_from_store() {
+        use crate::chatbot::memory_store::JsonlStore;
+
+        let dir = std::env::temp_dir().join(format!("xiaoshi-test-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn ShortTermStore> = Arc::new(JsonlStore::new(dir.join("memory.jsonl")));
+        let key = "test_user";
+
+        let memory = test_memory(10).with_store(store.clone());
+        memory.add_user_message(key, "你好".to_string());
+        memory.add_assistant_message(key, "你好呀".to_string());
+        memory.add_user_message("cleared_user", "再见".to_string());
+        memory.clear_history("cleared_user");
+
+        // 已超时的会话不会被恢复
+        store
+            .save(&ConversationSnapshot {
+                key: "expired_user".to_string(),
+                messages: memory.histories.lock().unwrap()[key].messages.clone(),
+                last_update: 0,
+                evicted: Vec::new(),
+                summary: None,
+            })
+            .unwrap();
+        drop(memory);
+
+        // 模拟插件重启
+        let memory = test_memory(10).with_store(store);
+        let history = memory.get_history(key, "系统", usize::MAX);
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[2].1, "你好呀");
+        assert_eq!(memory.get_token_count(key), 2 + 3);
+        assert!(!memory.is_initialized("cleared_user"));
+        assert!(!memory.is_initialized("expired_user"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_expired() {
+        let memory = test_memory(10);
+        memory.add_user_message("active_user", "你好".to_string());
+        memory.add_user_message("stale_user", "你好".to_string());
+        memory
+            .histories
+            .lock()
+            .unwrap()
+            .get_mut("stale_user")
+            .unwrap()
+            .last_update = 0;
+
+        assert_eq!(memory.cleanup_expired(), 1);
+        assert_eq!(memory.get_conversation_count(), 1);
+        assert!(memory.is_initialized("active_user"));
+        assert_eq!(memory.cleanup_expired(), 0);
+    }
+
+    #[test]
+    fn test_token_count() {
+        let memory = test_memory(10);
+        let key = "test_user";
+
+        memory.add_user_message(key, "你好".to_string());
+        memory.add_assistant_message(key, "hello world".to_string());
+
+        // 中文按字计数，英文约 4 字符 1 个 token
+        assert_eq!(memory.get_token_count(key), 2 + 3);
+        assert_eq!(memory.get_token_count("unknown"), 0);
+    }
 }
 
@@ -4,9 +4,10 @@
 
 use anyhow::Result;
 use chrono::{DateTime, Duration, Utc};
+use std::sync::Arc;
 
-use crate::chatbot::config::MemoryEvaluationConfig;
-use crate::chatbot::llm::{LlmClient, LlmRequestParams};
+use crate::chatbot::config::{MemoryEvaluationConfig, RetryConfig};
+use crate::chatbot::llm::{create_backend, LlmBackend, LlmRequestParams};
 
 /// 记忆保留时长枚举
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -67,7 +68,7 @@ impl RetentionDuration {
 
 /// 记忆评估器
 pub struct MemoryEvaluator {
-    llm_client: LlmClient,
+    llm_client: Arc<dyn LlmBackend>,
     system_prompt: String,
 }
 
@@ -81,13 +82,16 @@ impl MemoryEvaluator {
             max_tokens: config.max_tokens,
             presence_penalty: config.presence_penalty,
             frequency_penalty: config.frequency_penalty,
+            ..LlmRequestParams::default()
         };
         
-        let llm_client = LlmClient::new(
+        let llm_client = create_backend(
+            config.provider,
             config.apikey.clone(),
             config.url.clone(),
             config.model.clone(),
             llm_params,
+            RetryConfig::default(),
         ).map_err(|e| anyhow::anyhow!("记忆评估器初始化失败: {}", e))?;
 
         Ok(Self {
@@ -202,6 +206,8 @@ impl MemoryEvaluator {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chatbot::config::{Config, LlmProvider};
+    use crate::chatbot::test_support::{MockLlmServer, MockReply};
 
     /// 测试用例结构
     #[derive(Debug)]
@@ -339,6 +345,7 @@ mod tests {
         // 需要设置环境变量
         let config = MemoryEvaluationConfig {
             enabled: true,
+            provider: LlmProvider::default(),
             model: "deepseek-chat".to_string(),
             url: "https://api.deepseek.com/v1".to_string(),
             apikey: std::env::var("TEST_API_KEY")
@@ -437,5 +444,37 @@ mod tests {
             pass_rate * 100.0
         );
     }
-}
 
+    #[tokio::test]
+    async fn test_evaluate_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text(
+            "```json\n{\"score\": 92, \"reason\": \"用户的生日属于事实性信息\"}\n```",
+        ));
+        server.push_reply(MockReply::text("无法判断"));
+
+        let mut config = Config::default().memory.rag.memory_evaluation;
+        config.model = "mock-model".to_string();
+        config.url = server.url();
+        let evaluator = MemoryEvaluator::new(config).unwrap();
+
+        let (score, duration, expiry) = evaluator
+            .evaluate_and_decide("我的生日是3月5日", "记住啦")
+            .await
+            .unwrap();
+        assert_eq!(score, 92);
+        assert_eq!(duration, RetentionDuration::Forever);
+        assert!(expiry.is_none());
+
+        // 无法解析时使用默认分数
+        let score = evaluator.evaluate("哈哈", "嘿嘿").await.unwrap();
+        assert_eq!(score, 50);
+
+        let requests = server.chat_requests();
+        assert_eq!(requests[0]["messages"][0]["role"], "system");
+        assert_eq!(
+            requests[0]["messages"][1]["content"],
+            "User: 我的生日是3月5日\nAssistant: 记住啦"
+        );
+    }
+}
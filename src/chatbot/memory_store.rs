@@ -0,0 +1,350 @@
+//! 短期记忆持久化
+//!
+//! 未启用 PostgreSQL 时短期记忆只存在于内存中，插件重启即丢失。
+//! [`ShortTermStore`] 在每次会话变化时保存快照，启动时恢复。
+//! 快照由 [`StoreWriter`] 的后台线程写入，聊天流程不等待磁盘 I/O
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::chatbot::memory::ChatMessage;
+
+/// 单个会话的短期记忆快照
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationSnapshot {
+    /// 对话标识
+    pub key: String,
+    /// 短期记忆中的消息（为空表示会话已被清除）
+    pub messages: Vec<ChatMessage>,
+    /// 最后更新时间（Unix 时间戳）
+    pub last_update: u64,
+    /// 等待合并进摘要的消息
+    #[serde(default)]
+    pub evicted: Vec<ChatMessage>,
+    /// 早前对话的滚动摘要
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+/// 短期记忆存储
+pub trait ShortTermStore: Send + Sync {
+    /// 加载所有会话的最新快照
+    fn load(&self) -> Result<Vec<ConversationSnapshot>>;
+
+    /// 保存单个会话的快照
+    fn save(&self, snapshot: &ConversationSnapshot) -> Result<()>;
+
+    /// 用给定的快照替换全部内容（用于启动时压缩和清空）
+    fn replace_all(&self, snapshots: &[ConversationSnapshot]) -> Result<()>;
+}
+
+/// 文件至少达到这个大小（字节）才会在运行中压缩
+const COMPACT_MIN_BYTES: u64 = 1024 * 1024;
+
+/// 基于 JSON Lines 文件的短期记忆存储
+///
+/// 每次变化追加一行会话快照，同一会话以最后一行为准。启动加载后重写文件去掉旧快照；
+/// 运行中文件超过上次压缩后大小的两倍（且不小于 [`COMPACT_MIN_BYTES`]）时再次压缩
+pub struct JsonlStore {
+    path: PathBuf,
+    /// 串行化文件读写，保存上次压缩后的文件大小
+    compacted_len: Mutex<u64>,
+    /// 触发压缩的最小文件大小
+    compact_min_bytes: u64,
+}
+
+impl JsonlStore {
+    /// 创建文件存储
+    ///
+    /// # 参数
+    /// - `path`: JSONL 文件路径，不存在时在首次写入时创建
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            compacted_len: Mutex::new(0),
+            compact_min_bytes: COMPACT_MIN_BYTES,
+        }
+    }
+
+    /// 读取每个会话的最新快照（调用方需持有锁）
+    fn read_latest(&self) -> Result<Vec<ConversationSnapshot>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = fs::read_to_string(&self.path)?;
+        let mut latest: HashMap<String, ConversationSnapshot> = HashMap::new();
+        for (line_no, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // 进程崩溃可能留下写了一半的最后一行，跳过即可
+            match serde_json::from_str::<ConversationSnapshot>(line) {
+                Ok(snapshot) => {
+                    latest.insert(snapshot.key.clone(), snapshot);
+                }
+                Err(e) => log::warn!("⚠️  短期记忆文件第 {} 行解析失败: {}", line_no + 1, e),
+            }
+        }
+
+        let mut snapshots: Vec<ConversationSnapshot> = latest
+            .into_values()
+            .filter(|snapshot| !snapshot.messages.is_empty())
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.last_update);
+        Ok(snapshots)
+    }
+
+    /// 重写文件，返回写入后的文件大小（调用方需持有锁）
+    fn write_all(&self, snapshots: &[ConversationSnapshot]) -> Result<u64> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut content = String::new();
+        for snapshot in snapshots {
+            content.push_str(&serde_json::to_string(snapshot)?);
+            content.push('\n');
+        }
+
+        // 先写临时文件再重命名，避免中途崩溃损坏原文件
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        fs::write(&tmp_path, &content)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(content.len() as u64)
+    }
+}
+
+impl ShortTermStore for JsonlStore {
+    fn load(&self) -> Result<Vec<ConversationSnapshot>> {
+        let _guard = self.compacted_len.lock().unwrap();
+        self.read_latest()
+    }
+
+    fn save(&self, snapshot: &ConversationSnapshot) -> Result<()> {
+        let mut compacted_len = self.compacted_len.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut line = serde_json::to_string(snapshot)?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+
+        // 文件增长过多时去掉旧快照，避免长时间运行后无限增长
+        let len = file.metadata()?.len();
+        drop(file);
+        if len > (*compacted_len * 2).max(self.compact_min_bytes) {
+            let snapshots = self.read_latest()?;
+            *compacted_len = self.write_all(&snapshots)?;
+            log::debug!(
+                "🗜️  短期记忆文件已压缩: {} -> {} 字节（{} 个会话）",
+                len,
+                *compacted_len,
+                snapshots.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn replace_all(&self, snapshots: &[ConversationSnapshot]) -> Result<()> {
+        let mut compacted_len = self.compacted_len.lock().unwrap();
+        *compacted_len = self.write_all(snapshots)?;
+        Ok(())
+    }
+}
+
+/// 写入线程的任务
+enum WriteOp {
+    Save(ConversationSnapshot),
+    ReplaceAll(Vec<ConversationSnapshot>),
+}
+
+/// 短期记忆的后台写入线程
+///
+/// 调用方在持有会话锁时把快照放进队列，保证同一会话的快照按变化顺序写入；
+/// 销毁时写完队列中剩余的快照再退出
+pub struct StoreWriter {
+    sender: Option<mpsc::Sender<WriteOp>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StoreWriter {
+    /// 启动写入线程
+    pub fn spawn(store: Arc<dyn ShortTermStore>) -> Self {
+        let (sender, receiver) = mpsc::channel::<WriteOp>();
+        let handle = std::thread::Builder::new()
+            .name("xiaoshi-memory-store".to_string())
+            .spawn(move || {
+                for op in receiver {
+                    let result = match &op {
+                        WriteOp::Save(snapshot) => store.save(snapshot),
+                        WriteOp::ReplaceAll(snapshots) => store.replace_all(snapshots),
+                    };
+                    if let Err(e) = result {
+                        log::warn!("⚠️  保存短期记忆失败: {}", e);
+                    }
+                }
+            })
+            .map_err(|e| log::warn!("⚠️  启动短期记忆写入线程失败: {}", e))
+            .ok();
+
+        Self {
+            sender: handle.as_ref().map(|_| sender),
+            handle,
+        }
+    }
+
+    /// 排队保存单个会话的快照
+    pub fn save(&self, snapshot: ConversationSnapshot) {
+        self.send(WriteOp::Save(snapshot));
+    }
+
+    /// 排队替换全部内容
+    pub fn replace_all(&self, snapshots: Vec<ConversationSnapshot>) {
+        self.send(WriteOp::ReplaceAll(snapshots));
+    }
+
+    fn send(&self, op: WriteOp) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(op);
+        }
+    }
+}
+
+impl Drop for StoreWriter {
+    fn drop(&mut self) {
+        // 关闭队列后等待线程写完剩余的快照
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("xiaoshi-test-{}", uuid::Uuid::new_v4()))
+            .join("short_term_memory.jsonl")
+    }
+
+    fn snapshot(key: &str, contents: &[&str], last_update: u64) -> ConversationSnapshot {
+        ConversationSnapshot {
+            key: key.to_string(),
+            messages: contents
+                .iter()
+                .map(|content| ChatMessage {
+                    message_id: format!("msg_{}", content),
+                    role: "user".to_string(),
+                    content: content.to_string(),
+                    timestamp: last_update,
+                    token_count: 1,
+                })
+                .collect(),
+            last_update,
+            evicted: Vec::new(),
+            summary: None,
+        }
+    }
+
+    #[test]
+    fn test_jsonl_store_latest_snapshot_wins() {
+        let path = temp_store_path();
+        let store = JsonlStore::new(&path);
+        assert!(store.load().unwrap().is_empty());
+
+        store.save(&snapshot("a", &["你好"], 1)).unwrap();
+        store.save(&snapshot("b", &["在吗"], 2)).unwrap();
+        store
+            .save(&snapshot("a", &["你好", "今天吃什么"], 3))
+            .unwrap();
+        // 空快照表示会话已清除
+        store.save(&snapshot("b", &[], 4)).unwrap();
+
+        let snapshots = store.load().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].key, "a");
+        assert_eq!(snapshots[0].messages.len(), 2);
+
+        // 压缩后文件只剩最新快照
+        store.replace_all(&snapshots).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 1);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_jsonl_store_compacts_while_running() {
+        let path = temp_store_path();
+        let store = JsonlStore {
+            compact_min_bytes: 2048,
+            ..JsonlStore::new(&path)
+        };
+
+        for i in 0..100 {
+            store.save(&snapshot("a", &["你好", "今天吃什么"], i)).unwrap();
+            store.save(&snapshot("b", &["在吗"], i)).unwrap();
+        }
+
+        // 文件大小受限于压缩阈值，内容仍是每个会话的最新快照
+        let len = fs::metadata(&path).unwrap().len();
+        assert!(len <= 2048, "{}", len);
+        let snapshots = store.load().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots.iter().all(|snapshot| snapshot.last_update == 99));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_store_writer_flushes_on_drop() {
+        let path = temp_store_path();
+        let store: Arc<dyn ShortTermStore> = Arc::new(JsonlStore::new(&path));
+
+        let writer = StoreWriter::spawn(store.clone());
+        writer.save(snapshot("a", &["你好"], 1));
+        writer.save(snapshot("a", &["你好", "在吗"], 2));
+        writer.replace_all(vec![snapshot("b", &["早"], 3)]);
+        writer.save(snapshot("c", &["晚安"], 4));
+        drop(writer);
+
+        // 按排队顺序写入，销毁前写完
+        let keys: Vec<String> = store.load().unwrap().into_iter().map(|s| s.key).collect();
+        assert_eq!(keys, vec!["b", "c"]);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_jsonl_store_skips_broken_line() {
+        let path = temp_store_path();
+        let store = JsonlStore::new(&path);
+        store.save(&snapshot("a", &["你好"], 1)).unwrap();
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"key\":\"a\",\"messa").unwrap();
+
+        let snapshots = store.load().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].messages[0].content, "你好");
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! 数据库结构迁移
+//!
+//! 每个迁移有递增的版本号，已执行的版本记录在 `schema_migrations` 表中，
+//! 启动时按版本顺序执行尚未执行的迁移。早期版本直接建表，迁移语句都使用
+//! `IF NOT EXISTS`，在旧数据库上执行不会破坏已有数据。
+//!
+//! 新的结构变更只能追加到 [`MIGRATIONS`] 末尾，不能修改已发布的迁移
+
+use anyhow::Result;
+use sqlx::postgres::PgPool;
+
+/// 迁移语句中的向量维度占位符
+const DIMENSION_PLACEHOLDER: &str = "{dimension}";
+
+/// 一个版本化迁移
+#[derive(Debug)]
+pub struct Migration {
+    /// 版本号，必须严格递增
+    pub version: i32,
+    /// 迁移名称
+    pub name: &'static str,
+    /// 按顺序执行的 SQL 语句，`{dimension}` 会替换为嵌入向量维度
+    statements: &'static [&'static str],
+}
+
+impl Migration {
+    /// 生成实际执行的 SQL 语句
+    fn render(&self, dimension: usize) -> Vec<String> {
+        self.statements
+            .iter()
+            .map(|sql| sql.replace(DIMENSION_PLACEHOLDER, &dimension.to_string()))
+            .collect()
+    }
+}
+
+/// 所有迁移，按版本号排序
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_dialogues",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS dialogues (
+                id SERIAL PRIMARY KEY,
+                message_uuid TEXT UNIQUE NOT NULL,
+                user_id BIGINT NOT NULL,
+                group_id BIGINT,
+                chat_type TEXT CHECK (chat_type IN ('private', 'group')),
+                role TEXT CHECK (role IN ('user', 'assistant')),
+                content TEXT NOT NULL,
+                sender_name TEXT,
+                qq_message_id BIGINT,
+                embedding VECTOR({dimension}),
+                token_count INTEGER,
+                score INTEGER,
+                expires_at TIMESTAMP,
+                created_at TIMESTAMP DEFAULT NOW(),
+                created_date DATE GENERATED ALWAYS AS (created_at::date) STORED
+            )"#,
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_message_uuid ON dialogues (message_uuid)",
+            "CREATE INDEX IF NOT EXISTS idx_group_time ON dialogues (group_id, user_id, id DESC)
+             WHERE group_id IS NOT NULL",
+            "CREATE INDEX IF NOT EXISTS idx_private_time ON dialogues (user_id, id DESC)
+             WHERE group_id IS NULL",
+            "CREATE INDEX IF NOT EXISTS idx_chat_context ON dialogues
+             (chat_type, user_id, group_id, created_at DESC)",
+            "CREATE INDEX IF NOT EXISTS idx_created_at ON dialogues (created_at)",
+            "CREATE INDEX IF NOT EXISTS idx_expires_at ON dialogues (expires_at)
+             WHERE expires_at IS NOT NULL",
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "create_token_usage",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS token_usage (
+                id BIGSERIAL PRIMARY KEY,
+                user_id BIGINT NOT NULL,
+                group_id BIGINT,
+                model TEXT NOT NULL,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL,
+                total_tokens INTEGER NOT NULL,
+                created_at TIMESTAMPTZ DEFAULT NOW()
+            )"#,
+            "CREATE INDEX IF NOT EXISTS idx_usage_group_time ON token_usage (group_id, created_at)
+             WHERE group_id IS NOT NULL",
+            "CREATE INDEX IF NOT EXISTS idx_usage_user_time ON token_usage (user_id, created_at)",
+        ],
+    },
+    Migration {
+        version: 3,
+        name: "create_rag_meta",
+        statements: &[r#"CREATE TABLE IF NOT EXISTS rag_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                updated_at TIMESTAMPTZ DEFAULT NOW()
+            )"#],
+    },
+    Migration {
+        version: 4,
+        name: "index_missing_embeddings",
+        statements: &[
+            "CREATE INDEX IF NOT EXISTS idx_missing_embedding ON dialogues (id)
+             WHERE embedding IS NULL",
+        ],
+    },
+    Migration {
+        version: 5,
+        name: "add_search_vector",
+        statements: &[
+            "ALTER TABLE dialogues ADD COLUMN IF NOT EXISTS search_vector TSVECTOR",
+            "CREATE INDEX IF NOT EXISTS idx_search_vector ON dialogues USING gin (search_vector)",
+            "CREATE INDEX IF NOT EXISTS idx_missing_search_vector ON dialogues (id)
+             WHERE search_vector IS NULL",
+        ],
+    },
+];
+
+/// 返回尚未执行的迁移
+fn pending_migrations(applied: &[i32]) -> impl Iterator<Item = &'static Migration> + '_ {
+    MIGRATIONS.iter().filter(move |m| !applied.contains(&m.version))
+}
+
+/// 执行所有尚未执行的迁移，返回本次执行的版本号
+///
+/// 所有迁移在同一个事务中执行并锁住 `schema_migrations`，多个实例同时启动时不会重复执行
+///
+/// # 参数
+/// - `pool`: 数据库连接池
+/// - `dimension`: 嵌入向量维度（仅用于创建新表）
+pub async fn run_migrations(pool: &PgPool, dimension: usize) -> Result<Vec<i32>> {
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMPTZ DEFAULT NOW()
+        )"#,
+    )
+    .execute(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("LOCK TABLE schema_migrations IN EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+
+    let applied: Vec<i32> = sqlx::query_scalar("SELECT version FROM schema_migrations")
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut executed = Vec::new();
+    for migration in pending_migrations(&applied) {
+        log::info!("   - 执行迁移 {:03}_{}", migration.version, migration.name);
+        for sql in migration.render(dimension) {
+            sqlx::query(&sql).execute(&mut *tx).await.map_err(|e| {
+                anyhow::anyhow!("迁移 {:03}_{} 失败: {}", migration.version, migration.name, e)
+            })?;
+        }
+        sqlx::query("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut *tx)
+            .await?;
+        executed.push(migration.version);
+    }
+
+    tx.commit().await?;
+    Ok(executed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        assert!(!MIGRATIONS.is_empty());
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version, "{:?}", pair[1]);
+        }
+        assert!(MIGRATIONS.iter().all(|m| !m.statements.is_empty()));
+    }
+
+    #[test]
+    fn test_render_and_pending() {
+        let sql = MIGRATIONS[0].render(768).join("\n");
+        assert!(sql.contains("VECTOR(768)"));
+        assert!(!sql.contains(DIMENSION_PLACEHOLDER));
+
+        let pending: Vec<i32> = pending_migrations(&[1, 3]).map(|m| m.version).collect();
+        let expected: Vec<i32> = MIGRATIONS
+            .iter()
+            .map(|m| m.version)
+            .filter(|v| *v != 1 && *v != 3)
+            .collect();
+        assert_eq!(pending, expected);
+        assert_eq!(&pending[..2], &[2, 4]);
+    }
+}
@@ -1,36 +1,77 @@
 //! # Chatbot - 模块化聊天机器人库
 //!
 //! `chatbot` 是一个功能完整的聊天机器人库，集成了：
-//! - **LLM 集成**：支持 OpenAI 兼容的 API
+//! - **LLM 集成**：支持 OpenAI 兼容的 API 与 Anthropic Messages API
 //! - **记忆管理**：短期记忆和长期记忆（RAG）
 //! - **记忆评估**：智能评估对话价值，按需保存
 //! - **向量检索**：基于 PostgreSQL + pgvector 的语义检索
 //! - **MCP 支持**：Model Context Protocol 工具调用
+//! - **图片理解**：将图片交给视觉模型处理
 
 // 核心模块
+mod anthropic;
 mod chat;
+mod command;
 mod config;
+mod group_context;
+mod keyword;
 mod llm;
+mod maintenance;
 pub mod mcp;
 mod memory;
 mod memory_evaluation;
+mod memory_store;
+mod migrations;
 mod prompt_template;
 mod rag;
 mod rag_database;
+mod reload;
+mod secrets;
+mod summary;
+#[cfg(test)]
+mod test_support;
+mod tokenizer;
+mod usage;
+mod validation;
+mod vision;
 
 // 公开导出
+pub use anthropic::AnthropicClient;
 pub use chat::{ChatBot, ChatStats};
+pub use command::{execute_command, format_stats, help_text, AdminCommand};
 pub use config::{
-    load_config, save_config, Config, DbConfig, EmbeddingConfig, LlmConfig, McpConfig,
-    MemoryConfig, MemoryEvaluationConfig, PostgresConfig, RagConfig,
+    load_config, save_config, AdminConfig, BudgetConfig, ChatOverride, Config, DbConfig,
+    EmbeddingConfig, GroupContextConfig, HotReloadConfig, LlmConfig, LlmProvider, MaintenanceConfig,
+    McpConfig, MemoryConfig, MemoryEvaluationConfig, OverridesConfig, PersistenceConfig,
+    PersonaConfig, PostgresConfig, RagConfig, RankingConfig, ReasoningConfig, RerankConfig,
+    RetryConfig, SearchMode, SummaryConfig, TokenizerConfig, TokenizerEncoding, TriggerConfig,
+    VisionConfig,
+};
+pub use group_context::{GroupMessage, GroupTimeline};
+pub use keyword::keyword_terms;
+pub use llm::{
+    create_backend, CompletionResponse, FunctionCall, LlmBackend, LlmClient, LlmMessage,
+    LlmRequestParams, Reasoning, SentenceSplitter, ThinkTagFilter, TokenUsage, ToolCall,
 };
-pub use llm::{CompletionResponse, FunctionCall, LlmClient, LlmMessage, LlmRequestParams, ToolCall};
+pub use maintenance::{spawn_maintenance, CleanupReport};
 pub use mcp::{
     McpClient, McpConfigFile, McpContent, McpManager, McpServerConfig, McpTool, McpToolInputSchema,
     McpToolResult,
 };
 pub use memory_evaluation::{MemoryEvaluator, RetentionDuration};
-pub use rag::TemporalMemory;
+pub use memory_store::{ConversationSnapshot, JsonlStore, ShortTermStore};
+pub use migrations::{Migration, MIGRATIONS};
+pub use rag::{
+    mmr_select, rank_anchors, ranking_score, reciprocal_rank_fusion, RerankClient, TemporalMemory,
+};
+pub use rag_database::SearchHit;
+pub use reload::{spawn_config_watcher, SharedChatBot};
+pub use secrets::{expand_references, restore_references, Substitution};
+pub use summary::HistorySummarizer;
+pub use tokenizer::{create_token_counter, BpeCounter, HeuristicCounter, TokenCounter};
+pub use usage::{BudgetStatus, UsageTracker};
+pub use validation::{ConfigIssue, IssueLevel, ValidationReport};
+pub use vision::VisionProcessor;
 
 // 错误类型
 pub use anyhow::{Error, Result};
@@ -1,5 +1,8 @@
 use chrono::Local;
+use crate::chatbot::config::PersonaConfig;
+use crate::chatbot::group_context::GroupMessage;
 use crate::chatbot::rag::Dialogue;
+use crate::chatbot::tokenizer::TokenCounter;
 
 /// 提示词模板构建器
 pub struct PromptTemplate;
@@ -8,9 +11,11 @@ impl PromptTemplate {
     /// 构建完整的系统提示词
     /// 
     /// # 参数
+    /// - `persona`: 机器人人设（名字、自我介绍）
     /// - `character_prompt`: 角色性格设置（来自 config）
     /// - `memories`: RAG 检索到的长期记忆
     /// - `max_memory_tokens`: 记忆部分的最大 token 数
+    /// - `token_counter`: 用于统计记忆条目的 token 数
     /// 
     /// # 返回
     /// 完整的系统提示词，包含：
@@ -19,9 +24,11 @@ impl PromptTemplate {
     /// - 时间理解指引
     /// - 长期记忆（如果有）
     pub fn build_system_prompt(
+        persona: &PersonaConfig,
         character_prompt: &str,
         memories: Option<&[Dialogue]>,
         max_memory_tokens: usize,
+        token_counter: &dyn TokenCounter,
     ) -> String {
         let now = Local::now();
         let current_time = now.format("%Y-%m-%d %H:%M:%S 星期%w").to_string();
@@ -33,7 +40,7 @@ impl PromptTemplate {
         
         // 2. 角色性格设置
         prompt.push_str("# 角色设定，非常重要, 请务必牢记！！！\n");
-        prompt.push_str("下面扮演名为\"小诗\"的角色进行对话，你要时刻牢记自己的名字\n");
+        Self::push_persona(&mut prompt, persona);
         prompt.push_str(character_prompt);
         prompt.push_str("\n\n");
         
@@ -52,7 +59,9 @@ impl PromptTemplate {
                 
                 let mut total_tokens = 0;
                 for dialogue in memories {
-                    let tokens = dialogue.token_count.unwrap_or((dialogue.content.len() / 4) as i32) as usize;
+                    // 按格式化后的文本计数（包含时间戳、称呼等前缀）
+                    let formatted = Self::format_memory_item(dialogue);
+                    let tokens = token_counter.count(&formatted);
                     
                     // 检查是否超过 token 限制
                     if total_tokens + tokens > max_memory_tokens {
@@ -60,7 +69,6 @@ impl PromptTemplate {
                         break;
                     }
                     
-                    let formatted = Self::format_memory_item(dialogue);
                     prompt.push_str(&formatted);
                     prompt.push('\n');
                     
@@ -73,7 +81,7 @@ impl PromptTemplate {
         
         // 5. 对话指引
         prompt.push_str("# 对话指引\n");
-        prompt.push_str("* 你的名字叫\"小诗\"，你要时刻牢记自己的名字\n");
+        prompt.push_str(&format!("* 你的名字叫\"{}\"，你要时刻牢记自己的名字\n", persona.name));
         prompt.push_str("* 如果记忆中有相关信息，请自然地引用，但不要生硬地复述\n");
         prompt.push_str("* 如果用户问到之前聊过的内容，可以回忆并回答\n");
         prompt.push_str("* 如果记忆中的信息可能过时，请谨慎使用并适当提醒\n");
@@ -88,6 +96,27 @@ impl PromptTemplate {
         prompt
     }
     
+    /// 写入角色名字、别名和自我介绍
+    fn push_persona(prompt: &mut String, persona: &PersonaConfig) {
+        prompt.push_str(&format!(
+            "下面扮演名为\"{}\"的角色进行对话，你要时刻牢记自己的名字\n",
+            persona.name
+        ));
+        let aliases: Vec<&str> = persona
+            .aliases
+            .iter()
+            .map(|a| a.as_str())
+            .filter(|a| !a.is_empty())
+            .collect();
+        if !aliases.is_empty() {
+            prompt.push_str(&format!("大家也会叫你：{}\n", aliases.join("、")));
+        }
+        if !persona.description.is_empty() {
+            prompt.push_str(&persona.description);
+            prompt.push('\n');
+        }
+    }
+    
     /// 格式化单条记忆为文本
     fn format_memory_item(dialogue: &Dialogue) -> String {
         let local_time: chrono::DateTime<chrono::Local> = dialogue.created_at.into();
@@ -141,11 +170,42 @@ impl PromptTemplate {
         }
     }
     
+    /// 在系统提示词末尾追加早前对话的滚动摘要
+    ///
+    /// # 参数
+    /// - `prompt`: 已构建的系统提示词
+    /// - `summary`: 被挤出短期记忆的对话摘要
+    pub fn append_history_summary(prompt: &mut String, summary: &str) {
+        prompt.push_str("\n# 早前对话摘要\n");
+        prompt.push_str("以下是本次会话中较早对话的摘要，请结合它理解接下来的对话：\n");
+        prompt.push_str(summary);
+        prompt.push('\n');
+    }
+    
+    /// 在系统提示词末尾追加群里最近的发言（群聊共享上下文）
+    ///
+    /// # 参数
+    /// - `prompt`: 已构建的系统提示词
+    /// - `messages`: 群里最近未 @ 机器人的发言，按时间顺序
+    pub fn append_group_context(prompt: &mut String, messages: &[GroupMessage]) {
+        prompt.push_str("\n# 群聊最近消息\n");
+        prompt.push_str("以下是群里其他人最近的发言（没有直接对你说），用户提到\"他说的\"、\"刚才\"等内容时请参考：\n");
+        for msg in messages {
+            let time = chrono::DateTime::from_timestamp(msg.timestamp as i64, 0)
+                .map(|t| t.with_timezone(&Local).format("%H:%M").to_string())
+                .unwrap_or_default();
+            prompt.push_str(&format!(
+                "[{}] {}({}): {}\n",
+                time, msg.sender_name, msg.user_id, msg.content
+            ));
+        }
+    }
+    
     /// 构建简化的系统提示词（不包含长期记忆）
     /// 用于 RAG 未启用或检索失败的情况
-    pub fn build_simple_system_prompt(character_prompt: &str) -> String {
+    pub fn build_simple_system_prompt(persona: &PersonaConfig, character_prompt: &str) -> String {
         let mut prompt = String::new();
-        prompt.push_str("下面扮演名为\"小诗\"的角色进行对话，你要时刻牢记自己的名字\n");
+        Self::push_persona(&mut prompt, persona);
         prompt.push_str("接下来的对话回答请用纯文本，不要包含markdown等格式，也不要包含颜文字和emoji表情等其他非文本字符。\n\n");
         prompt.push_str(character_prompt);
         prompt
@@ -155,15 +215,60 @@ impl PromptTemplate {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chatbot::tokenizer::HeuristicCounter;
     use chrono::{Duration, Utc};
+
+    fn test_dialogue(content: &str) -> Dialogue {
+        Dialogue {
+            id: 0,
+            message_uuid: String::new(),
+            user_id: 10001,
+            group_id: None,
+            chat_type: "private".to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            sender_name: Some("测试".to_string()),
+            qq_message_id: None,
+            token_count: None,
+            score: None,
+            expires_at: None,
+            created_at: Utc::now(),
+            distance: None,
+        }
+    }
     
     #[test]
     fn test_build_simple_system_prompt() {
         let character = "你是一个友好的AI助手。";
-        let prompt = PromptTemplate::build_simple_system_prompt(character);
+        let persona = PersonaConfig {
+            name: "阿布".to_string(),
+            aliases: vec!["布布".to_string()],
+            description: "你是一只住在图书馆的猫。".to_string(),
+        };
+        let prompt = PromptTemplate::build_simple_system_prompt(&persona, character);
         
         assert!(prompt.contains("当前时间"));
         assert!(prompt.contains(character));
+        assert!(prompt.contains("名为\"阿布\""));
+        assert!(prompt.contains("布布"));
+        assert!(prompt.contains(&persona.description));
+        assert!(!prompt.contains("小诗"));
+    }
+    
+    #[test]
+    fn test_memory_token_limit() {
+        // 每条记忆约 33 个 token（20 个汉字 + 时间戳等前缀）
+        let memories: Vec<Dialogue> = (0..3).map(|_| test_dialogue(&"记".repeat(20))).collect();
+        let prompt = PromptTemplate::build_system_prompt(
+            &PersonaConfig::default(),
+            "角色设定",
+            Some(&memories),
+            70,
+            &HeuristicCounter,
+        );
+        
+        assert_eq!(prompt.matches("user(测试)").count(), 2);
+        assert!(prompt.contains("更多记忆因长度限制已省略"));
     }
     
     #[test]
@@ -2,9 +2,16 @@ use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use ndarray::Array1;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::chatbot::config::{EmbeddingConfig, PostgresConfig, RagConfig};
-use crate::chatbot::rag_database::RagDatabase;
+use crate::chatbot::config::{
+    EmbeddingConfig, PostgresConfig, RagConfig, RankingConfig, RerankConfig, SearchMode,
+};
+use crate::chatbot::llm::TokenUsage;
+use crate::chatbot::rag_database::{RagDatabase, SearchHit};
+use crate::chatbot::tokenizer::TokenCounter;
 
 /// 对话消息
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +29,9 @@ pub struct Dialogue {
     pub score: Option<i32>,      // 记忆评分（0-100）
     pub expires_at: Option<DateTime<Utc>>,  // 过期时间
     pub created_at: DateTime<Utc>,
+    /// 检索锚点与查询向量的余弦距离（上下文窗口中的非锚点对话为 None）
+    #[serde(default)]
+    pub distance: Option<f32>,
 }
 
 /// Embedding API 响应
@@ -42,53 +52,32 @@ struct EmbeddingRequest {
     input: String,
 }
 
-/// 时间感知的 RAG 记忆系统
-pub struct TemporalMemory {
-    database: RagDatabase,
-    embedding_config: EmbeddingConfig,
-    rag_config: RagConfig,
+/// Embedding API 客户端
+pub struct EmbeddingClient {
+    config: EmbeddingConfig,
     http_client: reqwest::Client,
 }
 
-impl TemporalMemory {
-    /// 创建新的 TemporalMemory 实例
-    pub async fn new(
-        postgres_config: PostgresConfig,
-        embedding_config: EmbeddingConfig,
-        rag_config: RagConfig,
-    ) -> Result<Self> {
-        // 创建数据库连接
-        let database = RagDatabase::new(postgres_config).await?;
-
-        Ok(Self {
-            database,
-            embedding_config,
-            rag_config,
+impl EmbeddingClient {
+    /// 创建新的 Embedding 客户端
+    pub fn new(config: EmbeddingConfig) -> Self {
+        Self {
+            config,
             http_client: reqwest::Client::new(),
-        })
-    }
-
-    /// 生成会话标识
-    /// 私聊："{user_id}"
-    /// 群聊："{group_id}:{user_id}"
-    pub fn generate_session_key(user_id: i64, group_id: Option<i64>) -> String {
-        match group_id {
-            Some(gid) => format!("{}:{}", gid, user_id),
-            None => user_id.to_string(),
         }
     }
 
     /// 调用 Embedding API 获取向量
-    async fn get_embedding(&self, text: &str) -> Result<Vec<f32>> {
+    pub async fn embed(&self, text: &str) -> Result<Vec<f32>> {
         let request = EmbeddingRequest {
-            model: self.embedding_config.model.clone(),
+            model: self.config.model.clone(),
             input: text.to_string(),
         };
 
         let response = self
             .http_client
-            .post(&self.embedding_config.url)
-            .header("Authorization", format!("Bearer {}", self.embedding_config.apikey))
+            .post(&self.config.url)
+            .header("Authorization", format!("Bearer {}", self.config.apikey))
             .header("Content-Type", "application/json")
             .json(&request)
             .send()
@@ -101,12 +90,365 @@ impl TemporalMemory {
         }
 
         let embedding_response: EmbeddingResponse = response.json().await?;
-        
-        if embedding_response.data.is_empty() {
+
+        let Some(data) = embedding_response.data.into_iter().next() else {
             return Err(anyhow!("Embedding API 返回空数据"));
+        };
+
+        if let Some(dimension) = self.config.dimension {
+            if data.embedding.len() != dimension {
+                return Err(anyhow!(
+                    "Embedding API 返回 {} 维向量，与配置的 {} 维不一致",
+                    data.embedding.len(),
+                    dimension
+                ));
+            }
+        }
+
+        Ok(data.embedding)
+    }
+
+    /// 向量维度：优先使用配置值，未配置时调用一次嵌入接口探测
+    pub async fn dimension(&self) -> Result<usize> {
+        if let Some(dimension) = self.config.dimension {
+            return Ok(dimension);
+        }
+        let dimension = self.embed("维度探测").await?.len();
+        if dimension == 0 {
+            return Err(anyhow!("Embedding API 返回空向量"));
+        }
+        log::info!("📐 探测到嵌入模型 {} 的向量维度: {}", self.config.model, dimension);
+        Ok(dimension)
+    }
+}
+
+/// Rerank API 请求（Jina / 硅基流动 / Cohere 格式）
+#[derive(Debug, Serialize)]
+struct RerankRequest<'a> {
+    model: &'a str,
+    query: &'a str,
+    documents: Vec<&'a str>,
+    top_n: usize,
+    return_documents: bool,
+}
+
+/// Rerank API 响应
+#[derive(Debug, Deserialize)]
+struct RerankResponse {
+    results: Vec<RerankResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RerankResult {
+    index: usize,
+    relevance_score: f32,
+}
+
+/// Rerank API 客户端（交叉编码器重排序）
+pub struct RerankClient {
+    config: RerankConfig,
+    http_client: reqwest::Client,
+}
+
+impl RerankClient {
+    /// 创建新的 Rerank 客户端
+    pub fn new(config: RerankConfig) -> Self {
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout))
+            .build()
+            .unwrap_or_default();
+        Self {
+            config,
+            http_client,
         }
+    }
+
+    /// 交给重排序模型的候选数
+    pub fn candidates(&self) -> usize {
+        self.config.candidates
+    }
+
+    /// 对照查询为候选锚点打分，返回 (锚点, 相关度)，按相关度从高到低排列
+    pub async fn rerank(
+        &self,
+        query: &str,
+        hits: Vec<SearchHit>,
+    ) -> Result<Vec<(SearchHit, f32)>> {
+        if hits.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let request = RerankRequest {
+            model: &self.config.model,
+            query,
+            documents: hits.iter().map(|hit| hit.content.as_str()).collect(),
+            top_n: hits.len(),
+            return_documents: false,
+        };
+
+        let response = self
+            .http_client
+            .post(&self.config.url)
+            .header("Authorization", format!("Bearer {}", self.config.apikey))
+            .header("Content-Type", "application/json")
+            .json(&request)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await?;
+            return Err(anyhow!("Rerank API 错误 [{}]: {}", status, body));
+        }
+
+        let rerank_response: RerankResponse = response.json().await?;
+        let mut hits: Vec<Option<SearchHit>> = hits.into_iter().map(Some).collect();
+        let mut ranked = Vec::with_capacity(hits.len());
+        for result in rerank_response.results {
+            let hit = hits
+                .get_mut(result.index)
+                .and_then(Option::take)
+                .ok_or_else(|| anyhow!("Rerank API 返回了无效的文档序号 {}", result.index))?;
+            ranked.push((hit, result.relevance_score));
+        }
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(ranked)
+    }
+
+    /// 重排序并按排序权重取前 `top_n` 个，失败时沿用候选的原有顺序和排序分
+    ///
+    /// # 参数
+    /// - `query`: 查询文本
+    /// - `candidates`: 按检索排序排列的候选锚点及其排序分
+    /// - `ranking`: 排序权重（相关度使用重排序得分）
+    /// - `top_n`: 返回的锚点数
+    /// - `now`: 当前时间，用于计算新近度
+    pub async fn rerank_top(
+        &self,
+        query: &str,
+        candidates: Vec<(SearchHit, f32)>,
+        ranking: &RankingConfig,
+        top_n: usize,
+        now: DateTime<Utc>,
+    ) -> Vec<(SearchHit, f32)> {
+        let hits = candidates.iter().map(|(hit, _)| hit.clone()).collect();
+        match self.rerank(query, hits).await {
+            Ok(scored) => rank_anchors(scored, ranking, top_n, now),
+            Err(e) => {
+                log::warn!("⚠️  记忆重排序失败，沿用检索排序: {}", e);
+                candidates.into_iter().take(top_n).collect()
+            }
+        }
+    }
+}
+
+/// 每批重新生成向量的对话条数
+const REEMBED_BATCH_SIZE: usize = 50;
+
+/// 连续这么多条对话生成向量失败时认为嵌入接口不可用，停止本次重新生成
+const REEMBED_MAX_CONSECUTIVE_FAILURES: usize = 5;
+
+/// 最近一次启动的后台补建任务编号，热重载启动新任务后旧任务在下一批前退出
+static BACKFILL_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// 是否有后台补建任务正在运行，同一时间只运行一个
+static BACKFILL_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// 当前任务是否已被更新的任务取代
+fn backfill_superseded(generation: u64) -> bool {
+    BACKFILL_GENERATION.load(Ordering::SeqCst) != generation
+}
+
+/// 任务结束（包括 panic）时清除运行标记
+struct BackfillRunning;
+
+impl Drop for BackfillRunning {
+    fn drop(&mut self) {
+        BACKFILL_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// 每路检索的候选数是最终锚点数的倍数，为阈值过滤、融合和重新排序留出余量
+const CANDIDATE_FACTOR: usize = 4;
+
+/// 倒数排名融合（Reciprocal Rank Fusion）
+///
+/// 每个结果的得分为它在各路排名中 `1 / (k + rank)` 之和（rank 从 1 开始），
+/// 只依赖排名，不需要把向量距离和 ts_rank 换算到同一尺度
+///
+/// # 参数
+/// - `rankings`: 各路检索结果，每路按相关度从高到低排列
+/// - `k`: 平滑常数，越大排名靠后的结果权重越接近排名靠前的
+///
+/// # 返回
+/// 按融合得分从高到低排列的结果，得分除以最高可能得分（每路都排第一）归一化到 0-1
+pub fn reciprocal_rank_fusion(rankings: &[Vec<SearchHit>], k: u32) -> Vec<(SearchHit, f32)> {
+    let mut fused: Vec<(SearchHit, f64)> = Vec::new();
+    for ranking in rankings {
+        for (rank, hit) in ranking.iter().enumerate() {
+            let score = 1.0 / (k as f64 + rank as f64 + 1.0);
+            match fused.iter_mut().find(|(existing, _)| existing.id == hit.id) {
+                Some((existing, total)) => {
+                    *total += score;
+                    // 关键词检索可能没有距离（对话还没有向量），以向量检索的为准
+                    if existing.distance.is_none() {
+                        existing.distance = hit.distance;
+                    }
+                }
+                None => fused.push((hit.clone(), score)),
+            }
+        }
+    }
+    // 稳定排序：得分相同时保留先出现（向量检索）的顺序
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let max_score = rankings.len().max(1) as f64 / (k as f64 + 1.0);
+    fused
+        .into_iter()
+        .map(|(hit, score)| (hit, (score / max_score) as f32))
+        .collect()
+}
+
+/// 计算锚点的排序分（见 [`RankingConfig`]）
+///
+/// # 参数
+/// - `relevance`: 相关度（0-1）
+/// - `hit`: 检索命中的锚点
+/// - `ranking`: 排序权重
+/// - `now`: 当前时间，用于计算新近度
+pub fn ranking_score(
+    relevance: f32,
+    hit: &SearchHit,
+    ranking: &RankingConfig,
+    now: DateTime<Utc>,
+) -> f32 {
+    let age_days = (now - hit.created_at).num_seconds().max(0) as f32 / 86400.0;
+    let recency = if ranking.recency_half_life_days > 0.0 {
+        0.5f32.powf(age_days / ranking.recency_half_life_days)
+    } else {
+        0.0
+    };
+    // 未评分的记忆（没有启用记忆评估）按中间值计
+    let score = hit.score.map(|s| s.clamp(0, 100) as f32 / 100.0).unwrap_or(0.5);
+
+    relevance * ranking.similarity_weight
+        + recency * ranking.recency_weight
+        + score * ranking.score_weight
+}
+
+/// 按排序分对候选锚点重新排序，返回前 `top_n` 个及其排序分
+pub fn rank_anchors(
+    candidates: Vec<(SearchHit, f32)>,
+    ranking: &RankingConfig,
+    top_n: usize,
+    now: DateTime<Utc>,
+) -> Vec<(SearchHit, f32)> {
+    let mut scored: Vec<(SearchHit, f32)> = candidates
+        .into_iter()
+        .map(|(hit, relevance)| {
+            let score = ranking_score(relevance, &hit, ranking, now);
+            (hit, score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.truncate(top_n);
+    scored
+}
+
+/// 最大边际相关性（Maximal Marginal Relevance）选择
+///
+/// 每次选出 `lambda × 相关度 - (1 - lambda) × 与已选锚点的最大余弦相似度` 最高的候选，
+/// 避免几乎相同的对话占满所有锚点。相关度先除以最高分归一化到 0-1；没有向量的候选不计冗余
+///
+/// # 参数
+/// - `candidates`: 候选锚点及其排序分
+/// - `lambda`: 1 表示只看相关度，0 表示只看多样性
+/// - `top_n`: 选出的锚点数
+pub fn mmr_select(candidates: Vec<(SearchHit, f32)>, lambda: f32, top_n: usize) -> Vec<SearchHit> {
+    let max_score = candidates.iter().map(|(_, score)| *score).fold(0.0f32, f32::max);
+    let mut remaining: Vec<(SearchHit, f32)> = candidates
+        .into_iter()
+        .map(|(hit, score)| {
+            let relevance = if max_score > 0.0 { score / max_score } else { 0.0 };
+            (hit, relevance)
+        })
+        .collect();
+
+    let mut selected: Vec<SearchHit> = Vec::new();
+    while selected.len() < top_n && !remaining.is_empty() {
+        let mmr = |(hit, relevance): &(SearchHit, f32)| {
+            let redundancy = hit.embedding.as_ref().map_or(0.0, |embedding| {
+                selected
+                    .iter()
+                    .filter_map(|chosen| chosen.embedding.as_ref())
+                    .map(|other| TemporalMemory::cosine_similarity(embedding, other))
+                    .fold(0.0f32, f32::max)
+            });
+            lambda * relevance - (1.0 - lambda) * redundancy
+        };
+        // 分数相同时取排在前面的候选
+        let mut best = 0;
+        let mut best_score = f32::NEG_INFINITY;
+        for (i, candidate) in remaining.iter().enumerate() {
+            let score = mmr(candidate);
+            if score > best_score {
+                best = i;
+                best_score = score;
+            }
+        }
+        selected.push(remaining.remove(best).0);
+    }
+    selected
+}
+
+/// 时间感知的 RAG 记忆系统
+pub struct TemporalMemory {
+    database: RagDatabase,
+    embedding: EmbeddingClient,
+    reranker: Option<RerankClient>,
+    rag_config: RagConfig,
+    token_counter: Arc<dyn TokenCounter>,
+}
+
+impl TemporalMemory {
+    /// 创建新的 TemporalMemory 实例
+    pub async fn new(
+        postgres_config: PostgresConfig,
+        mut embedding_config: EmbeddingConfig,
+        rag_config: RagConfig,
+        token_counter: Arc<dyn TokenCounter>,
+    ) -> Result<Self> {
+        // 确定向量维度，之后每次嵌入都校验返回的维度
+        let dimension = EmbeddingClient::new(embedding_config.clone()).dimension().await?;
+        embedding_config.dimension = Some(dimension);
+
+        // 创建数据库连接
+        let database = RagDatabase::new(postgres_config, &embedding_config, dimension).await?;
 
-        Ok(embedding_response.data[0].embedding.clone())
+        let reranker = if rag_config.rerank.enabled {
+            log::info!("✅ 记忆重排序已启用，模型: {}", rag_config.rerank.model);
+            Some(RerankClient::new(rag_config.rerank.clone()))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            database,
+            embedding: EmbeddingClient::new(embedding_config),
+            reranker,
+            rag_config,
+            token_counter,
+        })
+    }
+
+    /// 生成会话标识
+    /// 私聊："{user_id}"
+    /// 群聊："{group_id}:{user_id}"
+    pub fn generate_session_key(user_id: i64, group_id: Option<i64>) -> String {
+        match group_id {
+            Some(gid) => format!("{}:{}", gid, user_id),
+            None => user_id.to_string(),
+        }
     }
 
     /// 存储对话到长期记忆
@@ -131,10 +473,9 @@ impl TemporalMemory {
         let chat_type = if group_id.is_some() { "group" } else { "private" };
 
         // 生成向量
-        let embedding = self.get_embedding(content).await?;
+        let embedding = self.embedding.embed(content).await?;
 
-        // 简单的 token 计数
-        let token_count = (content.len() / 4) as i32;
+        let token_count = self.token_counter.count(content) as i32;
 
         // 如果没有指定过期时间，默认一周后过期
         let expires_at = expires_at.or_else(|| Some(chrono::Utc::now() + chrono::Duration::weeks(1)));
@@ -159,7 +500,6 @@ impl TemporalMemory {
     }
 
     /// 计算余弦相似度
-    #[allow(dead_code)]
     fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
         let arr_a = Array1::from_vec(a.to_vec());
         let arr_b = Array1::from_vec(b.to_vec());
@@ -189,20 +529,85 @@ impl TemporalMemory {
         let window_size = window_size.unwrap_or(self.rag_config.window_size);
 
         // 生成查询向量
-        let query_embedding = self.get_embedding(query).await?;
+        let query_embedding = self.embedding.embed(query).await?;
 
-        // 向量检索锚点
-        let anchor_results = self
+        // 检索候选锚点，向量检索的结果低于相似度阈值的丢弃
+        let mut candidates = top_n * CANDIDATE_FACTOR;
+        if let Some(reranker) = &self.reranker {
+            candidates = candidates.max(reranker.candidates());
+        }
+        let min_similarity = self.rag_config.min_similarity;
+        let mut by_vector = self
             .database
-            .search_by_embedding(user_id, group_id, &query_embedding, exclude_message_ids, top_n)
+            .search_by_embedding(
+                user_id,
+                group_id,
+                &query_embedding,
+                exclude_message_ids,
+                candidates,
+            )
             .await?;
+        // 默认 -1 表示不过滤（余弦相似度可能为负）
+        if min_similarity > -1.0 {
+            by_vector.retain(|hit| hit.similarity().is_some_and(|s| s >= min_similarity));
+        }
 
-        if anchor_results.is_empty() {
+        let scored = match self.rag_config.search_mode {
+            SearchMode::Vector => by_vector
+                .into_iter()
+                .map(|hit| {
+                    let relevance = hit.similarity().unwrap_or(0.0);
+                    (hit, relevance)
+                })
+                .collect(),
+            SearchMode::Hybrid => {
+                // 关键词命中的结果不受相似度阈值限制，名字、数字等的向量相似度往往不高
+                let by_keyword = self
+                    .database
+                    .search_by_keywords(
+                        user_id,
+                        group_id,
+                        query,
+                        &query_embedding,
+                        exclude_message_ids,
+                        candidates,
+                    )
+                    .await?;
+                reciprocal_rank_fusion(&[by_vector, by_keyword], self.rag_config.rrf_k)
+            }
+        };
+
+        // 启用重排序时先按检索排序取候选，再交给重排序模型；
+        // 启用 MMR 时保留全部候选，由 MMR 在相关度和多样性之间取舍
+        let ranking = &self.rag_config.ranking;
+        let now = Utc::now();
+        let keep = if self.rag_config.mmr_enabled { candidates } else { top_n };
+        let ranked = match &self.reranker {
+            Some(reranker) => {
+                let pool = rank_anchors(scored, ranking, reranker.candidates().max(top_n), now);
+                reranker.rerank_top(query, pool, ranking, keep, now).await
+            }
+            None => rank_anchors(scored, ranking, keep, now),
+        };
+        let anchors = if self.rag_config.mmr_enabled {
+            mmr_select(ranked, self.rag_config.mmr_lambda, top_n)
+        } else {
+            ranked.into_iter().map(|(hit, _)| hit).collect::<Vec<_>>()
+        };
+        if anchors.is_empty() {
             return Ok(Vec::new());
         }
+        for anchor in &anchors {
+            log::debug!(
+                "🔍 锚点 #{} 余弦距离 {:?} 评分 {:?}",
+                anchor.id,
+                anchor.distance,
+                anchor.score
+            );
+        }
 
         // 收集锚点ID
-        let anchor_ids: Vec<i32> = anchor_results.iter().map(|(id, _)| *id).collect();
+        let anchor_ids: Vec<i32> = anchors.iter().map(|hit| hit.id).collect();
 
         // 为每个锚点扩展上下文窗口
         let mut all_ids: Vec<i32> = Vec::new();
@@ -222,8 +627,15 @@ impl TemporalMemory {
         // 去重并排序
         all_ids.sort();
 
-        // 获取所有对话详情
-        self.database.get_dialogues_by_ids(&all_ids).await
+        // 获取所有对话详情，并标注锚点的余弦距离
+        let mut dialogues = self.database.get_dialogues_by_ids(&all_ids).await?;
+        for dialogue in &mut dialogues {
+            dialogue.distance = anchors
+                .iter()
+                .find(|hit| hit.id == dialogue.id)
+                .and_then(|hit| hit.distance);
+        }
+        Ok(dialogues)
     }
 
     /// 批量插入历史对话（用于初始化）
@@ -232,7 +644,7 @@ impl TemporalMemory {
 
         for dialogue in dialogues {
             // 生成向量
-            let embedding = self.get_embedding(&dialogue.content).await?;
+            let embedding = self.embedding.embed(&dialogue.content).await?;
 
             items.push((
                 dialogue.message_uuid,
@@ -266,11 +678,148 @@ impl TemporalMemory {
     pub async fn cleanup_expired_memories(&self) -> Result<u64> {
         self.database.cleanup_expired_memories().await
     }
+
+    /// 按 `cleanup_days` 删除过旧的非永久记忆，`cleanup_days` 为 0 时不执行
+    pub async fn cleanup_old_memories(&self) -> Result<u64> {
+        if self.rag_config.cleanup_days == 0 {
+            return Ok(0);
+        }
+        self.database
+            .cleanup_old_memories(self.rag_config.cleanup_days)
+            .await
+    }
+
+    /// 维护向量索引
+    pub async fn maintain_vector_indexes(&self) -> Result<()> {
+        self.database.maintain_vector_indexes().await
+    }
+
+    /// 为早于关键词检索的旧对话补建检索词，返回处理的条数
+    pub async fn backfill_keywords(&self) -> Result<usize> {
+        self.database.backfill_search_vectors(REEMBED_BATCH_SIZE).await
+    }
+
+    /// 在后台补建检索词并为没有向量的对话重新生成向量，不阻塞启动
+    ///
+    /// 同一时间只运行一个任务：热重载重建长期记忆后，新任务等旧任务在当前批次结束后退出再开始
+    pub fn spawn_backfill(self: &Arc<Self>) {
+        let generation = BACKFILL_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+        let memory = self.clone();
+        tokio::spawn(async move {
+            while BACKFILL_RUNNING
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                if backfill_superseded(generation) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            let _running = BackfillRunning;
+
+            if !backfill_superseded(generation) {
+                match memory.backfill_keywords().await {
+                    Ok(0) => {}
+                    Ok(count) => log::info!("✅ 已为 {} 条记忆补建检索词", count),
+                    Err(e) => log::warn!("⚠️  补建检索词失败，下次启动时继续: {}", e),
+                }
+                match memory.reembed_missing(generation).await {
+                    Ok(0) => {}
+                    Ok(count) => log::info!("✅ {} 条记忆的向量已重新生成", count),
+                    Err(e) => log::warn!("⚠️  重新生成向量失败，下次启动时继续: {}", e),
+                }
+            }
+        });
+    }
+
+    /// 为没有向量的对话重新生成向量（嵌入模型变更后），返回成功生成的条数
+    ///
+    /// 单条失败（如内容超过嵌入模型的输入长度）时跳过继续处理后面的对话，下次启动时再重试；
+    /// 连续多条失败时认为接口不可用，停止本次处理。处理完成后补建向量索引
+    async fn reembed_missing(&self, generation: u64) -> Result<usize> {
+        let mut total = 0;
+        let mut skipped = 0;
+        let mut consecutive_failures = 0;
+        let mut after_id = 0;
+        loop {
+            if backfill_superseded(generation) {
+                log::info!("🔁 长期记忆已重新加载，停止旧的向量重新生成任务");
+                break;
+            }
+
+            let batch = self
+                .database
+                .dialogues_missing_embedding(after_id, REEMBED_BATCH_SIZE)
+                .await?;
+            let Some((last_id, _)) = batch.last() else {
+                break;
+            };
+            after_id = *last_id;
+
+            for (id, content) in &batch {
+                match self.embedding.embed(content).await {
+                    Ok(embedding) => {
+                        self.database.update_embedding(*id, &embedding).await?;
+                        total += 1;
+                        consecutive_failures = 0;
+                    }
+                    Err(e) => {
+                        log::warn!("⚠️  对话 {} 的向量生成失败，已跳过: {}", id, e);
+                        skipped += 1;
+                        consecutive_failures += 1;
+                        if consecutive_failures >= REEMBED_MAX_CONSECUTIVE_FAILURES {
+                            return Err(anyhow!(
+                                "连续 {} 条对话生成向量失败，嵌入接口可能不可用",
+                                consecutive_failures
+                            ));
+                        }
+                    }
+                }
+            }
+            log::info!("🔁 已重新生成 {} 条记忆的向量（跳过 {} 条）", total, skipped);
+        }
+
+        if total > 0 {
+            self.database.maintain_vector_indexes().await?;
+        }
+        Ok(total)
+    }
+
+    /// 记录一次对话的 token 用量
+    pub async fn record_token_usage(
+        &self,
+        user_id: i64,
+        group_id: Option<i64>,
+        model: &str,
+        usage: &TokenUsage,
+    ) -> Result<()> {
+        self.database
+            .insert_token_usage(
+                user_id,
+                group_id,
+                model,
+                usage.prompt_tokens as i32,
+                usage.completion_tokens as i32,
+                usage.total_tokens as i32,
+            )
+            .await
+    }
+
+    /// 统计 token 用量：传入 group_id 时按群统计，否则按用户统计
+    pub async fn sum_token_usage(
+        &self,
+        user_id: i64,
+        group_id: Option<i64>,
+        since: DateTime<Utc>,
+    ) -> Result<u64> {
+        self.database.sum_token_usage(user_id, group_id, since).await
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chatbot::test_support::{mock_embedding, MockLlmServer};
 
     #[test]
     fn test_generate_session_key() {
@@ -291,5 +840,183 @@ mod tests {
         let d = vec![0.0, 1.0, 0.0];
         assert!((TemporalMemory::cosine_similarity(&c, &d) - 0.0).abs() < 0.001);
     }
-}
 
+    fn test_hit(id: i32, distance: Option<f32>, score: Option<i32>, days_ago: i64) -> SearchHit {
+        SearchHit {
+            id,
+            message_uuid: format!("uuid-{}", id),
+            content: format!("对话 {}", id),
+            embedding: None,
+            distance,
+            score,
+            created_at: Utc::now() - chrono::Duration::days(days_ago),
+        }
+    }
+
+    #[test]
+    fn test_reciprocal_rank_fusion() {
+        let item = |id: i32| test_hit(id, Some(0.2), None, 0);
+        let by_vector = vec![item(1), item(2), item(3)];
+        let mut only_keyword = item(4);
+        only_keyword.distance = None;
+        let by_keyword = vec![item(3), only_keyword];
+
+        // 3 在两路中都出现，排在最前；2 和 4 都排第二，得分相同时向量检索的结果在前
+        let fused = reciprocal_rank_fusion(&[by_vector.clone(), by_keyword], 60);
+        let ids: Vec<i32> = fused.iter().map(|(hit, _)| hit.id).collect();
+        assert_eq!(ids, vec![3, 1, 2, 4]);
+        assert!(fused[0].1 < 1.0 && fused[0].1 > fused[1].1);
+        assert_eq!(fused[3].0.distance, None);
+
+        // 两路都排第一时归一化得分为 1
+        let fused = reciprocal_rank_fusion(&[vec![item(1)], vec![item(1)]], 60);
+        assert!((fused[0].1 - 1.0).abs() < 1e-6);
+
+        // 只有一路结果时保持原排名
+        let fused = reciprocal_rank_fusion(&[by_vector.clone(), Vec::new()], 60);
+        let hits: Vec<SearchHit> = fused.into_iter().map(|(hit, _)| hit).collect();
+        assert_eq!(hits, by_vector);
+    }
+
+    #[test]
+    fn test_rank_anchors() {
+        let now = Utc::now();
+        let old_relevant = test_hit(1, Some(0.1), Some(30), 90);
+        let recent_important = test_hit(2, Some(0.3), Some(95), 1);
+        let candidates = || {
+            vec![
+                (old_relevant.clone(), old_relevant.similarity().unwrap()),
+                (recent_important.clone(), recent_important.similarity().unwrap()),
+            ]
+        };
+
+        // 默认只按相似度排序
+        let ranked = rank_anchors(candidates(), &RankingConfig::default(), 2, now);
+        assert_eq!(ranked.iter().map(|(h, _)| h.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert!((ranked[0].1 - 0.9).abs() < 1e-6);
+
+        // 加入新近度和评分后，较新且重要的记忆排在前面
+        let ranking = RankingConfig {
+            recency_weight: 0.3,
+            score_weight: 0.3,
+            ..RankingConfig::default()
+        };
+        let ranked = rank_anchors(candidates(), &ranking, 1, now);
+        assert_eq!(ranked.iter().map(|(h, _)| h.id).collect::<Vec<_>>(), vec![2]);
+
+        // 半衰期处新近度为 0.5，未评分按 0.5 计
+        let ranking = RankingConfig {
+            similarity_weight: 0.0,
+            recency_weight: 1.0,
+            score_weight: 1.0,
+            recency_half_life_days: 30.0,
+        };
+        let hit = test_hit(3, None, None, 0);
+        let later = hit.created_at + chrono::Duration::days(30);
+        assert!((ranking_score(0.0, &hit, &ranking, later) - 1.0).abs() < 1e-3);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        let config = RerankConfig {
+            enabled: true,
+            model: "mock-reranker".to_string(),
+            url: server.rerank_url(),
+            apikey: "test-key".to_string(),
+            ..RerankConfig::default()
+        };
+        let mut candidates = vec![
+            (test_hit(1, Some(0.1), None, 0), 0.9),
+            (test_hit(2, Some(0.2), None, 0), 0.8),
+            (test_hit(3, Some(0.3), None, 0), 0.7),
+        ];
+        candidates[0].0.content = "今天天气不错".to_string();
+        candidates[1].0.content = "我家的猫叫橘子".to_string();
+        candidates[2].0.content = "猫喜欢吃鱼".to_string();
+
+        // 重排序模型认为 2 最相关
+        let reranker = RerankClient::new(config.clone());
+        let ranking = RankingConfig::default();
+        let top = reranker
+            .rerank_top("猫叫什么", candidates.clone(), &ranking, 2, Utc::now())
+            .await;
+        assert_eq!(top.iter().map(|(h, _)| h.id).collect::<Vec<_>>(), vec![2, 3]);
+
+        let request = &server.requests()[0].body;
+        assert_eq!(request["model"], "mock-reranker");
+        assert_eq!(request["query"], "猫叫什么");
+        assert_eq!(request["documents"][1], "我家的猫叫橘子");
+
+        // 接口出错时沿用检索排序
+        let reranker = RerankClient::new(RerankConfig {
+            url: format!("{}/unknown", server.url()),
+            ..config
+        });
+        let top = reranker
+            .rerank_top("猫叫什么", candidates, &ranking, 2, Utc::now())
+            .await;
+        assert_eq!(top.iter().map(|(h, _)| h.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(top[0].1, 0.9);
+    }
+
+    #[test]
+    fn test_mmr_select() {
+        let with_embedding = |id: i32, embedding: Vec<f32>| {
+            let mut hit = test_hit(id, Some(0.1), None, 0);
+            hit.embedding = Some(embedding);
+            hit
+        };
+        // 1 和 2 几乎相同，3 略不相关但内容不同
+        let candidates = vec![
+            (with_embedding(1, vec![1.0, 0.0]), 0.95),
+            (with_embedding(2, vec![0.99, 0.01]), 0.94),
+            (with_embedding(3, vec![0.0, 1.0]), 0.80),
+        ];
+
+        let ids = |hits: Vec<SearchHit>| hits.iter().map(|h| h.id).collect::<Vec<_>>();
+        assert_eq!(ids(mmr_select(candidates.clone(), 0.5, 2)), vec![1, 3]);
+        // lambda 为 1 时只看相关度
+        assert_eq!(ids(mmr_select(candidates.clone(), 1.0, 2)), vec![1, 2]);
+        assert_eq!(ids(mmr_select(candidates, 0.5, 5)), vec![1, 3, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_embedding_client_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.set_embedding_dim(16);
+
+        let client = EmbeddingClient::new(EmbeddingConfig {
+            model: "mock-embedding".to_string(),
+            url: server.embeddings_url(),
+            apikey: "test-key".to_string(),
+            dimension: None,
+            reembed_on_change: false,
+        });
+        assert_eq!(client.dimension().await.unwrap(), 16);
+
+        let a = client.embed("我喜欢猫").await.unwrap();
+        let b = client.embed("我喜欢猫").await.unwrap();
+        let c = client.embed("明天要考试").await.unwrap();
+        assert_eq!(a, mock_embedding("我喜欢猫", 16));
+        assert!((TemporalMemory::cosine_similarity(&a, &b) - 1.0).abs() < 0.001);
+        assert!(TemporalMemory::cosine_similarity(&a, &c) < 0.999);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[1].body["model"], "mock-embedding");
+        assert_eq!(requests[1].body["input"], "我喜欢猫");
+
+        // 返回的维度与配置不一致时报错
+        let client = EmbeddingClient::new(EmbeddingConfig {
+            model: "mock-embedding".to_string(),
+            url: server.embeddings_url(),
+            apikey: "test-key".to_string(),
+            dimension: Some(1024),
+            reembed_on_change: false,
+        });
+        assert_eq!(client.dimension().await.unwrap(), 1024);
+        let err = client.embed("我喜欢猫").await.unwrap_err().to_string();
+        assert!(err.contains("1024"), "{}", err);
+    }
+}
@@ -6,9 +6,62 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use pgvector::Vector;
 
-use crate::chatbot::config::{PostgresConfig, VectorIndexConfig};
+use crate::chatbot::config::{EmbeddingConfig, PostgresConfig, VectorIndexConfig};
+use crate::chatbot::keyword::{keyword_terms, tsquery_text};
+use crate::chatbot::migrations::run_migrations;
 use crate::chatbot::rag::Dialogue;
 
+/// rag_meta 中记录嵌入模型名称的键
+const META_EMBEDDING_MODEL: &str = "embedding_model";
+
+/// rag_meta 中记录向量维度的键
+const META_EMBEDDING_DIMENSION: &str = "embedding_dimension";
+
+/// 检索命中的锚点
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub id: i32,
+    pub message_uuid: String,
+    pub content: String,
+    /// 对话的嵌入向量，用于 MMR 计算锚点之间的相似度
+    pub embedding: Option<Vec<f32>>,
+    /// 与查询向量的余弦距离（0 表示方向相同），对话还没有向量时为 None
+    pub distance: Option<f32>,
+    /// 记忆评分（0-100）
+    pub score: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl SearchHit {
+    /// 余弦相似度（1 - 余弦距离）
+    pub fn similarity(&self) -> Option<f32> {
+        self.distance.map(|d| 1.0 - d)
+    }
+
+    fn from_row(row: &sqlx::postgres::PgRow) -> Self {
+        Self {
+            id: row.get("id"),
+            message_uuid: row.get("message_uuid"),
+            content: row.get("content"),
+            embedding: row.get::<Option<Vector>, _>("embedding").map(|v| v.to_vec()),
+            distance: row.get("distance"),
+            score: row.try_get("score").ok().flatten(),
+            created_at: read_timestamp(row, "created_at"),
+        }
+    }
+}
+
+/// 读取 TIMESTAMP 列（不带时区，按 UTC 解释）或 TIMESTAMPTZ 列
+fn read_timestamp(row: &sqlx::postgres::PgRow, column: &str) -> DateTime<Utc> {
+    match row.try_get(column) {
+        Ok(val) => val,
+        Err(_) => {
+            let naive: NaiveDateTime = row.get(column);
+            DateTime::from_naive_utc_and_offset(naive, Utc)
+        }
+    }
+}
+
 /// RAG 数据库操作类
 pub struct RagDatabase {
     pool: PgPool,
@@ -17,8 +70,17 @@ pub struct RagDatabase {
 }
 
 impl RagDatabase {
-    /// 创建新的数据库连接
-    pub async fn new(postgres_config: PostgresConfig) -> Result<Self> {
+    /// 创建新的数据库连接，执行结构迁移并检查嵌入模型是否变化
+    ///
+    /// # 参数
+    /// - `postgres_config`: 数据库配置
+    /// - `embedding_config`: 嵌入模型配置
+    /// - `dimension`: 嵌入向量维度（配置值或探测结果）
+    pub async fn new(
+        postgres_config: PostgresConfig,
+        embedding_config: &EmbeddingConfig,
+        dimension: usize,
+    ) -> Result<Self> {
         let connection_string = format!(
             "postgres://{}:{}@{}:{}/{}",
             postgres_config.username,
@@ -35,7 +97,8 @@ impl RagDatabase {
             .connect(&connection_string)
             .await?;
 
-        let indexes_created = Self::initialize_database(&pool, &vector_config).await?;
+        let indexes_created =
+            Self::initialize_database(&pool, &vector_config, embedding_config, dimension).await?;
 
         log::info!("✅ 数据库初始化完成");
         if !indexes_created {
@@ -49,7 +112,12 @@ impl RagDatabase {
         })
     }
 
-    async fn initialize_database(pool: &PgPool, vector_config: &VectorIndexConfig) -> Result<bool> {
+    async fn initialize_database(
+        pool: &PgPool,
+        vector_config: &VectorIndexConfig,
+        embedding_config: &EmbeddingConfig,
+        dimension: usize,
+    ) -> Result<bool> {
         log::info!("📦 开始初始化数据库...");
         
         log::info!("   - 启用 pgvector 扩展");
@@ -57,37 +125,17 @@ impl RagDatabase {
             .execute(pool)
             .await?;
 
-        log::info!("   - 创建 dialogues 表");
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS dialogues (
-                id SERIAL PRIMARY KEY,
-                message_uuid TEXT UNIQUE NOT NULL,
-                user_id BIGINT NOT NULL,
-                group_id BIGINT,
-                chat_type TEXT CHECK (chat_type IN ('private', 'group')),
-                role TEXT CHECK (role IN ('user', 'assistant')),
-                content TEXT NOT NULL,
-                sender_name TEXT,
-                qq_message_id BIGINT,
-                embedding VECTOR(1024),
-                token_count INTEGER,
-                score INTEGER,
-                expires_at TIMESTAMP,
-                created_at TIMESTAMP DEFAULT NOW(),
-                created_date DATE GENERATED ALWAYS AS (created_at::date) STORED
-            )
-            "#,
-        )
-        .execute(pool)
-        .await?;
+        let executed = run_migrations(pool, dimension).await?;
+        if executed.is_empty() {
+            log::info!("   - 数据库结构已是最新版本");
+        }
 
-        log::info!("   - 创建索引");
-        
-        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS idx_message_uuid ON dialogues (message_uuid)")
-            .execute(pool)
-            .await?;
+        if Self::check_embedding_model(pool, embedding_config, dimension).await? {
+            // 旧向量已清空，等重新生成后再建向量索引
+            return Ok(false);
+        }
 
+        log::info!("   - 创建向量索引");
         let mut indexes_created = true;
         
         let group_index_sql = format!(
@@ -120,24 +168,128 @@ impl RagDatabase {
             }
         }
 
-        sqlx::query(r#"CREATE INDEX IF NOT EXISTS idx_group_time ON dialogues (group_id, user_id, id DESC) 
-            WHERE group_id IS NOT NULL"#)
-            .execute(pool).await?;
+        Ok(indexes_created)
+    }
 
-        sqlx::query(r#"CREATE INDEX IF NOT EXISTS idx_private_time ON dialogues (user_id, id DESC) 
-            WHERE group_id IS NULL"#)
-            .execute(pool).await?;
+    /// 检查嵌入模型和向量维度是否与数据库中记录的一致
+    ///
+    /// 不一致时旧向量无法与新模型的查询向量比较：启用 `reembed_on_change` 时删除向量索引、
+    /// 清空旧向量并修改向量列维度，由 [`RagDatabase::dialogues_missing_embedding`] 取出后重新生成；
+    /// 否则返回错误
+    ///
+    /// # 返回
+    /// 是否清空了旧向量
+    async fn check_embedding_model(
+        pool: &PgPool,
+        embedding_config: &EmbeddingConfig,
+        dimension: usize,
+    ) -> Result<bool> {
+        let stored_model: Option<String> =
+            sqlx::query_scalar("SELECT value FROM rag_meta WHERE key = $1")
+                .bind(META_EMBEDDING_MODEL)
+                .fetch_optional(pool)
+                .await?;
+
+        // pgvector 把维度存放在列的类型修饰符中
+        let column_dimension: i32 = sqlx::query_scalar(
+                "SELECT atttypmod FROM pg_attribute
+                 WHERE attrelid = 'dialogues'::regclass AND attname = 'embedding'",
+            )
+            .fetch_one(pool)
+            .await?;
 
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_chat_context ON dialogues (chat_type, user_id, group_id, created_at DESC)")
-            .execute(pool).await?;
+        // 早于 rag_meta 的数据库没有记录模型名称，只能按维度判断
+        let model_changed = stored_model
+            .as_deref()
+            .is_some_and(|model| model != embedding_config.model);
+        let dimension_changed = column_dimension != dimension as i32;
+
+        if model_changed || dimension_changed {
+            let previous = format!(
+                "`{}`（{} 维）",
+                stored_model.as_deref().unwrap_or("未知模型"),
+                column_dimension
+            );
+            if !embedding_config.reembed_on_change {
+                return Err(anyhow::anyhow!(
+                    "嵌入模型已从 {} 变为 `{}`（{} 维），旧向量无法继续使用；\
+                     设置 memory.rag.embedding.reembed_on_change = true 以重新生成向量",
+                    previous,
+                    embedding_config.model,
+                    dimension
+                ));
+            }
 
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_created_at ON dialogues (created_at)")
-            .execute(pool).await?;
+            log::warn!(
+                "🔁 嵌入模型已从 {} 变为 `{}`（{} 维），清空旧向量并在后台重新生成",
+                previous,
+                embedding_config.model,
+                dimension
+            );
+            let mut tx = pool.begin().await?;
+            for index in ["idx_group_embedding", "idx_private_embedding"] {
+                sqlx::query(&format!("DROP INDEX IF EXISTS {}", index))
+                    .execute(&mut *tx).await?;
+            }
+            sqlx::query(&format!(
+                    "ALTER TABLE dialogues ALTER COLUMN embedding TYPE VECTOR({}) USING NULL",
+                    dimension
+                ))
+                .execute(&mut *tx).await?;
+            Self::save_embedding_meta(&mut tx, &embedding_config.model, dimension).await?;
+            tx.commit().await?;
+            return Ok(true);
+        }
 
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_expires_at ON dialogues (expires_at) WHERE expires_at IS NOT NULL")
-            .execute(pool).await?;
+        let mut tx = pool.begin().await?;
+        Self::save_embedding_meta(&mut tx, &embedding_config.model, dimension).await?;
+        tx.commit().await?;
+        Ok(false)
+    }
 
-        Ok(indexes_created)
+    async fn save_embedding_meta(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        model: &str,
+        dimension: usize,
+    ) -> Result<()> {
+        for (key, value) in [
+            (META_EMBEDDING_MODEL, model.to_string()),
+            (META_EMBEDDING_DIMENSION, dimension.to_string()),
+        ] {
+            sqlx::query(
+                    "INSERT INTO rag_meta (key, value) VALUES ($1, $2)
+                     ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, updated_at = NOW()",
+                )
+                .bind(key).bind(value)
+                .execute(&mut **tx).await?;
+        }
+        Ok(())
+    }
+
+    /// 取出一批 id 大于 `after_id` 且还没有向量的对话（嵌入模型变更后等待重新生成）
+    ///
+    /// 调用方按 id 递增翻页，生成失败的对话不会被反复取出
+    pub async fn dialogues_missing_embedding(
+        &self,
+        after_id: i32,
+        limit: usize,
+    ) -> Result<Vec<(i32, String)>> {
+        let rows = sqlx::query(
+                "SELECT id, content FROM dialogues WHERE embedding IS NULL AND id > $1
+                 ORDER BY id LIMIT $2",
+            )
+            .bind(after_id).bind(limit as i64)
+            .fetch_all(&self.pool).await?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// 写入重新生成的向量
+    pub async fn update_embedding(&self, id: i32, embedding: &[f32]) -> Result<()> {
+        sqlx::query("UPDATE dialogues SET embedding = $1 WHERE id = $2")
+            .bind(Vector::from(embedding.to_vec())).bind(id)
+            .execute(&self.pool).await?;
+        Ok(())
     }
 
     pub async fn insert_dialogue_with_score(
@@ -159,8 +311,8 @@ impl RagDatabase {
 
         let row = sqlx::query(
                 "INSERT INTO dialogues 
-                (message_uuid, user_id, group_id, chat_type, role, content, sender_name, qq_message_id, embedding, token_count, score, expires_at, created_at)
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, NOW())
+                (message_uuid, user_id, group_id, chat_type, role, content, sender_name, qq_message_id, embedding, token_count, score, expires_at, search_vector, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, array_to_tsvector($13::text[]), NOW())
                 ON CONFLICT (message_uuid) DO NOTHING
                 RETURNING id",
             )
@@ -176,11 +328,12 @@ impl RagDatabase {
             .bind(token_count)
             .bind(score)
             .bind(expires_at)
+            .bind(keyword_terms(content))
             .fetch_optional(&self.pool)
             .await?;
         
         if !self.vector_indexes_created.load(Ordering::Relaxed) {
-            let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM dialogues")
+            let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM dialogues WHERE embedding IS NOT NULL")
                 .fetch_one(&self.pool).await.unwrap_or(0);
                 
             if count >= 100 {
@@ -200,6 +353,7 @@ impl RagDatabase {
         }
     }
 
+    /// 向量检索：按余弦距离从近到远返回锚点
     pub async fn search_by_embedding(
         &self,
         user_id: i64,
@@ -207,27 +361,32 @@ impl RagDatabase {
         embedding: &[f32],
         exclude_message_ids: Option<&[String]>,
         limit: usize,
-    ) -> Result<Vec<(i32, String)>> {
+    ) -> Result<Vec<SearchHit>> {
         let embedding_vec = Vector::from(embedding.to_vec());
         
         let exclude_ids: Vec<&str> = exclude_message_ids
             .unwrap_or(&[]).iter().map(|s| s.as_str()).collect();
         
+        // 排序使用与 ivfflat 索引（vector_cosine_ops）一致的余弦距离
         let query_str = if group_id.is_some() {
              if !exclude_ids.is_empty() {
-                "SELECT id, message_uuid FROM dialogues WHERE user_id = $1 AND group_id = $2 AND message_uuid != ALL($3)
-                 ORDER BY embedding <-> $4 LIMIT $5"
+                "SELECT id, message_uuid, content, embedding, (embedding <=> $4)::REAL AS distance, score, created_at
+                 FROM dialogues WHERE user_id = $1 AND group_id = $2 AND message_uuid != ALL($3) AND embedding IS NOT NULL
+                 ORDER BY embedding <=> $4 LIMIT $5"
              } else {
-                "SELECT id, message_uuid FROM dialogues WHERE user_id = $1 AND group_id = $2
-                 ORDER BY embedding <-> $3 LIMIT $4"
+                "SELECT id, message_uuid, content, embedding, (embedding <=> $3)::REAL AS distance, score, created_at
+                 FROM dialogues WHERE user_id = $1 AND group_id = $2 AND embedding IS NOT NULL
+                 ORDER BY embedding <=> $3 LIMIT $4"
              }
         } else {
              if !exclude_ids.is_empty() {
-                "SELECT id, message_uuid FROM dialogues WHERE user_id = $1 AND group_id IS NULL AND message_uuid != ALL($2)
-                 ORDER BY embedding <-> $3 LIMIT $4"
+                "SELECT id, message_uuid, content, embedding, (embedding <=> $3)::REAL AS distance, score, created_at
+                 FROM dialogues WHERE user_id = $1 AND group_id IS NULL AND message_uuid != ALL($2) AND embedding IS NOT NULL
+                 ORDER BY embedding <=> $3 LIMIT $4"
              } else {
-                "SELECT id, message_uuid FROM dialogues WHERE user_id = $1 AND group_id IS NULL
-                 ORDER BY embedding <-> $2 LIMIT $3"
+                "SELECT id, message_uuid, content, embedding, (embedding <=> $2)::REAL AS distance, score, created_at
+                 FROM dialogues WHERE user_id = $1 AND group_id IS NULL AND embedding IS NOT NULL
+                 ORDER BY embedding <=> $2 LIMIT $3"
              }
         };
         
@@ -249,9 +408,64 @@ impl RagDatabase {
             }
         };
         
-        let mut results = Vec::new();
-        for row in rows { results.push((row.get(0), row.get(1))); }
-        Ok(results)
+        Ok(rows.iter().map(SearchHit::from_row).collect())
+    }
+
+    /// 关键词检索：匹配任一检索词的对话，按 ts_rank 排序
+    ///
+    /// 同时计算与查询向量的余弦距离（还没有向量的对话为 None），供后续排序使用
+    pub async fn search_by_keywords(
+        &self,
+        user_id: i64,
+        group_id: Option<i64>,
+        query: &str,
+        embedding: &[f32],
+        exclude_message_ids: Option<&[String]>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>> {
+        let Some(tsquery) = tsquery_text(&keyword_terms(query)) else {
+            return Ok(Vec::new());
+        };
+
+        let exclude_ids: Vec<&str> = exclude_message_ids
+            .unwrap_or(&[]).iter().map(|s| s.as_str()).collect();
+
+        let rows = sqlx::query(
+                "SELECT id, message_uuid, content, embedding, (embedding <=> $6)::REAL AS distance, score, created_at
+                 FROM dialogues
+                 WHERE user_id = $1 AND group_id IS NOT DISTINCT FROM $2 AND message_uuid != ALL($3)
+                   AND search_vector @@ $4::tsquery
+                 ORDER BY ts_rank(search_vector, $4::tsquery) DESC, id DESC LIMIT $5",
+            )
+            .bind(user_id).bind(group_id).bind(&exclude_ids)
+            .bind(tsquery).bind(limit as i64).bind(Vector::from(embedding.to_vec()))
+            .fetch_all(&self.pool).await?;
+
+        Ok(rows.iter().map(SearchHit::from_row).collect())
+    }
+
+    /// 为早于关键词检索的对话补建检索词，返回处理的条数
+    pub async fn backfill_search_vectors(&self, batch_size: usize) -> Result<usize> {
+        let mut total = 0;
+        loop {
+            let rows = sqlx::query(
+                    "SELECT id, content FROM dialogues WHERE search_vector IS NULL ORDER BY id LIMIT $1",
+                )
+                .bind(batch_size as i64)
+                .fetch_all(&self.pool).await?;
+            if rows.is_empty() {
+                return Ok(total);
+            }
+
+            for row in &rows {
+                let id: i32 = row.get(0);
+                let content: String = row.get(1);
+                sqlx::query("UPDATE dialogues SET search_vector = array_to_tsvector($1::text[]) WHERE id = $2")
+                    .bind(keyword_terms(&content)).bind(id)
+                    .execute(&self.pool).await?;
+            }
+            total += rows.len();
+        }
     }
 
     pub async fn get_context_window(
@@ -307,7 +521,7 @@ impl RagDatabase {
                 chat_type: row.get("chat_type"), role: row.get("role"),
                 content: row.get("content"), sender_name: row.get("sender_name"),
                 qq_message_id: row.get("qq_message_id"), token_count: row.get("token_count"),
-                score: row.try_get("score").ok(), expires_at, created_at,
+                score: row.try_get("score").ok(), expires_at, created_at, distance: None,
             });
         }
         Ok(dialogues)
@@ -356,7 +570,7 @@ impl RagDatabase {
                 chat_type: row.get("chat_type"), role: row.get("role"),
                 content: row.get("content"), sender_name: row.get("sender_name"),
                 qq_message_id: row.get("qq_message_id"), token_count: row.get("token_count"),
-                score: row.try_get("score").ok(), expires_at, created_at,
+                score: row.try_get("score").ok(), expires_at, created_at, distance: None,
             });
         }
         
@@ -372,15 +586,16 @@ impl RagDatabase {
 
         for (message_uuid, user_id, group_id, chat_type, role, content, sender_name, qq_message_id, embedding, token_count, created_at) in dialogues {
             let embedding_vec = Vector::from(embedding);
+            let terms = keyword_terms(&content);
             
             let result = sqlx::query(
                     "INSERT INTO dialogues 
-                    (message_uuid, user_id, group_id, chat_type, role, content, sender_name, qq_message_id, embedding, token_count, created_at)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) ON CONFLICT (message_uuid) DO NOTHING",
+                    (message_uuid, user_id, group_id, chat_type, role, content, sender_name, qq_message_id, embedding, token_count, created_at, search_vector)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, array_to_tsvector($12::text[])) ON CONFLICT (message_uuid) DO NOTHING",
                 )
                 .bind(message_uuid).bind(user_id).bind(group_id).bind(chat_type).bind(role)
                 .bind(content).bind(sender_name).bind(qq_message_id).bind(embedding_vec)
-                .bind(token_count).bind(created_at).execute(&self.pool).await?;
+                .bind(token_count).bind(created_at).bind(terms).execute(&self.pool).await?;
 
             inserted += result.rows_affected() as usize;
         }
@@ -396,6 +611,73 @@ impl RagDatabase {
         Ok(count)
     }
 
+    /// 删除创建超过 `days` 天的非永久记忆（永久记忆 expires_at 为空，不受影响）
+    pub async fn cleanup_old_memories(&self, days: u64) -> Result<u64> {
+        let result = sqlx::query(
+                "DELETE FROM dialogues
+                 WHERE expires_at IS NOT NULL AND created_at < NOW() - make_interval(days => $1)",
+            )
+            .bind(days.min(i32::MAX as u64) as i32)
+            .execute(&self.pool).await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// 维护向量索引：数据量足够时补建索引，已有索引则重建以适应新的数据分布，最后更新统计信息
+    pub async fn maintain_vector_indexes(&self) -> Result<()> {
+        if self.vector_indexes_created.load(Ordering::Relaxed) {
+            // ivfflat 的聚类中心在建索引时确定，数据增长后需要重建才能保持召回率
+            for index in ["idx_group_embedding", "idx_private_embedding"] {
+                sqlx::query(&format!("REINDEX INDEX CONCURRENTLY {}", index))
+                    .execute(&self.pool).await?;
+            }
+            log::info!("   ✓ 向量索引已重建");
+        } else {
+            let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM dialogues WHERE embedding IS NOT NULL")
+                .fetch_one(&self.pool).await?;
+            if count >= 100 {
+                self.try_create_vector_indexes().await?;
+            }
+        }
+
+        sqlx::query("ANALYZE dialogues").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    pub async fn insert_token_usage(
+        &self, user_id: i64, group_id: Option<i64>, model: &str,
+        prompt_tokens: i32, completion_tokens: i32, total_tokens: i32,
+    ) -> Result<()> {
+        sqlx::query(
+                "INSERT INTO token_usage (user_id, group_id, model, prompt_tokens, completion_tokens, total_tokens)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(user_id).bind(group_id).bind(model)
+            .bind(prompt_tokens).bind(completion_tokens).bind(total_tokens)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// 统计 `since` 之后的 token 总量：传入 group_id 时按群统计，否则按用户统计
+    pub async fn sum_token_usage(
+        &self, user_id: i64, group_id: Option<i64>, since: DateTime<Utc>,
+    ) -> Result<u64> {
+        let total: i64 = if let Some(gid) = group_id {
+            sqlx::query_scalar(
+                    "SELECT COALESCE(SUM(total_tokens), 0)::BIGINT FROM token_usage
+                     WHERE group_id = $1 AND created_at >= $2",
+                )
+                .bind(gid).bind(since).fetch_one(&self.pool).await?
+        } else {
+            sqlx::query_scalar(
+                    "SELECT COALESCE(SUM(total_tokens), 0)::BIGINT FROM token_usage
+                     WHERE user_id = $1 AND created_at >= $2",
+                )
+                .bind(user_id).bind(since).fetch_one(&self.pool).await?
+        };
+        Ok(total.max(0) as u64)
+    }
+
     async fn try_create_vector_indexes(&self) -> Result<()> {
         let mut success = true;
         
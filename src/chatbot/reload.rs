@@ -0,0 +1,203 @@
+//! 配置热重载
+//!
+//! [`SharedChatBot`] 持有当前生效的聊天机器人，重载时用新配置构建新实例后整体替换；
+//! 每条消息开始处理时取出当时的实例，进行中的对话会在旧实例上完成
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+use crate::chatbot::chat::ChatBot;
+use crate::chatbot::config::{load_config, HotReloadConfig};
+use crate::chatbot::mcp::McpConfigFile;
+
+/// 等待旧实例上的对话结束的最长时间（秒），超时后直接释放旧资源
+const RETIRE_TIMEOUT_SECS: u64 = 300;
+
+/// 可热重载的聊天机器人
+pub struct SharedChatBot {
+    current: RwLock<Arc<ChatBot>>,
+    config_path: PathBuf,
+    /// 串行化重载，避免文件监听和管理命令同时重载
+    reload_lock: tokio::sync::Mutex<()>,
+}
+
+impl SharedChatBot {
+    /// 创建可热重载的聊天机器人
+    ///
+    /// # 参数
+    /// - `chatbot`: 初始实例
+    /// - `config_path`: config.json 路径
+    pub fn new<P: Into<PathBuf>>(chatbot: ChatBot, config_path: P) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(chatbot)),
+            config_path: config_path.into(),
+            reload_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// 当前生效的聊天机器人
+    pub fn current(&self) -> Arc<ChatBot> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// config.json 路径
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    /// 重新读取 config.json 和 mcp.json，校验通过后替换当前实例
+    ///
+    /// 任一文件无效时返回错误，当前实例保持不变
+    ///
+    /// # 参数
+    /// - `reload_mcp`: 是否重新连接 MCP 服务（mcp.json 变化或手动重载时为 true）
+    pub async fn reload(&self, reload_mcp: bool) -> Result<()> {
+        let _guard = self.reload_lock.lock().await;
+
+        if !self.config_path.exists() {
+            return Err(anyhow::anyhow!("配置文件不存在: {:?}", self.config_path));
+        }
+        let config = load_config(&self.config_path)
+            .map_err(|e| anyhow::anyhow!("配置文件无效: {}", e))?;
+
+        let config_dir = self.config_path.parent();
+        let report = config.validate(config_dir);
+        for warning in report.warnings() {
+            log::warn!("⚠️  配置警告 {}", warning);
+        }
+        if report.has_errors() {
+            return Err(anyhow::anyhow!("配置校验失败:\n{}", report.error_summary()));
+        }
+
+        // MCP 配置文件必须能解析（路径相对于 config.json 所在目录）
+        if config.mcp.enabled && !config.mcp.path.is_empty() {
+            let config_dir = config_dir.unwrap_or(Path::new(""));
+            McpConfigFile::load(config_dir.join(&config.mcp.path))?;
+        }
+
+        let new_chatbot = Arc::new(self.current().reload(config, reload_mcp).await?);
+        let old = std::mem::replace(&mut *self.current.write().unwrap(), new_chatbot.clone());
+        log::info!("🔄 配置已重新加载，主模型: {}", new_chatbot.get_stats().llm_model);
+
+        tokio::spawn(retire_when_idle(old, new_chatbot));
+        Ok(())
+    }
+}
+
+/// 等待旧实例上的对话结束后释放它独占的资源
+async fn retire_when_idle(old: Arc<ChatBot>, successor: Arc<ChatBot>) {
+    let deadline = Instant::now() + Duration::from_secs(RETIRE_TIMEOUT_SECS);
+    while Arc::strong_count(&old) > 1 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    old.retire(&successor).await;
+}
+
+/// 文件的修改时间，文件不存在或无法读取时为 None
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// 启动配置文件监听任务
+///
+/// 按间隔检查 config.json 和 mcp.json 的修改时间，变化后自动重载；未启用时返回 None
+///
+/// # 参数
+/// - `chatbot`: 可热重载的聊天机器人
+/// - `config`: 热重载配置
+pub fn spawn_config_watcher(
+    chatbot: Arc<SharedChatBot>,
+    config: HotReloadConfig,
+) -> Option<JoinHandle<()>> {
+    if !config.enabled || config.interval == 0 {
+        log::info!("⏸️  配置文件监听未启用");
+        return None;
+    }
+
+    log::info!("👀 配置文件监听已启动，检查间隔 {} 秒", config.interval);
+
+    Some(tokio::spawn(async move {
+        let mut timer = interval(Duration::from_secs(config.interval));
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut config_mtime = modified_time(chatbot.config_path());
+        let mut mcp_path = chatbot.current().mcp_config_path();
+        let mut mcp_mtime = mcp_path.as_deref().and_then(modified_time);
+
+        loop {
+            timer.tick().await;
+
+            let new_config_mtime = modified_time(chatbot.config_path());
+            let new_mcp_mtime = mcp_path.as_deref().and_then(modified_time);
+            let config_changed = new_config_mtime != config_mtime;
+            let mcp_changed = new_mcp_mtime != mcp_mtime;
+            if !config_changed && !mcp_changed {
+                continue;
+            }
+
+            // 无论重载是否成功都记下新的修改时间，文件再次修改前不重复尝试
+            config_mtime = new_config_mtime;
+            mcp_mtime = new_mcp_mtime;
+
+            log::info!("📝 检测到配置文件变化，正在重新加载...");
+            match chatbot.reload(mcp_changed).await {
+                Ok(()) => {
+                    // mcp.path 可能随 config.json 一起变化
+                    mcp_path = chatbot.current().mcp_config_path();
+                    mcp_mtime = mcp_path.as_deref().and_then(modified_time);
+                }
+                Err(e) => log::error!("❌ 配置重新加载失败，继续使用当前配置: {}", e),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::config::save_config;
+    use crate::chatbot::test_support::{mock_config, MockLlmServer};
+
+    #[tokio::test]
+    async fn test_reload_swaps_config() {
+        let server = MockLlmServer::start().await;
+        let dir = std::env::temp_dir().join(format!("xiaoshi-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json");
+
+        let mut config = mock_config(&server);
+        config.llm.model = "old-model".to_string();
+        config.mcp.enabled = false;
+        save_config(&config_path, &config).unwrap();
+
+        let chatbot = ChatBot::new(config.clone(), &config_path).await.unwrap();
+        let shared = SharedChatBot::new(chatbot, &config_path);
+        shared.current().chat(10001, None, "你好", &[], "测试用户").await.unwrap();
+        let old = shared.current();
+
+        config.llm.model = "new-model".to_string();
+        save_config(&config_path, &config).unwrap();
+        shared.reload(false).await.unwrap();
+
+        // 新实例使用新模型，短期记忆沿用
+        let current = shared.current();
+        assert_eq!(current.get_stats().llm_model, "new-model");
+        assert_eq!(current.get_stats().conversation_count, 1);
+        assert_eq!(old.get_stats().llm_model, "old-model");
+
+        // 配置文件无法解析或校验失败时保留当前实例
+        std::fs::write(&config_path, "{ invalid").unwrap();
+        assert!(shared.reload(false).await.is_err());
+        config.llm.temperature = Some(5.0);
+        save_config(&config_path, &config).unwrap();
+        let err = shared.reload(false).await.unwrap_err();
+        assert!(err.to_string().contains("llm.temperature"));
+        assert_eq!(shared.current().get_stats().llm_model, "new-model");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+//! 配置中的密钥引用
+//!
+//! 配置文件中的字符串可以引用环境变量或文件，避免 API Key、数据库密码以明文写在配置里：
+//! - `${ENV_VAR}`：替换为环境变量的值
+//! - `${file:/run/secrets/xxx}`：替换为文件内容（去掉首尾空白），相对路径相对于配置文件所在目录
+//!
+//! 只有整个值就是一个引用时才会展开，提示词里的 `${name}`、MCP 参数里的 `file:///data`
+//! 等普通文本保持原样；整个值需要是字面量 `${...}` 时写作 `$${...}`。
+//!
+//! 加载时展开引用并记下原始写法，保存时恢复，展开后的密钥不会被写回磁盘
+
+use anyhow::Result;
+use serde_json::Value;
+use std::path::Path;
+
+/// 文件引用的前缀（位于 `${` 和 `}` 之间）
+const FILE_PREFIX: &str = "file:";
+
+/// 存放密钥的字段名（`*.apikey`、`db.postgres.password` 等）
+const SECRET_FIELDS: [&str; 2] = ["apikey", "password"];
+
+/// 是否为合法的环境变量名（字母或下划线开头，只包含字母、数字和下划线）
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// 一处被展开的引用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Substitution {
+    /// 值在配置中的位置（JSON Pointer，如 `/llm/apikey`）
+    pub pointer: String,
+    /// 配置文件中的原始写法
+    pub raw: String,
+    /// 展开后的值
+    pub expanded: String,
+}
+
+/// 把 JSON Pointer 转换为便于阅读的路径（`/llm/fallbacks/0/url` -> `llm.fallbacks[0].url`）
+fn display_path(pointer: &str) -> String {
+    let mut path = String::new();
+    for segment in pointer.split('/').skip(1) {
+        let segment = segment.replace("~1", "/").replace("~0", "~");
+        if segment.parse::<usize>().is_ok() {
+            path.push_str(&format!("[{}]", segment));
+        } else {
+            if !path.is_empty() {
+                path.push('.');
+            }
+            path.push_str(&segment);
+        }
+    }
+    path
+}
+
+/// 密钥字段的值是否像是少了 `${}` 的文件引用（如 `file:/run/secrets/xxx`）
+///
+/// 这样的值不会被展开，会被当作密钥原文使用
+fn is_bare_file_ref(pointer: &str, raw: &str) -> bool {
+    let field = pointer.rsplit('/').next().unwrap_or_default();
+    SECRET_FIELDS.contains(&field) && raw.starts_with(FILE_PREFIX)
+}
+
+/// 展开单个字符串中的引用，整个值不是引用时返回 None
+fn expand_str(raw: &str, base_dir: Option<&Path>) -> Result<Option<String>> {
+    // `$${...}` 转义为字面量 `${...}`
+    if let Some(literal) = raw.strip_prefix('$') {
+        if literal.starts_with("${") && literal.ends_with('}') {
+            return Ok(Some(literal.to_string()));
+        }
+    }
+
+    let Some(inner) = raw.strip_prefix("${").and_then(|rest| rest.strip_suffix('}')) else {
+        return Ok(None);
+    };
+
+    if let Some(file) = inner.strip_prefix(FILE_PREFIX) {
+        let file = file.trim();
+        let path = match base_dir {
+            Some(dir) => dir.join(file),
+            None => Path::new(file).to_path_buf(),
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("读取密钥文件 {:?} 失败: {}", path, e))?;
+        return Ok(Some(content.trim().to_string()));
+    }
+
+    if !is_env_name(inner) {
+        return Ok(None);
+    }
+    let value = std::env::var(inner)
+        .map_err(|_| anyhow::anyhow!("环境变量 {} 未设置", inner))?;
+    Ok(Some(value))
+}
+
+fn expand_at(
+    value: &mut Value,
+    pointer: &mut String,
+    base_dir: Option<&Path>,
+    substitutions: &mut Vec<Substitution>,
+) -> Result<()> {
+    match value {
+        Value::String(raw) => {
+            let expanded = expand_str(raw, base_dir)
+                .map_err(|e| anyhow::anyhow!("{}: {}", display_path(pointer), e))?;
+            match expanded {
+                Some(expanded) => substitutions.push(Substitution {
+                    pointer: pointer.clone(),
+                    raw: std::mem::replace(raw, expanded.clone()),
+                    expanded,
+                }),
+                None if is_bare_file_ref(pointer, raw) => log::warn!(
+                    "⚠️  {} 的值以 file: 开头但不会被展开，引用密钥文件请写作 \"${{{}}}\"",
+                    display_path(pointer),
+                    raw
+                ),
+                None => {}
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                let len = pointer.len();
+                pointer.push_str(&format!("/{}", i));
+                expand_at(item, pointer, base_dir, substitutions)?;
+                pointer.truncate(len);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                let len = pointer.len();
+                pointer.push('/');
+                pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                expand_at(item, pointer, base_dir, substitutions)?;
+                pointer.truncate(len);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// 展开 JSON 中所有字符串里的环境变量和文件引用
+///
+/// # 参数
+/// - `value`: 从配置文件解析出的 JSON，原地展开
+/// - `base_dir`: 配置文件所在目录，用于解析相对路径的文件引用
+///
+/// # 返回
+/// 所有被展开的引用，保存配置时用于恢复原始写法
+pub fn expand_references(value: &mut Value, base_dir: Option<&Path>) -> Result<Vec<Substitution>> {
+    let mut substitutions = Vec::new();
+    expand_at(value, &mut String::new(), base_dir, &mut substitutions)?;
+    Ok(substitutions)
+}
+
+/// 把展开过的值恢复为原始引用（值已被修改的位置保留新值）
+pub fn restore_references(value: &mut Value, substitutions: &[Substitution]) {
+    for substitution in substitutions {
+        if let Some(Value::String(current)) = value.pointer_mut(&substitution.pointer) {
+            if *current == substitution.expanded {
+                *current = substitution.raw.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_expand_and_restore() {
+        std::env::set_var("XIAOSHI_TEST_API_KEY", "sk-secret");
+        let dir = std::env::temp_dir().join(format!("xiaoshi-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("db_password"), "p@ss\n").unwrap();
+
+        let original = json!({
+            "llm": { "apikey": "${XIAOSHI_TEST_API_KEY}", "model": "gpt-4o" },
+            "db": { "password": "${file:db_password}" },
+            "list": ["Bearer ${XIAOSHI_TEST_API_KEY}", "$${HOME}"]
+        });
+        let mut value = original.clone();
+        let substitutions = expand_references(&mut value, Some(&dir)).unwrap();
+
+        assert_eq!(value["llm"]["apikey"], "sk-secret");
+        assert_eq!(value["db"]["password"], "p@ss");
+        // 只展开整个值就是引用的字符串
+        assert_eq!(value["list"][0], "Bearer ${XIAOSHI_TEST_API_KEY}");
+        assert_eq!(value["list"][1], "${HOME}");
+        assert_eq!(value["llm"]["model"], "gpt-4o");
+
+        // 未修改的值恢复为引用，修改过的值保留新值
+        value["llm"]["model"] = json!("gpt-4.1");
+        value["db"]["password"] = json!("new-password");
+        restore_references(&mut value, &substitutions);
+        assert_eq!(value["llm"]["apikey"], original["llm"]["apikey"]);
+        assert_eq!(value["list"], original["list"]);
+        assert_eq!(value["db"]["password"], "new-password");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plain_text_is_not_expanded() {
+        // 路径参数和提示词中的占位符不是引用
+        let original = json!({
+            "args": ["file:///data", "file:notes", "${HOME}/data"],
+            "memory": { "prompt": "你好 ${name}，今天是 ${date}" },
+            "persona": { "description": "${不是变量}" }
+        });
+        let mut value = original.clone();
+        let substitutions = expand_references(&mut value, None).unwrap();
+        assert!(substitutions.is_empty());
+        assert_eq!(value, original);
+    }
+
+    #[test]
+    fn test_bare_file_ref_in_secret_field() {
+        assert!(is_bare_file_ref("/llm/apikey", "file:/run/secrets/llm_key"));
+        assert!(is_bare_file_ref("/db/postgres/password", "file:db_password"));
+        assert!(!is_bare_file_ref("/db/postgres/password", "p@ss"));
+        assert!(!is_bare_file_ref("/mcpServers/fs/args/0", "file:///data"));
+    }
+
+    #[test]
+    fn test_missing_env_var_reports_path() {
+        let mut value = json!({ "llm": { "fallbacks": [{ "apikey": "${XIAOSHI_TEST_UNSET}" }] } });
+        let err = expand_references(&mut value, None).unwrap_err().to_string();
+        assert!(err.contains("llm.fallbacks[0].apikey"), "{}", err);
+        assert!(err.contains("XIAOSHI_TEST_UNSET"), "{}", err);
+    }
+}
@@ -0,0 +1,150 @@
+//! 对话摘要模块
+//!
+//! 把被挤出短期记忆的旧对话压缩进每个会话的滚动摘要，长对话不会因为窗口限制而断线
+
+use anyhow::Result;
+use std::sync::Arc;
+
+use crate::chatbot::config::SummaryConfig;
+use crate::chatbot::llm::LlmBackend;
+use crate::chatbot::memory::ChatMessage;
+
+/// 单次摘要请求的超时时间（秒）
+const SUMMARY_TIMEOUT_SECS: u64 = 60;
+
+/// 对话摘要器
+pub struct HistorySummarizer {
+    llm_client: Arc<dyn LlmBackend>,
+    config: SummaryConfig,
+}
+
+impl HistorySummarizer {
+    /// 创建新的对话摘要器
+    ///
+    /// # 参数
+    /// - `llm_client`: 用于生成摘要的模型（通常为主对话模型）
+    /// - `config`: 摘要配置
+    pub fn new(llm_client: Arc<dyn LlmBackend>, config: SummaryConfig) -> Self {
+        Self { llm_client, config }
+    }
+
+    /// 把新挤出的消息合并进已有摘要
+    ///
+    /// # 参数
+    /// - `previous`: 已有摘要（首次摘要时为 None）
+    /// - `evicted`: 按时间顺序排列的被挤出消息
+    ///
+    /// # 返回
+    /// 合并后的新摘要
+    pub async fn summarize(
+        &self,
+        previous: Option<&str>,
+        evicted: &[ChatMessage],
+    ) -> Result<String> {
+        use tokio::time::{timeout, Duration};
+
+        let dialogue = evicted
+            .iter()
+            .map(|msg| {
+                let speaker = if msg.role == "assistant" {
+                    "Assistant"
+                } else {
+                    "User"
+                };
+                format!("{}: {}", speaker, msg.content)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let content = format!(
+            "【已有摘要】\n{}\n\n【新增对话】\n{}",
+            previous.unwrap_or("无"),
+            dialogue
+        );
+
+        let messages = vec![
+            (
+                "system".to_string(),
+                format!(
+                    "{}\n摘要不超过 {} 字。",
+                    self.config.prompt, self.config.max_chars
+                ),
+            ),
+            ("user".to_string(), content),
+        ];
+
+        let response = timeout(
+            Duration::from_secs(SUMMARY_TIMEOUT_SECS),
+            self.llm_client.chat_with_history(messages),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("摘要API调用超时（>{}秒）", SUMMARY_TIMEOUT_SECS))?
+        .map_err(|e| anyhow::anyhow!("摘要API调用失败: {}", e))?;
+
+        let summary = response.trim().to_string();
+        if summary.is_empty() {
+            return Err(anyhow::anyhow!("模型返回了空摘要"));
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::config::{LlmProvider, RetryConfig};
+    use crate::chatbot::llm::{create_backend, LlmRequestParams};
+    use crate::chatbot::test_support::{MockLlmServer, MockReply};
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            message_id: String::new(),
+            role: role.to_string(),
+            content: content.to_string(),
+            timestamp: 0,
+            token_count: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_summarize_with_mock_server() {
+        let server = MockLlmServer::start().await;
+        server.push_reply(MockReply::text("  用户叫小明，下周三要考试。  "));
+        server.push_reply(MockReply::text(""));
+
+        let client = create_backend(
+            LlmProvider::OpenAi,
+            "test-key".to_string(),
+            server.url(),
+            "mock-model".to_string(),
+            LlmRequestParams::default(),
+            RetryConfig::default(),
+        )
+        .unwrap();
+        let summarizer = HistorySummarizer::new(client, SummaryConfig::default());
+
+        let evicted = vec![
+            message("user", "我叫小明"),
+            message("assistant", "你好小明"),
+        ];
+        let summary = summarizer
+            .summarize(Some("用户下周三考试"), &evicted)
+            .await
+            .unwrap();
+        assert_eq!(summary, "用户叫小明，下周三要考试。");
+
+        // 空摘要视为失败，保留旧摘要
+        assert!(summarizer.summarize(None, &evicted).await.is_err());
+
+        let requests = server.chat_requests();
+        assert_eq!(
+            requests[0]["messages"][1]["content"],
+            "【已有摘要】\n用户下周三考试\n\n【新增对话】\nUser: 我叫小明\nAssistant: 你好小明"
+        );
+        assert!(requests[1]["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("【已有摘要】\n无"));
+    }
+}
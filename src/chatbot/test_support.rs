@@ -0,0 +1,544 @@
+//! 测试辅助模块
+//!
+//! 提供一个进程内的 OpenAI 兼容模拟服务器，用于离线测试 LLM、记忆评估、
+//! Embedding 和工具调用循环：
+//! - `/chat/completions`：按顺序返回预设的回复（文本、工具调用、错误），支持流式输出
+//! - `/embeddings`：根据输入文本生成确定性的向量
+//! - `/rerank`：按查询中的字符在文档里出现的比例打分
+//! - `/mcp`：StreamableHTTP 模式的 MCP 服务器，提供一个按参数等待后返回的 `sleep` 工具
+
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::chatbot::config::Config;
+
+/// 没有预设回复时使用的默认文本
+pub const DEFAULT_REPLY: &str = "这是模拟回复。";
+
+/// 每次请求固定计入的 prompt token 数
+pub const MOCK_PROMPT_TOKENS: u32 = 10;
+
+/// 默认的向量维度
+const DEFAULT_EMBEDDING_DIM: usize = 8;
+
+/// 预设的 `/chat/completions` 回复
+#[derive(Debug, Clone)]
+pub enum MockReply {
+    /// 普通文本回复
+    Text(String),
+    /// 工具调用，每个元素为 (函数名, 参数)
+    ToolCalls(Vec<(String, Value)>),
+    /// 返回错误状态码和响应体
+    Error(u16, String),
+}
+
+impl MockReply {
+    /// 创建文本回复
+    pub fn text(content: &str) -> Self {
+        MockReply::Text(content.to_string())
+    }
+
+    /// 创建单个工具调用回复
+    pub fn tool_call(name: &str, arguments: Value) -> Self {
+        MockReply::ToolCalls(vec![(name.to_string(), arguments)])
+    }
+}
+
+/// 服务器收到的请求
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub path: String,
+    pub body: Value,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    replies: VecDeque<MockReply>,
+    requests: Vec<RecordedRequest>,
+    embedding_dim: usize,
+    next_call_id: usize,
+    /// 正在执行的 MCP 工具调用数
+    tool_calls_in_flight: usize,
+    /// 同时执行的 MCP 工具调用数的最大值
+    max_tool_calls_in_flight: usize,
+}
+
+/// OpenAI 兼容的模拟服务器
+///
+/// 服务器随 `MockLlmServer` 一起销毁
+pub struct MockLlmServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockLlmServer {
+    /// 在随机端口上启动模拟服务器
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("无法绑定模拟服务器端口");
+        let addr = listener.local_addr().expect("无法获取模拟服务器地址");
+
+        let state = Arc::new(Mutex::new(MockState {
+            embedding_dim: DEFAULT_EMBEDDING_DIM,
+            ..MockState::default()
+        }));
+
+        let server_state = state.clone();
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, state).await {
+                        log::warn!("模拟服务器处理请求失败: {}", e);
+                    }
+                });
+            }
+        });
+
+        Self {
+            addr,
+            state,
+            handle,
+        }
+    }
+
+    /// API 基础地址（对应配置中的 `url`）
+    pub fn url(&self) -> String {
+        format!("http://{}/v1", self.addr)
+    }
+
+    /// Embedding 接口地址（对应 `embedding.url`）
+    pub fn embeddings_url(&self) -> String {
+        format!("{}/embeddings", self.url())
+    }
+
+    /// Rerank 接口地址（对应 `rerank.url`）
+    pub fn rerank_url(&self) -> String {
+        format!("{}/rerank", self.url())
+    }
+
+    /// MCP 接口地址（对应 mcp.json 中 `streamable-http` 服务器的 `url`）
+    pub fn mcp_url(&self) -> String {
+        format!("{}/mcp", self.url())
+    }
+
+    /// 同时执行的 MCP 工具调用数的最大值
+    pub fn max_tool_calls_in_flight(&self) -> usize {
+        self.state.lock().unwrap().max_tool_calls_in_flight
+    }
+
+    /// 追加一条预设回复，按追加顺序依次返回
+    pub fn push_reply(&self, reply: MockReply) {
+        self.state.lock().unwrap().replies.push_back(reply);
+    }
+
+    /// 设置生成的向量维度
+    pub fn set_embedding_dim(&self, dim: usize) {
+        self.state.lock().unwrap().embedding_dim = dim;
+    }
+
+    /// 获取所有收到的请求
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// 获取所有 `/chat/completions` 请求体
+    pub fn chat_requests(&self) -> Vec<Value> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.path.ends_with("/chat/completions"))
+            .map(|r| r.body)
+            .collect()
+    }
+}
+
+impl Drop for MockLlmServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// 连接模拟服务器的测试配置：主模型为 `mock-model`，关闭 RAG
+pub fn mock_config(server: &MockLlmServer) -> Config {
+    let mut config = Config::default();
+    config.llm.model = "mock-model".to_string();
+    config.llm.url = server.url();
+    config.llm.apikey = "test-key".to_string();
+    config.memory.rag.enabled = false;
+    config
+}
+
+/// 为文本生成确定性的单位向量
+pub fn mock_embedding(text: &str, dim: usize) -> Vec<f32> {
+    let values: Vec<f32> = (0..dim)
+        .map(|i| {
+            // FNV-1a，按维度加盐
+            let mut hash: u64 = 0xcbf29ce484222325 ^ (i as u64);
+            for byte in text.as_bytes() {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+            (hash % 2001) as f32 / 1000.0 - 1.0
+        })
+        .collect();
+
+    let norm = values.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        values
+    } else {
+        values.into_iter().map(|v| v / norm).collect()
+    }
+}
+
+/// 处理一个连接（每个连接只处理一个请求，响应后关闭）
+async fn handle_connection(
+    mut stream: TcpStream,
+    state: Arc<Mutex<MockState>>,
+) -> std::io::Result<()> {
+    let (path, body) = match read_request(&mut stream).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    state.lock().unwrap().requests.push(RecordedRequest {
+        path: path.clone(),
+        body: body.clone(),
+    });
+
+    let response = if path.ends_with("/chat/completions") {
+        let (reply, call_id) = {
+            let mut state = state.lock().unwrap();
+            let reply = state
+                .replies
+                .pop_front()
+                .unwrap_or_else(|| MockReply::text(DEFAULT_REPLY));
+            state.next_call_id += 1;
+            (reply, state.next_call_id)
+        };
+        chat_response(&reply, &body, call_id)
+    } else if path.ends_with("/embeddings") {
+        let dim = state.lock().unwrap().embedding_dim;
+        embeddings_response(&body, dim)
+    } else if path.ends_with("/rerank") {
+        rerank_response(&body)
+    } else if path.ends_with("/mcp") {
+        mcp_response(&body, &state).await
+    } else {
+        http_response(404, "application/json", r#"{"error":"not found"}"#)
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// 读取一个 HTTP 请求，返回 (路径, 请求体)
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<(String, Vec<u8>)>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+    let path = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/")
+        .to_string();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let mut body = buffer[header_end..].to_vec();
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+
+    Ok(Some((path, body)))
+}
+
+/// 生成 `/chat/completions` 响应
+fn chat_response(reply: &MockReply, request: &Value, call_id: usize) -> String {
+    let model = request["model"].as_str().unwrap_or("mock-model");
+    let stream = request["stream"].as_bool().unwrap_or(false);
+
+    let (content, tool_calls) = match reply {
+        MockReply::Error(status, body) => {
+            return http_response(*status, "application/json", body);
+        }
+        MockReply::Text(text) => (Some(text.as_str()), Vec::new()),
+        MockReply::ToolCalls(calls) => {
+            let tool_calls: Vec<Value> = calls
+                .iter()
+                .enumerate()
+                .map(|(index, (name, arguments))| {
+                    json!({
+                        "id": format!("call_{}_{}", call_id, index),
+                        "type": "function",
+                        "function": { "name": name, "arguments": arguments.to_string() }
+                    })
+                })
+                .collect();
+            (None, tool_calls)
+        }
+    };
+
+    let completion_tokens = content.map(|c| c.chars().count() as u32).unwrap_or(1);
+    let usage = json!({
+        "prompt_tokens": MOCK_PROMPT_TOKENS,
+        "completion_tokens": completion_tokens,
+        "total_tokens": MOCK_PROMPT_TOKENS + completion_tokens,
+    });
+    let finish_reason = if tool_calls.is_empty() {
+        "stop"
+    } else {
+        "tool_calls"
+    };
+
+    if !stream {
+        let mut message = json!({ "role": "assistant", "content": content });
+        if !tool_calls.is_empty() {
+            message["tool_calls"] = json!(tool_calls);
+        }
+        let body = json!({
+            "id": format!("chatcmpl-{}", call_id),
+            "object": "chat.completion",
+            "model": model,
+            "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+            "usage": usage,
+        });
+        return http_response(200, "application/json", &body.to_string());
+    }
+
+    // 流式响应：文本每两个字符一个分片，工具参数拆成两个分片
+    let mut chunks = vec![json!({ "choices": [{ "index": 0, "delta": { "role": "assistant" } }] })];
+    if let Some(content) = content {
+        let chars: Vec<char> = content.chars().collect();
+        for piece in chars.chunks(2) {
+            let piece: String = piece.iter().collect();
+            chunks.push(json!({ "choices": [{ "index": 0, "delta": { "content": piece } }] }));
+        }
+    }
+    for (index, call) in tool_calls.iter().enumerate() {
+        let arguments = call["function"]["arguments"].as_str().unwrap_or_default();
+        let split = arguments
+            .char_indices()
+            .nth(arguments.chars().count() / 2)
+            .map(|(pos, _)| pos)
+            .unwrap_or(0);
+        chunks.push(json!({ "choices": [{ "index": 0, "delta": { "tool_calls": [{
+            "index": index,
+            "id": call["id"],
+            "type": "function",
+            "function": { "name": call["function"]["name"], "arguments": &arguments[..split] }
+        }] } }] }));
+        chunks.push(json!({ "choices": [{ "index": 0, "delta": { "tool_calls": [{
+            "index": index,
+            "function": { "arguments": &arguments[split..] }
+        }] } }] }));
+    }
+    chunks.push(json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": finish_reason }] }));
+    chunks.push(json!({ "choices": [], "usage": usage }));
+
+    let mut body = String::new();
+    for chunk in chunks {
+        body.push_str(&format!("data: {}\n\n", chunk));
+    }
+    body.push_str("data: [DONE]\n\n");
+
+    http_response(200, "text/event-stream", &body)
+}
+
+/// 生成 `/embeddings` 响应，支持字符串或字符串数组输入
+fn embeddings_response(request: &Value, dim: usize) -> String {
+    let inputs: Vec<String> = match &request["input"] {
+        Value::Array(items) => items
+            .iter()
+            .map(|item| item.as_str().unwrap_or_default().to_string())
+            .collect(),
+        other => vec![other.as_str().unwrap_or_default().to_string()],
+    };
+
+    let data: Vec<Value> = inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            json!({ "object": "embedding", "index": index, "embedding": mock_embedding(input, dim) })
+        })
+        .collect();
+
+    let body = json!({
+        "object": "list",
+        "model": request["model"],
+        "data": data,
+        "usage": { "prompt_tokens": 0, "total_tokens": 0 },
+    });
+    http_response(200, "application/json", &body.to_string())
+}
+
+/// 模拟的相关度：查询中的字符在文档里出现的比例
+pub fn mock_rerank_score(query: &str, document: &str) -> f64 {
+    let chars: Vec<char> = query.chars().filter(|c| !c.is_whitespace()).collect();
+    if chars.is_empty() {
+        return 0.0;
+    }
+    let hits = chars.iter().filter(|c| document.contains(**c)).count();
+    hits as f64 / chars.len() as f64
+}
+
+/// 生成 `/rerank` 响应（Jina / 硅基流动格式），按相关度从高到低排列
+fn rerank_response(request: &Value) -> String {
+    let query = request["query"].as_str().unwrap_or_default();
+    let documents: Vec<&str> = request["documents"]
+        .as_array()
+        .map(|docs| docs.iter().map(|d| d.as_str().unwrap_or_default()).collect())
+        .unwrap_or_default();
+
+    let mut results: Vec<(usize, f64)> = documents
+        .iter()
+        .enumerate()
+        .map(|(index, doc)| (index, mock_rerank_score(query, doc)))
+        .collect();
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    if let Some(top_n) = request["top_n"].as_u64() {
+        results.truncate(top_n as usize);
+    }
+
+    let results: Vec<Value> = results
+        .into_iter()
+        .map(|(index, score)| json!({ "index": index, "relevance_score": score }))
+        .collect();
+    let body = json!({ "model": request["model"], "results": results });
+    http_response(200, "application/json", &body.to_string())
+}
+
+/// 处理 MCP JSON-RPC 请求
+///
+/// `sleep` 工具等待 `ms` 毫秒后返回 `label`，并记录同时执行的调用数
+async fn mcp_response(request: &Value, state: &Arc<Mutex<MockState>>) -> String {
+    // 通知不需要响应
+    let Some(id) = request.get("id").cloned() else {
+        return http_response(202, "application/json", "");
+    };
+
+    let result = match request["method"].as_str().unwrap_or_default() {
+        "initialize" => json!({
+            "protocolVersion": "2024-11-05",
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": "mock-mcp", "version": "0.1.0" }
+        }),
+        "tools/list" => json!({
+            "tools": [{
+                "name": "sleep",
+                "description": "等待指定毫秒数后返回标签",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": { "label": { "type": "string" }, "ms": { "type": "integer" } },
+                    "required": ["label", "ms"]
+                }
+            }]
+        }),
+        "tools/call" => {
+            let arguments = &request["params"]["arguments"];
+            {
+                let mut state = state.lock().unwrap();
+                state.tool_calls_in_flight += 1;
+                state.max_tool_calls_in_flight =
+                    state.max_tool_calls_in_flight.max(state.tool_calls_in_flight);
+            }
+            let ms = arguments["ms"].as_u64().unwrap_or(0);
+            tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+            state.lock().unwrap().tool_calls_in_flight -= 1;
+
+            let label = arguments["label"].as_str().unwrap_or_default();
+            json!({ "content": [{ "type": "text", "text": label }], "isError": false })
+        }
+        method => {
+            let body = json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": format!("未知方法 {}", method) }
+            });
+            return http_response(200, "application/json", &body.to_string());
+        }
+    };
+
+    let body = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+    http_response(200, "application/json", &body.to_string())
+}
+
+/// 生成完整的 HTTP 响应报文
+fn http_response(status: u16, content_type: &str, body: &str) -> String {
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_embedding_is_deterministic() {
+        let a = mock_embedding("你好", 16);
+        assert_eq!(a.len(), 16);
+        assert_eq!(a, mock_embedding("你好", 16));
+        assert_ne!(a, mock_embedding("再见", 16));
+
+        let norm: f32 = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_path_returns_404() {
+        let server = MockLlmServer::start().await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/unknown", server.url()))
+            .json(&json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(server.requests()[0].path, "/v1/unknown");
+    }
+}
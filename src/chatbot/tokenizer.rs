@@ -0,0 +1,375 @@
+//! Token 计数
+//!
+//! 配置了 tiktoken 词表时按 BPE 精确计数，否则按字符类型估算，
+//! 用于短期记忆截断、长期记忆注入和记忆的 token 统计
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::chatbot::config::{TokenizerConfig, TokenizerEncoding};
+
+/// Token 计数器
+///
+/// 用于长期记忆的 `token_count`、记忆注入时的 `max_memory_tokens` 截断以及短期记忆的长度统计
+pub trait TokenCounter: Send + Sync {
+    /// 统计文本的 token 数
+    fn count(&self, text: &str) -> usize;
+}
+
+/// 启发式 token 估算
+///
+/// 中日韩字符（含全角标点）按每字 1 个 token 计算，其余字符按每 4 个字符 1 个 token 计算。
+/// 未配置 BPE 词表或词表加载失败时使用
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicCounter;
+
+impl TokenCounter for HeuristicCounter {
+    fn count(&self, text: &str) -> usize {
+        let mut cjk = 0;
+        let mut other = 0;
+        for c in text.chars() {
+            if is_cjk(c) {
+                cjk += 1;
+            } else {
+                other += 1;
+            }
+        }
+        cjk + other.div_ceil(4)
+    }
+}
+
+/// 判断是否为中日韩字符或全角标点
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3000..=0x303F     // CJK 标点
+            | 0x3040..=0x30FF   // 平假名、片假名
+            | 0x3400..=0x4DBF   // CJK 扩展 A
+            | 0x4E00..=0x9FFF   // CJK 基本汉字
+            | 0xAC00..=0xD7AF   // 韩文音节
+            | 0xF900..=0xFAFF   // CJK 兼容汉字
+            | 0xFF00..=0xFFEF   // 全角字符
+            | 0x20000..=0x2FFFF // CJK 扩展 B 及以后
+    )
+}
+
+/// tiktoken 风格的 BPE 计数器
+///
+/// 从本地 `.tiktoken` 词表文件（每行 `base64(token) rank`）加载，
+/// 预分词规则按 cl100k_base / o200k_base 的正则手工实现
+pub struct BpeCounter {
+    ranks: HashMap<Vec<u8>, u32>,
+    encoding: TokenizerEncoding,
+}
+
+impl BpeCounter {
+    /// 从词表文件加载
+    pub fn from_file<P: AsRef<Path>>(path: P, encoding: TokenizerEncoding) -> Result<Self> {
+        let content = std::fs::read_to_string(path.as_ref())?;
+        Self::from_tiktoken(&content, encoding)
+    }
+
+    /// 从 `.tiktoken` 格式的文本解析词表
+    pub fn from_tiktoken(content: &str, encoding: TokenizerEncoding) -> Result<Self> {
+        let mut ranks = HashMap::new();
+        for (line_no, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (token, rank) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("词表第 {} 行格式错误", line_no + 1))?;
+            let token = BASE64
+                .decode(token)
+                .map_err(|e| anyhow!("词表第 {} 行 base64 解码失败: {}", line_no + 1, e))?;
+            let rank: u32 = rank
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("词表第 {} 行 rank 解析失败: {}", line_no + 1, e))?;
+            ranks.insert(token, rank);
+        }
+
+        if ranks.is_empty() {
+            return Err(anyhow!("词表为空"));
+        }
+
+        Ok(Self { ranks, encoding })
+    }
+
+    /// 词表大小
+    pub fn vocab_size(&self) -> usize {
+        self.ranks.len()
+    }
+
+    /// 对单个预分词片段做 byte-pair 合并，返回 token 数
+    fn bpe_len(&self, piece: &[u8]) -> usize {
+        if piece.len() <= 1 || self.ranks.contains_key(piece) {
+            return piece.len().min(1);
+        }
+
+        // parts[i]..parts[i+1] 为当前的一个 token，每次合并 rank 最小的相邻对
+        let mut parts: Vec<usize> = (0..=piece.len()).collect();
+        while parts.len() > 2 {
+            let mut best: Option<(u32, usize)> = None;
+            for i in 0..parts.len() - 2 {
+                if let Some(&rank) = self.ranks.get(&piece[parts[i]..parts[i + 2]]) {
+                    if best.map(|(r, _)| rank < r).unwrap_or(true) {
+                        best = Some((rank, i));
+                    }
+                }
+            }
+            match best {
+                Some((_, i)) => {
+                    parts.remove(i + 1);
+                }
+                None => break,
+            }
+        }
+        parts.len() - 1
+    }
+}
+
+impl TokenCounter for BpeCounter {
+    fn count(&self, text: &str) -> usize {
+        pre_tokenize(text, self.encoding)
+            .into_iter()
+            .map(|piece| self.bpe_len(piece.as_bytes()))
+            .sum()
+    }
+}
+
+/// 按编码创建 token 计数器
+///
+/// 词表路径相对于 config.json 所在目录；未配置或加载失败时降级为启发式估算
+pub fn create_token_counter(
+    config: &TokenizerConfig,
+    config_dir: Option<&Path>,
+) -> Arc<dyn TokenCounter> {
+    if config.path.is_empty() {
+        log::info!("⏸️  未配置 BPE 词表，使用启发式 token 估算");
+        return Arc::new(HeuristicCounter);
+    }
+
+    let path = match config_dir {
+        Some(dir) => dir.join(&config.path),
+        None => PathBuf::from(&config.path),
+    };
+
+    match BpeCounter::from_file(&path, config.encoding) {
+        Ok(counter) => {
+            log::info!(
+                "✅ 已加载 BPE 词表: {:?}（{} 个 token）",
+                path,
+                counter.vocab_size()
+            );
+            Arc::new(counter)
+        }
+        Err(e) => {
+            log::error!("❌ 加载 BPE 词表失败 {:?}: {}", path, e);
+            log::warn!("   将使用启发式 token 估算");
+            Arc::new(HeuristicCounter)
+        }
+    }
+}
+
+fn is_letter(c: char) -> bool {
+    c.is_alphabetic()
+}
+
+fn is_number(c: char) -> bool {
+    c.is_numeric()
+}
+
+fn is_newline(c: char) -> bool {
+    c == '\r' || c == '\n'
+}
+
+/// 既不是空白也不是字母数字的字符（标点、符号）
+fn is_symbol(c: char) -> bool {
+    !c.is_whitespace() && !is_letter(c) && !is_number(c)
+}
+
+/// 匹配英文缩写后缀（'s、't、're、've、'm、'll、'd，不区分大小写），返回匹配的字符数
+fn match_contraction(chars: &[char], i: usize) -> usize {
+    if chars.get(i) != Some(&'\'') {
+        return 0;
+    }
+    let lower = |j: usize| chars.get(j).map(|c| c.to_ascii_lowercase());
+    match (lower(i + 1), lower(i + 2)) {
+        (Some('r'), Some('e')) | (Some('v'), Some('e')) | (Some('l'), Some('l')) => 3,
+        (Some('s' | 't' | 'm' | 'd'), _) => 2,
+        _ => 0,
+    }
+}
+
+/// 从 `i` 开始匹配一个单词（cl100k：连续字母；o200k：大写段 + 小写段 + 可选缩写），返回结束位置
+fn match_letters(chars: &[char], i: usize, encoding: TokenizerEncoding) -> usize {
+    let mut j = i;
+    match encoding {
+        TokenizerEncoding::Cl100kBase => {
+            while j < chars.len() && is_letter(chars[j]) {
+                j += 1;
+            }
+        }
+        TokenizerEncoding::O200kBase => {
+            // 没有大小写的文字（如汉字）同时属于两段
+            while j < chars.len() && is_letter(chars[j]) && !chars[j].is_lowercase() {
+                j += 1;
+            }
+            while j < chars.len() && is_letter(chars[j]) && !chars[j].is_uppercase() {
+                j += 1;
+            }
+            j += match_contraction(chars, j);
+        }
+    }
+    j
+}
+
+/// 预分词：手工实现 tiktoken 的分词正则
+///
+/// cl100k_base:
+/// `'s|'t|'re|'ve|'m|'ll|'d|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`
+///
+/// o200k_base 在此基础上按大小写切分单词、缩写跟随单词，标点后可带 `/`
+fn pre_tokenize(text: &str, encoding: TokenizerEncoding) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pieces = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        let end = if encoding == TokenizerEncoding::Cl100kBase && match_contraction(&chars, i) > 0 {
+            i + match_contraction(&chars, i)
+        } else if is_letter(c) {
+            match_letters(&chars, i, encoding)
+        } else if !is_newline(c) && !is_number(c) && next.is_some_and(is_letter) {
+            // 单个前缀字符（空格、标点）+ 单词
+            match_letters(&chars, i + 1, encoding)
+        } else if is_number(c) {
+            let mut j = i;
+            while j < chars.len() && j - i < 3 && is_number(chars[j]) {
+                j += 1;
+            }
+            j
+        } else if is_symbol(c) || (c == ' ' && next.is_some_and(is_symbol)) {
+            let mut j = if c == ' ' { i + 1 } else { i };
+            while j < chars.len() && is_symbol(chars[j]) {
+                j += 1;
+            }
+            while j < chars.len()
+                && (is_newline(chars[j])
+                    || (encoding == TokenizerEncoding::O200kBase && chars[j] == '/'))
+            {
+                j += 1;
+            }
+            j
+        } else {
+            // 空白
+            let mut k = i;
+            while k < chars.len() && chars[k].is_whitespace() {
+                k += 1;
+            }
+            if let Some(p) = (i..k).rev().find(|&p| is_newline(chars[p])) {
+                // \s*[\r\n]+
+                p + 1
+            } else if k == chars.len() || k - i == 1 {
+                // \s+ 到结尾，或单个空白
+                k
+            } else {
+                // \s+(?!\S)：留下最后一个空白给后面的单词
+                k - 1
+            }
+        };
+
+        pieces.push(chars[i..end].iter().collect());
+        i = end;
+    }
+
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 构造一个只包含单字节和少量合并的迷你词表
+    fn tiny_vocab() -> String {
+        let mut lines: Vec<String> = (0u32..256)
+            .map(|b| format!("{} {}", BASE64.encode([b as u8]), b))
+            .collect();
+        for (rank, token) in [
+            "he", "ll", "hell", "hello", " w", " wo", "or", " wor", " world",
+        ]
+        .iter()
+        .enumerate()
+        {
+            lines.push(format!("{} {}", BASE64.encode(token), 256 + rank));
+        }
+        lines.join("\n")
+    }
+
+    #[test]
+    fn test_heuristic_counter() {
+        let counter = HeuristicCounter;
+        assert_eq!(counter.count(""), 0);
+        assert_eq!(counter.count("你好，世界"), 5);
+        assert_eq!(counter.count("hello world"), 3);
+        assert_eq!(counter.count("小诗 hi"), 3);
+        // 中文不再被当成字节数 / 4
+        assert!(counter.count("今天天气怎么样") > "今天天气怎么样".len() / 4);
+    }
+
+    #[test]
+    fn test_pre_tokenize_cl100k() {
+        let pieces = pre_tokenize(
+            "Hello world, it's 12345!\n\n  ok",
+            TokenizerEncoding::Cl100kBase,
+        );
+        assert_eq!(
+            pieces,
+            vec!["Hello", " world", ",", " it", "'s", " ", "123", "45", "!\n\n", " ", " ok"]
+        );
+    }
+
+    #[test]
+    fn test_pre_tokenize_o200k() {
+        let pieces = pre_tokenize("HelloWorld it's", TokenizerEncoding::O200kBase);
+        assert_eq!(pieces, vec!["Hello", "World", " it's"]);
+    }
+
+    #[test]
+    fn test_bpe_counter() {
+        let counter =
+            BpeCounter::from_tiktoken(&tiny_vocab(), TokenizerEncoding::Cl100kBase).unwrap();
+        assert_eq!(counter.vocab_size(), 265);
+        assert_eq!(counter.count("hello"), 1);
+        assert_eq!(counter.count("hello world"), 2);
+        // "help" 只能合并出 "he" + "l" + "p"
+        assert_eq!(counter.count("help"), 3);
+        // 汉字按 UTF-8 字节计数（迷你词表没有合并规则）
+        assert_eq!(counter.count("你"), 3);
+    }
+
+    #[test]
+    fn test_bpe_counter_invalid_vocab() {
+        assert!(BpeCounter::from_tiktoken("", TokenizerEncoding::Cl100kBase).is_err());
+        assert!(BpeCounter::from_tiktoken("not-a-line", TokenizerEncoding::Cl100kBase).is_err());
+    }
+
+    #[test]
+    fn test_create_token_counter_fallback() {
+        let config = TokenizerConfig {
+            encoding: TokenizerEncoding::Cl100kBase,
+            path: "no/such/cl100k_base.tiktoken".to_string(),
+        };
+        let counter = create_token_counter(&config, None);
+        assert_eq!(counter.count("你好"), HeuristicCounter.count("你好"));
+    }
+}
@@ -0,0 +1,297 @@
+//! Token 用量统计与预算控制
+//!
+//! 启用 RAG 时用量持久化到 PostgreSQL 的 token_usage 表，
+//! 否则退化为进程内计数（重启后清零）
+
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::chatbot::config::BudgetConfig;
+use crate::chatbot::llm::TokenUsage;
+use crate::chatbot::rag::TemporalMemory;
+
+/// 预算检查结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetStatus {
+    /// 额度充足
+    Available,
+    /// 超出额度
+    Exceeded {
+        /// 超额的统计范围，如 "群 123456"、"用户 10001"
+        scope: String,
+        /// 统计周期："今日" 或 "本月"
+        period: &'static str,
+        used: u64,
+        limit: u64,
+    },
+}
+
+/// 进程内用量计数器（按自然日 / 自然月滚动）
+#[derive(Debug, Clone)]
+struct UsageCounter {
+    day: NaiveDate,
+    day_tokens: u64,
+    month: (i32, u32),
+    month_tokens: u64,
+}
+
+impl UsageCounter {
+    fn new(today: NaiveDate) -> Self {
+        Self {
+            day: today,
+            day_tokens: 0,
+            month: (today.year(), today.month()),
+            month_tokens: 0,
+        }
+    }
+
+    /// 跨天 / 跨月时清零对应计数
+    fn roll(&mut self, today: NaiveDate) {
+        if self.day != today {
+            self.day = today;
+            self.day_tokens = 0;
+        }
+        let month = (today.year(), today.month());
+        if self.month != month {
+            self.month = month;
+            self.month_tokens = 0;
+        }
+    }
+
+    fn add(&mut self, today: NaiveDate, tokens: u64) {
+        self.roll(today);
+        self.day_tokens += tokens;
+        self.month_tokens += tokens;
+    }
+}
+
+/// 用量统计器
+pub struct UsageTracker {
+    long_term_memory: Option<Arc<TemporalMemory>>,
+    counters: Mutex<HashMap<String, UsageCounter>>,
+}
+
+impl UsageTracker {
+    /// 创建用量统计器
+    ///
+    /// # 参数
+    /// - `long_term_memory`: RAG 记忆系统，存在时用量写入数据库
+    pub fn new(long_term_memory: Option<Arc<TemporalMemory>>) -> Self {
+        Self {
+            long_term_memory,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 创建绑定新 RAG 记忆系统的统计器，保留当前的进程内计数（配置热重载时使用）
+    ///
+    /// # 参数
+    /// - `long_term_memory`: 新的 RAG 记忆系统
+    pub fn rebind(&self, long_term_memory: Option<Arc<TemporalMemory>>) -> Self {
+        Self {
+            long_term_memory,
+            counters: Mutex::new(self.counters.lock().unwrap().clone()),
+        }
+    }
+
+    fn group_key(group_id: i64) -> String {
+        format!("group:{}", group_id)
+    }
+
+    fn user_key(user_id: i64) -> String {
+        format!("user:{}", user_id)
+    }
+
+    /// 记录一次对话的用量
+    pub async fn record(
+        &self,
+        user_id: i64,
+        group_id: Option<i64>,
+        model: &str,
+        usage: &TokenUsage,
+    ) -> Result<()> {
+        let today = Local::now().date_naive();
+        let tokens = usage.total_tokens as u64;
+        {
+            let mut counters = self.counters.lock().unwrap();
+            let mut keys = vec![Self::user_key(user_id)];
+            if let Some(gid) = group_id {
+                keys.push(Self::group_key(gid));
+            }
+            for key in keys {
+                counters
+                    .entry(key)
+                    .or_insert_with(|| UsageCounter::new(today))
+                    .add(today, tokens);
+            }
+        }
+
+        if let Some(rag) = &self.long_term_memory {
+            rag.record_token_usage(user_id, group_id, model, usage).await?;
+        }
+        Ok(())
+    }
+
+    /// 检查当前会话是否超出预算
+    pub async fn check_budget(
+        &self,
+        budget: &BudgetConfig,
+        user_id: i64,
+        group_id: Option<i64>,
+    ) -> Result<BudgetStatus> {
+        if !budget.enabled {
+            return Ok(BudgetStatus::Available);
+        }
+
+        let mut checks = Vec::new();
+        if let Some(gid) = group_id {
+            checks.push((
+                format!("群 {}", gid),
+                Some(gid),
+                budget.group_daily_tokens,
+                budget.group_monthly_tokens,
+            ));
+        }
+        checks.push((
+            format!("用户 {}", user_id),
+            None,
+            budget.user_daily_tokens,
+            budget.user_monthly_tokens,
+        ));
+
+        for (scope, scope_group, daily_limit, monthly_limit) in checks {
+            if daily_limit.is_none() && monthly_limit.is_none() {
+                continue;
+            }
+
+            let (daily_used, monthly_used) = self.usage_of(user_id, scope_group).await?;
+
+            if let Some(limit) = daily_limit {
+                if daily_used >= limit {
+                    return Ok(BudgetStatus::Exceeded { scope, period: "今日", used: daily_used, limit });
+                }
+            }
+            if let Some(limit) = monthly_limit {
+                if monthly_used >= limit {
+                    return Ok(BudgetStatus::Exceeded { scope, period: "本月", used: monthly_used, limit });
+                }
+            }
+        }
+
+        Ok(BudgetStatus::Available)
+    }
+
+    /// 查询 (今日用量, 本月用量)：传入 group_id 时按群统计，否则按用户统计
+    pub async fn usage_of(&self, user_id: i64, group_id: Option<i64>) -> Result<(u64, u64)> {
+        if let Some(rag) = &self.long_term_memory {
+            let (day_start, month_start) = Self::period_starts(Local::now());
+            let daily = rag.sum_token_usage(user_id, group_id, day_start).await?;
+            let monthly = rag.sum_token_usage(user_id, group_id, month_start).await?;
+            return Ok((daily, monthly));
+        }
+
+        let key = match group_id {
+            Some(gid) => Self::group_key(gid),
+            None => Self::user_key(user_id),
+        };
+        let today = Local::now().date_naive();
+        let mut counters = self.counters.lock().unwrap();
+        Ok(match counters.get_mut(&key) {
+            Some(counter) => {
+                counter.roll(today);
+                (counter.day_tokens, counter.month_tokens)
+            }
+            None => (0, 0),
+        })
+    }
+
+    /// 计算本地时间下今日零点与本月一日零点（转换为 UTC）
+    fn period_starts(now: DateTime<Local>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.date_naive();
+        let month_first = today.with_day(1).unwrap_or(today);
+        let to_utc = |date: NaiveDate| {
+            let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+            Local
+                .from_local_datetime(&midnight)
+                .earliest()
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|| Utc.from_utc_datetime(&midnight))
+        };
+        (to_utc(today), to_utc(month_first))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(total: u32) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: total,
+            completion_tokens: 0,
+            total_tokens: total,
+            reasoning_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn test_usage_counter_rollover() {
+        let day1 = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+        let day2 = NaiveDate::from_ymd_opt(2024, 2, 1).unwrap();
+
+        let mut counter = UsageCounter::new(day1);
+        counter.add(day1, 100);
+        assert_eq!((counter.day_tokens, counter.month_tokens), (100, 100));
+
+        counter.add(day2, 30);
+        assert_eq!((counter.day_tokens, counter.month_tokens), (30, 30));
+    }
+
+    #[tokio::test]
+    async fn test_budget_in_memory() {
+        let tracker = UsageTracker::new(None);
+        let budget = BudgetConfig {
+            enabled: true,
+            group_daily_tokens: Some(150),
+            user_monthly_tokens: Some(1000),
+            ..BudgetConfig::default()
+        };
+
+        tracker.record(1, Some(100), "m", &usage(80)).await.unwrap();
+        assert_eq!(
+            tracker.check_budget(&budget, 2, Some(100)).await.unwrap(),
+            BudgetStatus::Available
+        );
+
+        tracker.record(2, Some(100), "m", &usage(80)).await.unwrap();
+        match tracker.check_budget(&budget, 3, Some(100)).await.unwrap() {
+            BudgetStatus::Exceeded { period, used, limit, .. } => {
+                assert_eq!(period, "今日");
+                assert_eq!((used, limit), (160, 150));
+            }
+            BudgetStatus::Available => panic!("群额度应已用完"),
+        }
+
+        // 其他群和私聊不受影响
+        assert_eq!(
+            tracker.check_budget(&budget, 3, Some(200)).await.unwrap(),
+            BudgetStatus::Available
+        );
+        assert_eq!(tracker.usage_of(1, None).await.unwrap(), (80, 80));
+    }
+
+    #[tokio::test]
+    async fn test_budget_disabled() {
+        let tracker = UsageTracker::new(None);
+        let budget = BudgetConfig {
+            group_daily_tokens: Some(0),
+            ..BudgetConfig::default()
+        };
+        assert_eq!(
+            tracker.check_budget(&budget, 1, Some(1)).await.unwrap(),
+            BudgetStatus::Available
+        );
+    }
+}
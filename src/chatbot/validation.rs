@@ -0,0 +1,512 @@
+//! 配置校验
+//!
+//! 启动和热重载前检查配置，一次列出所有问题及其在 config.json 中的位置，
+//! 避免带着空密钥、越界参数启动后才在对话中报错
+
+use reqwest::Url;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::chatbot::config::{Config, LlmConfig};
+
+/// 问题的严重程度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueLevel {
+    /// 无法正常运行，拒绝启动
+    Error,
+    /// 可以运行，但可能不是预期的行为
+    Warning,
+}
+
+/// 单个配置问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub level: IssueLevel,
+    /// 问题所在的 JSON 路径，如 `llm.fallbacks[0].url`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// 配置校验结果
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl ValidationReport {
+    /// 是否存在致命错误
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    /// 所有致命错误
+    pub fn errors(&self) -> impl Iterator<Item = &ConfigIssue> {
+        self.issues.iter().filter(|i| i.level == IssueLevel::Error)
+    }
+
+    /// 所有警告
+    pub fn warnings(&self) -> impl Iterator<Item = &ConfigIssue> {
+        self.issues.iter().filter(|i| i.level == IssueLevel::Warning)
+    }
+
+    /// 把所有致命错误合并为一段文本（每行一个）
+    pub fn error_summary(&self) -> String {
+        self.errors()
+            .map(|issue| format!("  - {}", issue))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(IssueLevel::Error, path.into(), message.into());
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(IssueLevel::Warning, path.into(), message.into());
+    }
+
+    fn push(&mut self, level: IssueLevel, path: String, message: String) {
+        self.issues.push(ConfigIssue {
+            level,
+            path,
+            message,
+        });
+    }
+
+    /// 检查 API 地址：必须是 http(s) URL
+    fn check_url(&mut self, path: &str, url: &str) {
+        if url.trim().is_empty() {
+            self.error(path, "未填写 API 地址");
+            return;
+        }
+        match Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+            Ok(parsed) => self.error(
+                path,
+                format!("不支持的协议 `{}`，应以 http:// 或 https:// 开头", parsed.scheme()),
+            ),
+            Err(e) => self.error(path, format!("不是有效的 URL ({})", e)),
+        }
+    }
+
+    /// 检查 API Key：本地服务允许为空，其余为空时报错
+    fn check_apikey(&mut self, path: &str, apikey: &str, url: &str) {
+        if !apikey.trim().is_empty() {
+            return;
+        }
+        if is_local_url(url) {
+            self.warning(path, "未填写 API Key（本地服务可忽略）");
+        } else {
+            self.error(path, "未填写 API Key");
+        }
+    }
+
+    fn check_not_empty(&mut self, path: &str, value: &str, what: &str) {
+        if value.trim().is_empty() {
+            self.error(path, format!("未填写{}", what));
+        }
+    }
+
+    fn check_range(&mut self, path: &str, value: Option<f64>, min: f64, max: f64) {
+        if let Some(v) = value {
+            if !(min..=max).contains(&v) {
+                self.error(path, format!("取值 {} 超出范围 [{}, {}]", v, min, max));
+            }
+        }
+    }
+
+    /// 检查一个对话模型端点（model / url / apikey / 采样参数）
+    fn check_endpoint(
+        &mut self,
+        prefix: &str,
+        model: &str,
+        url: &str,
+        apikey: &str,
+        temperature: Option<f64>,
+        top_p: Option<f64>,
+    ) {
+        self.check_not_empty(&format!("{}.model", prefix), model, "模型名称");
+        self.check_url(&format!("{}.url", prefix), url);
+        self.check_apikey(&format!("{}.apikey", prefix), apikey, url);
+        self.check_range(&format!("{}.temperature", prefix), temperature, 0.0, 2.0);
+        self.check_range(&format!("{}.top_p", prefix), top_p, 0.0, 1.0);
+    }
+
+    fn check_llm(&mut self, prefix: &str, llm: &LlmConfig) {
+        self.check_endpoint(
+            prefix,
+            &llm.model,
+            &llm.url,
+            &llm.apikey,
+            llm.temperature,
+            llm.top_p,
+        );
+        self.check_range(
+            &format!("{}.presence_penalty", prefix),
+            llm.presence_penalty,
+            -2.0,
+            2.0,
+        );
+        self.check_range(
+            &format!("{}.frequency_penalty", prefix),
+            llm.frequency_penalty,
+            -2.0,
+            2.0,
+        );
+
+        if llm.context_length == 0 {
+            self.error(format!("{}.context_length", prefix), "必须大于 0");
+        }
+        match llm.max_tokens {
+            Some(0) => self.error(format!("{}.max_tokens", prefix), "必须大于 0"),
+            Some(max_tokens) if max_tokens as usize >= llm.context_length => self.error(
+                format!("{}.max_tokens", prefix),
+                format!("回复上限 {} 不小于上下文长度 {}", max_tokens, llm.context_length),
+            ),
+            _ => {}
+        }
+        if llm.retry.initial_backoff_ms > llm.retry.max_backoff_ms {
+            self.warning(
+                format!("{}.retry.initial_backoff_ms", prefix),
+                "初始退避时间大于 max_backoff_ms",
+            );
+        }
+    }
+}
+
+/// 是否为本机地址（本地部署的模型服务通常不需要 API Key）
+fn is_local_url(url: &str) -> bool {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .is_some_and(|host| matches!(host.as_str(), "localhost" | "127.0.0.1" | "[::1]"))
+}
+
+/// 常见嵌入模型的输出维度
+fn known_embedding_dimension(model: &str) -> Option<usize> {
+    // 兼容 `BAAI/bge-m3` 这类带组织前缀的名称
+    let name = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+    let dimension = match name.as_str() {
+        "text-embedding-3-small" | "text-embedding-ada-002" => 1536,
+        "text-embedding-3-large" => 3072,
+        "embedding-3" => 2048,
+        "bge-base-zh-v1.5" | "nomic-embed-text" => 768,
+        "bge-m3" | "bge-large-zh-v1.5" | "mxbai-embed-large" | "text-embedding-v3"
+        | "jina-embeddings-v3" | "qwen3-embedding-0.6b" => 1024,
+        "qwen3-embedding-4b" => 2560,
+        "qwen3-embedding-8b" => 4096,
+        _ => return None,
+    };
+    Some(dimension)
+}
+
+fn resolve_path(config_dir: Option<&Path>, path: &str) -> PathBuf {
+    match config_dir {
+        Some(dir) => dir.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+impl Config {
+    /// 校验配置，返回发现的所有问题
+    ///
+    /// # 参数
+    /// - `config_dir`: config.json 所在目录，用于检查相对路径的文件是否存在
+    pub fn validate(&self, config_dir: Option<&Path>) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        // 主模型与备用模型
+        report.check_llm("llm", &self.llm);
+        for (i, fallback) in self.llm.fallbacks.iter().enumerate() {
+            report.check_llm(&format!("llm.fallbacks[{}]", i), fallback);
+        }
+
+        // 短期记忆
+        let memory = &self.memory;
+        if memory.history_limit == 0 {
+            report.error("memory.history_limit", "必须大于 0");
+        }
+        if memory.prompt.trim().is_empty() {
+            report.warning("memory.prompt", "系统提示词为空");
+        }
+        if !memory.tokenizer.path.is_empty()
+            && !resolve_path(config_dir, &memory.tokenizer.path).exists()
+        {
+            report.warning(
+                "memory.tokenizer.path",
+                format!("词表文件 `{}` 不存在，将使用估算计数", memory.tokenizer.path),
+            );
+        }
+        if memory.summary.enabled && memory.summary.batch_size == 0 {
+            report.error("memory.summary.batch_size", "必须大于 0");
+        }
+        if memory.persistence.enabled && memory.persistence.path.trim().is_empty() {
+            report.error("memory.persistence.path", "启用持久化时必须填写文件路径");
+        }
+
+        // 长期记忆（RAG）
+        let rag = &memory.rag;
+        if rag.enabled {
+            let embedding = &rag.embedding;
+            report.check_not_empty("memory.rag.embedding.model", &embedding.model, "嵌入模型名称");
+            report.check_url("memory.rag.embedding.url", &embedding.url);
+            report.check_apikey("memory.rag.embedding.apikey", &embedding.apikey, &embedding.url);
+            match (embedding.dimension, known_embedding_dimension(&embedding.model)) {
+                (Some(0), _) => report.error("memory.rag.embedding.dimension", "必须大于 0"),
+                (Some(configured), Some(dimension)) if configured != dimension => report.error(
+                    "memory.rag.embedding.dimension",
+                    format!(
+                        "模型 `{}` 输出 {} 维向量，与配置的 {} 维不一致",
+                        embedding.model, dimension, configured
+                    ),
+                ),
+                _ => {}
+            }
+
+            // 检索与排序
+            report.check_range(
+                "memory.rag.min_similarity",
+                Some(rag.min_similarity as f64),
+                -1.0,
+                1.0,
+            );
+            let ranking = &rag.ranking;
+            for (name, weight) in [
+                ("similarity_weight", ranking.similarity_weight),
+                ("recency_weight", ranking.recency_weight),
+                ("score_weight", ranking.score_weight),
+            ] {
+                if weight < 0.0 {
+                    report.error(format!("memory.rag.ranking.{}", name), "不能为负数");
+                }
+            }
+            if ranking.recency_weight > 0.0 && ranking.recency_half_life_days <= 0.0 {
+                report.error("memory.rag.ranking.recency_half_life_days", "启用新近度时必须大于 0");
+            }
+
+            let rerank = &rag.rerank;
+            if rerank.enabled {
+                report.check_not_empty("memory.rag.rerank.model", &rerank.model, "重排序模型名称");
+                report.check_url("memory.rag.rerank.url", &rerank.url);
+                report.check_apikey("memory.rag.rerank.apikey", &rerank.apikey, &rerank.url);
+                if rerank.candidates < rag.top_n {
+                    report.warning(
+                        "memory.rag.rerank.candidates",
+                        format!("小于 top_n（{}），将按 top_n 取候选", rag.top_n),
+                    );
+                }
+                if rerank.timeout == 0 {
+                    report.error("memory.rag.rerank.timeout", "必须大于 0");
+                }
+            }
+            if rag.mmr_enabled {
+                report.check_range("memory.rag.mmr_lambda", Some(rag.mmr_lambda as f64), 0.0, 1.0);
+            }
+
+            let postgres = &self.db.postgres;
+            report.check_not_empty("db.postgres.host", &postgres.host, "数据库地址");
+            report.check_not_empty("db.postgres.username", &postgres.username, "数据库用户名");
+            report.check_not_empty("db.postgres.database", &postgres.database, "数据库名");
+            if postgres.port.parse::<u16>().is_err() {
+                report.error(
+                    "db.postgres.port",
+                    format!("`{}` 不是有效的端口号", postgres.port),
+                );
+            }
+            if postgres.vector.lists == 0 {
+                report.error("db.postgres.vector.lists", "必须大于 0");
+            }
+
+            if rag.top_n == 0 {
+                report.error("memory.rag.top_n", "必须大于 0");
+            }
+            if rag.max_memory_tokens == 0 {
+                report.warning("memory.rag.max_memory_tokens", "为 0 时不会注入任何长期记忆");
+            }
+
+            let evaluation = &rag.memory_evaluation;
+            if evaluation.enabled {
+                report.check_endpoint(
+                    "memory.rag.memory_evaluation",
+                    &evaluation.model,
+                    &evaluation.url,
+                    &evaluation.apikey,
+                    evaluation.temperature,
+                    evaluation.top_p,
+                );
+            }
+        }
+
+        // MCP
+        if self.mcp.enabled {
+            if self.mcp.path.trim().is_empty() {
+                report.error("mcp.path", "启用 MCP 时必须填写配置文件路径");
+            } else if !resolve_path(config_dir, &self.mcp.path).exists() {
+                report.error(
+                    "mcp.path",
+                    format!("MCP 配置文件 `{}` 不存在", self.mcp.path),
+                );
+            }
+            if self.mcp.max_tool_iterations == 0 {
+                report.warning("mcp.max_tool_iterations", "为 0 时不会调用任何工具");
+            }
+        }
+
+        // 图片理解
+        if self.vision.enabled {
+            report.check_endpoint(
+                "vision",
+                &self.vision.model,
+                &self.vision.url,
+                &self.vision.apikey,
+                None,
+                None,
+            );
+            if self.vision.max_images == 0 {
+                report.warning("vision.max_images", "为 0 时不会处理任何图片");
+            }
+        }
+
+        // Token 预算
+        let budget = &self.budget;
+        if budget.enabled
+            && budget.group_daily_tokens.is_none()
+            && budget.group_monthly_tokens.is_none()
+            && budget.user_daily_tokens.is_none()
+            && budget.user_monthly_tokens.is_none()
+        {
+            report.warning("budget", "已启用但没有设置任何限额");
+        }
+
+        // 群聊共享上下文
+        if !self.group.shared_groups.is_empty() && self.group.buffer_size == 0 {
+            report.warning("group.buffer_size", "为 0 时不会缓冲未 @ 机器人的发言");
+        }
+
+        // 人设
+        if self.persona.name.trim().is_empty() {
+            report.error("persona.name", "名字不能为空");
+        }
+        if self.persona.aliases.iter().any(|a| a.trim().is_empty()) {
+            report.warning("persona.aliases", "包含空的别名，已忽略");
+        }
+
+        // 管理命令
+        if self.admin.prefix.trim().is_empty() {
+            report.error("admin.prefix", "命令前缀不能为空，否则所有消息都会被当作命令");
+        }
+
+        // 按群/用户覆盖
+        for (kind, overrides) in [
+            ("groups", &self.overrides.groups),
+            ("users", &self.overrides.users),
+        ] {
+            let mut ids: Vec<&i64> = overrides.keys().collect();
+            ids.sort();
+            for id in ids {
+                let item = &overrides[id];
+                let prefix = format!("overrides.{}.{}", kind, id);
+                if item.model.as_deref().is_some_and(|m| m.trim().is_empty()) {
+                    report.error(format!("{}.model", prefix), "模型名称不能为空");
+                }
+                report.check_range(&format!("{}.temperature", prefix), item.temperature, 0.0, 2.0);
+                if item.rag_enabled == Some(true) && !rag.enabled {
+                    report.warning(
+                        format!("{}.rag_enabled", prefix),
+                        "全局未启用 RAG，覆盖只能关闭长期记忆",
+                    );
+                }
+                if item.allowed_tools.is_some() && !self.mcp.enabled {
+                    report.warning(format!("{}.allowed_tools", prefix), "MCP 未启用，该项不会生效");
+                }
+            }
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.llm.model = "gpt-4o-mini".to_string();
+        config.llm.url = "https://api.openai.com/v1/chat/completions".to_string();
+        config.llm.apikey = "sk-test".to_string();
+        config.memory.rag.enabled = false;
+        config
+    }
+
+    fn paths(report: &ValidationReport, level: IssueLevel) -> Vec<String> {
+        report
+            .issues
+            .iter()
+            .filter(|i| i.level == level)
+            .map(|i| i.path.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let report = valid_config().validate(None);
+        assert!(!report.has_errors(), "{}", report.error_summary());
+    }
+
+    #[test]
+    fn test_reports_every_problem_with_path() {
+        let mut config = valid_config();
+        config.llm.apikey = String::new();
+        config.llm.temperature = Some(3.0);
+        config.llm.top_p = Some(1.5);
+        let mut fallback = valid_config().llm;
+        fallback.url = "ftp://example.com".to_string();
+        config.llm.fallbacks = vec![fallback];
+        config.mcp.enabled = true;
+        config.mcp.path = "not-exist-mcp.json".to_string();
+
+        let report = config.validate(Some(Path::new("/nonexistent-dir")));
+        assert_eq!(
+            paths(&report, IssueLevel::Error),
+            vec![
+                "llm.apikey",
+                "llm.temperature",
+                "llm.top_p",
+                "llm.fallbacks[0].url",
+                "mcp.path",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_local_url_and_embedding_dimension() {
+        let mut config = valid_config();
+        config.llm.url = "http://localhost:11434/v1/chat/completions".to_string();
+        config.llm.apikey = String::new();
+        config.memory.rag.enabled = true;
+        config.memory.rag.memory_evaluation.enabled = false;
+        config.memory.rag.embedding.model = "text-embedding-3-small".to_string();
+        config.memory.rag.embedding.url = "https://api.openai.com/v1/embeddings".to_string();
+        config.memory.rag.embedding.apikey = "sk-test".to_string();
+
+        config.memory.rag.embedding.dimension = Some(1024);
+
+        let report = config.validate(None);
+        assert_eq!(paths(&report, IssueLevel::Warning), vec!["llm.apikey"]);
+        assert_eq!(paths(&report, IssueLevel::Error), vec!["memory.rag.embedding.dimension"]);
+
+        // 维度与模型一致，或未配置维度（启动时探测）
+        config.memory.rag.embedding.model = "Qwen/Qwen3-Embedding-0.6B".to_string();
+        assert!(!config.validate(None).has_errors());
+        config.memory.rag.embedding.model = "text-embedding-3-small".to_string();
+        config.memory.rag.embedding.dimension = None;
+        assert!(!config.validate(None).has_errors());
+    }
+}
@@ -0,0 +1,274 @@
+//! 图片理解模块
+//!
+//! 负责获取 QQ 图片消息段中的图片、编码为 data URL，
+//! 并调用视觉模型生成回复或图片描述
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::StreamExt;
+use std::sync::Arc;
+
+use crate::chatbot::config::{RetryConfig, VisionConfig};
+use crate::chatbot::llm::{create_backend, LlmBackend, LlmMessage, LlmRequestParams};
+
+/// 图片在记忆中的占位文本
+pub const IMAGE_PLACEHOLDER: &str = "[图片]";
+
+/// 图片处理器
+pub struct VisionProcessor {
+    client: Arc<dyn LlmBackend>,
+    http_client: reqwest::Client,
+    config: VisionConfig,
+}
+
+impl VisionProcessor {
+    /// 创建图片处理器
+    pub fn new(config: VisionConfig) -> Result<Self> {
+        let llm_params = LlmRequestParams {
+            max_tokens: config.max_tokens,
+            ..LlmRequestParams::default()
+        };
+
+        let client = create_backend(
+            config.provider,
+            config.apikey.clone(),
+            config.url.clone(),
+            config.model.clone(),
+            llm_params,
+            RetryConfig::default(),
+        )
+        .map_err(|e| anyhow!("视觉模型初始化失败: {}", e))?;
+
+        Ok(Self {
+            client,
+            http_client: reqwest::Client::new(),
+            config,
+        })
+    }
+
+    /// 获取视觉模型客户端
+    pub fn client(&self) -> Arc<dyn LlmBackend> {
+        self.client.clone()
+    }
+
+    /// 获取图片并编码为 data URL，失败的图片会被跳过
+    ///
+    /// # 参数
+    /// - `sources`: 图片来源（消息段中的 url 或 file 字段）
+    pub async fn prepare_images(&self, sources: &[String]) -> Vec<String> {
+        let mut images = Vec::new();
+        for source in sources.iter().take(self.config.max_images) {
+            match self.fetch_image(source).await {
+                Ok(data_url) => images.push(data_url),
+                Err(e) => log::warn!("⚠️  获取图片失败 ({}): {}", source, e),
+            }
+        }
+        images
+    }
+
+    /// 为图片生成简短描述
+    pub async fn caption(&self, images: &[String]) -> Result<String> {
+        let messages = vec![LlmMessage::user_with_images(
+            &self.config.caption_prompt,
+            images.to_vec(),
+        )];
+
+        let response = self
+            .client
+            .chat_completion(messages, None)
+            .await
+            .map_err(|e| anyhow!("图片描述生成失败: {}", e))?;
+
+        response
+            .content
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .ok_or_else(|| anyhow!("视觉模型没有返回图片描述"))
+    }
+
+    /// 是否需要生成图片描述
+    pub fn caption_enabled(&self) -> bool {
+        self.config.caption
+    }
+
+    /// 读取单张图片
+    ///
+    /// 只支持 http(s) 地址和 `base64://` 内联数据。本地路径不会被读取，
+    /// 避免把机器上的任意文件发送给远程视觉模型
+    async fn fetch_image(&self, source: &str) -> Result<String> {
+        let max_bytes = self.config.max_image_bytes;
+        let bytes = if let Some(data) = source.strip_prefix("base64://") {
+            let data = data.trim();
+            // base64 每 4 个字符解码为 3 个字节，解码前先粗略检查大小
+            if data.len() / 4 * 3 > max_bytes + 2 {
+                return Err(anyhow!("图片过大 (约 {} 字节)", data.len() / 4 * 3));
+            }
+            BASE64.decode(data)?
+        } else if source.starts_with("http://") || source.starts_with("https://") {
+            self.download(source).await?
+        } else {
+            return Err(anyhow!("不支持的图片来源，只接受 http(s) 地址和 base64:// 数据"));
+        };
+
+        if bytes.len() > max_bytes {
+            return Err(anyhow!("图片过大 ({} 字节)", bytes.len()));
+        }
+
+        Ok(encode_data_url(&bytes))
+    }
+
+    /// 下载图片，超过大小上限时立即停止读取
+    ///
+    /// 服务器没有返回 Content-Length 时按块读取并累计大小，不会先把整个响应读入内存
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let max_bytes = self.config.max_image_bytes;
+        let response = self.http_client.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("HTTP {}", response.status()));
+        }
+        if let Some(len) = response.content_length() {
+            if len > max_bytes as u64 {
+                return Err(anyhow!("图片过大 ({} 字节)", len));
+            }
+        }
+
+        let mut bytes = Vec::new();
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            if bytes.len() + chunk.len() > max_bytes {
+                return Err(anyhow!("图片过大 (超过 {} 字节)", max_bytes));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+}
+
+/// 根据文件头判断图片 MIME 类型
+fn sniff_mime(bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(&[0x89, b'P', b'N', b'G']) {
+        "image/png"
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else if bytes.starts_with(b"GIF8") {
+        "image/gif"
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        "image/webp"
+    } else if bytes.starts_with(b"BM") {
+        "image/bmp"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// 将图片编码为 data URL
+fn encode_data_url(bytes: &[u8]) -> String {
+    format!("data:{};base64,{}", sniff_mime(bytes), BASE64.encode(bytes))
+}
+
+/// 生成写入记忆的图片占位文本
+///
+/// # 参数
+/// - `text`: 用户消息文本
+/// - `caption`: 图片描述（可选）
+pub fn memory_text_with_images(text: &str, caption: Option<&str>) -> String {
+    let placeholder = match caption {
+        Some(caption) => format!("[图片: {}]", caption),
+        None => IMAGE_PLACEHOLDER.to_string(),
+    };
+    if text.trim().is_empty() {
+        placeholder
+    } else {
+        format!("{} {}", text.trim(), placeholder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_mime() {
+        assert_eq!(sniff_mime(&[0x89, b'P', b'N', b'G', 0x0D]), "image/png");
+        assert_eq!(sniff_mime(&[0xFF, 0xD8, 0xFF, 0xE0]), "image/jpeg");
+        assert_eq!(sniff_mime(b"GIF89a"), "image/gif");
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff_mime(b"unknown"), "image/jpeg");
+    }
+
+    #[test]
+    fn test_encode_data_url() {
+        let url = encode_data_url(b"GIF89a");
+        assert_eq!(url, "data:image/gif;base64,R0lGODlh");
+    }
+
+    #[test]
+    fn test_memory_text_with_images() {
+        assert_eq!(memory_text_with_images("", None), "[图片]");
+        assert_eq!(memory_text_with_images("看看这个 ", None), "看看这个 [图片]");
+        assert_eq!(
+            memory_text_with_images("可爱吗", Some("一只橘猫")),
+            "可爱吗 [图片: 一只橘猫]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_base64_image() {
+        let processor = VisionProcessor::new(VisionConfig::default()).unwrap();
+        let images = processor
+            .prepare_images(&["base64://R0lGODlh".to_string(), "/no/such/file.png".to_string()])
+            .await;
+        assert_eq!(images, vec!["data:image/gif;base64,R0lGODlh".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_rejects_local_and_oversized() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = VisionConfig {
+            max_image_bytes: 1024,
+            ..VisionConfig::default()
+        };
+        let processor = VisionProcessor::new(config).unwrap();
+
+        // 本地路径和 file:// 不会被读取
+        let file = std::env::temp_dir()
+            .join(format!("xiaoshi-test-{}.gif", uuid::Uuid::new_v4()));
+        std::fs::write(&file, b"GIF89a").unwrap();
+        let path = file.to_string_lossy().to_string();
+        for source in [path.clone(), format!("file://{}", path)] {
+            let err = processor.fetch_image(&source).await.unwrap_err();
+            assert!(err.to_string().contains("不支持的图片来源"), "{}", err);
+        }
+        std::fs::remove_file(&file).unwrap();
+
+        // 没有 Content-Length 的响应按块读取，超过上限即失败
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let header = "HTTP/1.1 200 OK\r\nContent-Type: image/gif\r\nConnection: close\r\n\r\n";
+            let _ = stream.write_all(header.as_bytes()).await;
+            for _ in 0..64 {
+                if stream.write_all(&[0u8; 512]).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let err = processor
+            .fetch_image(&format!("http://{}/big.gif", addr))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("图片过大"), "{}", err);
+
+        let err = processor
+            .fetch_image(&format!("base64://{}", "A".repeat(4096)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("图片过大"), "{}", err);
+    }
+}
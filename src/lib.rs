@@ -3,7 +3,10 @@ mod chatbot;
 use kovi::PluginBuilder as plugin;
 use kovi::MsgEvent;
 use std::sync::Arc;
-use crate::chatbot::{ChatBot, load_config};
+use crate::chatbot::{
+    AdminCommand, ChatBot, SharedChatBot, execute_command, load_config, spawn_config_watcher,
+    spawn_maintenance,
+};
 
 #[kovi::plugin]
 async fn main() {
@@ -11,19 +14,32 @@ async fn main() {
     let data_path = bot.get_data_path();
     let config_json_path = data_path.join("config.json");
 
-    // 加载配置
+    // 加载配置（文件不存在时生成默认配置）
     let config = match load_config(&config_json_path) {
         Ok(cfg) => {
-            kovi::log::info!("✅ 成功加载配置: {:?}, config: {:?}", config_json_path, cfg);
+            kovi::log::info!("✅ 成功加载配置: {:?}", config_json_path);
             cfg
         }
         Err(e) => {
-            kovi::log::error!("❌ 加载配置失败: {}, 使用默认配置", e);
-            chatbot::Config::default()
+            kovi::log::error!("❌ 加载配置失败: {:?}: {}", config_json_path, e);
+            kovi::log::error!("   请修正配置文件后重启插件");
+            return;
         }
     };
 
+    // 校验配置，存在致命错误时拒绝启动
+    let report = config.validate(config_json_path.parent());
+    for warning in report.warnings() {
+        kovi::log::warn!("⚠️  配置警告 {}", warning);
+    }
+    if report.has_errors() {
+        kovi::log::error!("❌ 配置校验失败，插件未启动:\n{}", report.error_summary());
+        return;
+    }
+
     // 初始化聊天机器人
+    let maintenance_config = config.maintenance.clone();
+    let hot_reload_config = config.hot_reload.clone();
     let chatbot = match ChatBot::new(config, &config_json_path).await {
         Ok(service) => {
             let stats = service.get_stats();
@@ -31,7 +47,7 @@ async fn main() {
             kovi::log::info!("   LLM: {}", stats.llm_model);
             kovi::log::info!("   RAG: {}", if stats.rag_enabled { "已启用" } else { "未启用" });
             kovi::log::info!("   MCP: {}", if stats.mcp_enabled { "已启用" } else { "未启用" });
-            Arc::new(service)
+            Arc::new(SharedChatBot::new(service, &config_json_path))
         }
         Err(e) => {
             kovi::log::error!("❌ 聊天机器人初始化失败: {}", e);
@@ -39,23 +55,70 @@ async fn main() {
         }
     };
 
+    // 后台维护：定期清理过期记忆、超时会话并维护向量索引
+    spawn_maintenance(Arc::clone(&chatbot), maintenance_config);
+
+    // 监听 config.json 和 mcp.json，变化后自动重新加载
+    spawn_config_watcher(Arc::clone(&chatbot), hot_reload_config);
+
     // 消息处理
     plugin::on_msg(move |event| {
-        let chatbot = Arc::clone(&chatbot);
+        let shared = Arc::clone(&chatbot);
 
         async move {
-            // 检查消息是否发给机器人
-            if !is_to_me(&event) {
-                return;
-            }
+            // 取出当前生效的实例，处理过程中重载配置不影响本条消息
+            let chatbot = shared.current();
+
+            // 优先使用群名片，其次昵称，最后默认值
+            let sender_name = event
+                .sender.card.clone()
+                .or_else(|| event.sender.nickname.clone())
+                .unwrap_or_else(|| "未知用户".to_string());
 
             // 提取消息文本
-            let text = match event.borrow_text() {
-                Some(t) => t,
-                None => return,
+            let text = event.borrow_text().unwrap_or("");
+
+            // 检查消息是否触发回复（私聊总是回复，群聊按触发规则），
+            // 群聊中的其他发言交给共享上下文缓冲
+            let triggered = match (event.is_group(), event.group_id) {
+                (true, Some(group_id)) => {
+                    chatbot.should_respond(event.sender.user_id, group_id, text, is_to_me(&event))
+                }
+                _ => is_to_me(&event),
             };
+            if !triggered {
+                if let (true, Some(group_id)) = (event.is_group(), event.group_id) {
+                    chatbot.record_group_message(group_id, event.sender.user_id, &sender_name, text);
+                }
+                return;
+            }
 
-            kovi::log::info!("📩 收到消息: {}", text);
+            // 管理命令：不交给模型，直接回复执行结果
+            if let Some(command) = AdminCommand::parse(text, chatbot.command_prefix()) {
+                let user_id = event.sender.user_id;
+                if !chatbot.is_admin(user_id) {
+                    kovi::log::warn!("⛔ 非管理员 {} 尝试执行管理命令: {}", user_id, text);
+                    event.reply("⛔ 只有管理员可以使用管理命令");
+                    return;
+                }
+                kovi::log::info!("🛠️  管理员 {} 执行命令: {}", user_id, text);
+                let group_id = if event.is_group() { event.group_id } else { None };
+                let reply = execute_command(&shared, command, user_id, group_id).await;
+                event.reply(&reply);
+                return;
+            }
+
+            // 提取图片
+            let images = extract_images(&event);
+            if text.trim().is_empty() && images.is_empty() {
+                return;
+            }
+
+            if images.is_empty() {
+                kovi::log::info!("📩 收到消息: {}", text);
+            } else {
+                kovi::log::info!("📩 收到消息: {} (附带 {} 张图片)", text, images.len());
+            }
 
             // 获取用户信息
             let user_id = event.sender.user_id;
@@ -64,15 +127,23 @@ async fn main() {
             } else {
                 None
             };
-            
-            // 优先使用群名片，其次昵称，最后默认值
-            let sender_name = event
-                .sender.card.clone()
-                .or_else(|| event.sender.nickname.clone())
-                .unwrap_or_else(|| "未知用户".to_string());
 
             // 调用聊天机器人
-            match chatbot.chat(user_id, group_id, text, &sender_name).await {
+            if chatbot.is_streaming() {
+                // 流式模式：每生成一句就发送一句
+                let result = chatbot
+                    .chat_stream(user_id, group_id, text, &images, &sender_name, |segment| {
+                        event.reply(segment);
+                    })
+                    .await;
+                if let Err(e) = result {
+                    kovi::log::error!("❌ 聊天失败: {}", e);
+                    event.reply(&format!("抱歉，处理消息时出错: {}", e));
+                }
+                return;
+            }
+
+            match chatbot.chat(user_id, group_id, text, &images, &sender_name).await {
                 Ok(response) => {
                     event.reply(&response);
                 }
@@ -103,3 +174,21 @@ fn is_to_me(event: &Arc<MsgEvent>) -> bool {
     }
     false
 }
+
+/// 提取消息中的图片来源，优先使用 url 字段，其次 file 字段
+fn extract_images(event: &Arc<MsgEvent>) -> Vec<String> {
+    event
+        .message
+        .iter()
+        .filter(|segment| segment.type_ == "image")
+        .filter_map(|segment| {
+            segment
+                .data
+                .get("url")
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .or_else(|| segment.data.get("file").and_then(|v| v.as_str()))
+                .map(|s| s.to_string())
+        })
+        .collect()
+}